@@ -0,0 +1,205 @@
+// Copyright Dan Schatzberg, 2016. This file is part of Genesis.
+
+// Genesis is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// Genesis is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+
+// You should have received a copy of the GNU Affero General Public License
+// along with Genesis.  If not, see <http://www.gnu.org/licenses/>.
+#![cfg_attr(feature = "user", feature(asm))]
+#![no_std]
+
+#![deny(missing_docs,
+        missing_debug_implementations, missing_copy_implementations,
+        trivial_casts, trivial_numeric_casts,
+        unused_import_braces, unused_qualifications)]
+//! The Genesis system call ABI.
+//!
+//! The kernel dispatches on this table and, with the `user` feature, user
+//! programs call through stubs generated from the same table, so the two
+//! cannot disagree about numbering.
+//!
+//! On x86_64 the number goes in `%rax` and up to six arguments in `%rdi`,
+//! `%rsi`, `%rdx`, `%r10`, `%r8` and `%r9`. The result comes back in
+//! `%rax`, encoded as by `encode`. `%rcx` and `%r11` are clobbered, every
+//! other register is preserved. Arguments a call does not take must be
+//! zero.
+
+#[cfg(test)]
+#[macro_use]
+extern crate std;
+
+/// Version of this ABI, returned by `Syscall::AbiVersion`
+///
+/// Bumped whenever a number, argument or result changes meaning.
+pub const ABI_VERSION: u64 = 1;
+
+/// Maximum number of system call arguments
+pub const MAX_ARGS: usize = 6;
+
+/// Errors returned by system calls
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Error {
+    /// There is no system call with this number
+    NoSys = 1,
+    /// An argument was out of range, or an unused argument was not zero
+    InvalidArgument = 2,
+    /// A pointer argument did not refer to user memory
+    BadAddress = 3,
+}
+
+// Results above this are errors, negated
+const MAX_ERROR: u64 = 4095;
+
+impl Error {
+    /// Returns the `Error` with code `code`
+    pub fn from_code(code: u64) -> Option<Error> {
+        match code {
+            1 => Some(Error::NoSys),
+            2 => Some(Error::InvalidArgument),
+            3 => Some(Error::BadAddress),
+            _ => None,
+        }
+    }
+
+    /// Returns the code of this `Error`
+    pub fn code(&self) -> u64 {
+        *self as u64
+    }
+}
+
+/// Encode a system call result for `%rax`
+///
+/// Errors are returned as their negated code, so successful results must be
+/// below `-4095`.
+pub fn encode(result: Result<u64, Error>) -> u64 {
+    match result {
+        Ok(value) => {
+            debug_assert!(value <= !MAX_ERROR);
+            value
+        }
+        Err(e) => e.code().wrapping_neg(),
+    }
+}
+
+/// Decode a system call result from `%rax`
+pub fn decode(value: u64) -> Result<u64, Error> {
+    if value > !MAX_ERROR {
+        // An error code this ABI version doesn't know about can only come
+        // from a newer kernel
+        Err(Error::from_code(value.wrapping_neg()).unwrap_or(Error::NoSys))
+    } else {
+        Ok(value)
+    }
+}
+
+macro_rules! count {
+    () => (0usize);
+    ($head:ident $($tail:ident)*) => (1usize + count!($($tail)*));
+}
+
+macro_rules! syscalls {
+    ($($(#[$attr:meta])*
+       $variant:ident = $num:tt => $stub:ident($($arg:ident),*);)*) => {
+        /// System calls, numbered as passed in `%rax`
+        #[derive(Clone, Copy, Debug, PartialEq, Eq)]
+        pub enum Syscall {
+            $($(#[$attr])* $variant = $num,)*
+        }
+
+        impl Syscall {
+            /// Returns the `Syscall` numbered `number`
+            pub fn from_number(number: u64) -> Option<Syscall> {
+                match number {
+                    $($num => Some(Syscall::$variant),)*
+                    _ => None,
+                }
+            }
+
+            /// Returns the number of this `Syscall`
+            pub fn number(&self) -> u64 {
+                *self as u64
+            }
+
+            /// Returns how many arguments this `Syscall` takes
+            pub fn nargs(&self) -> usize {
+                match *self {
+                    $(Syscall::$variant => count!($($arg)*),)*
+                }
+            }
+        }
+
+        /// User-mode system call stubs
+        #[cfg(feature = "user")]
+        pub mod user {
+            use super::{Error, MAX_ARGS, Syscall, decode};
+
+            $($(#[$attr])*
+              #[inline]
+              pub unsafe fn $stub($($arg: u64),*) -> Result<u64, Error> {
+                  raw(Syscall::$variant, &[$($arg),*])
+              })*
+
+            /// Make system call `syscall` with `args`
+            pub unsafe fn raw(syscall: Syscall,
+                              args: &[u64])
+                              -> Result<u64, Error> {
+                let mut a = [0; MAX_ARGS];
+                a[..args.len()].copy_from_slice(args);
+                let ret: u64;
+                asm!("syscall"
+                     : "={rax}"(ret)
+                     : "{rax}"(syscall.number()),
+                       "{rdi}"(a[0]), "{rsi}"(a[1]), "{rdx}"(a[2]),
+                       "{r10}"(a[3]), "{r8}"(a[4]), "{r9}"(a[5])
+                     : "rcx", "r11", "memory"
+                     : "volatile");
+                decode(ret)
+            }
+        }
+    }
+}
+
+syscalls! {
+    /// Returns `ABI_VERSION`
+    AbiVersion = 0 => abi_version();
+    /// Write the UTF-8 string at `ptr` of `len` bytes to the kernel log
+    DebugWrite = 1 => debug_write(ptr, len);
+    /// Give up the CPU
+    Yield = 2 => yield_now();
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_encode_decode() {
+        for value in [0, 1, 4096, !MAX_ERROR].iter() {
+            assert_eq!(decode(encode(Ok(*value))), Ok(*value));
+        }
+        for e in [Error::NoSys, Error::InvalidArgument, Error::BadAddress]
+            .iter() {
+            assert_eq!(decode(encode(Err(*e))), Err(*e));
+        }
+    }
+
+    #[test]
+    fn test_numbering() {
+        for n in 0..64 {
+            if let Some(syscall) = Syscall::from_number(n) {
+                assert_eq!(syscall.number(), n);
+                assert!(syscall.nargs() <= MAX_ARGS);
+            }
+        }
+        assert_eq!(Syscall::from_number(Syscall::DebugWrite.number()),
+                   Some(Syscall::DebugWrite));
+        assert_eq!(Syscall::DebugWrite.nargs(), 2);
+    }
+}
@@ -0,0 +1,83 @@
+// Copyright Dan Schatzberg, 2016. This file is part of Genesis.
+
+// Genesis is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// Genesis is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+
+// You should have received a copy of the GNU Affero General Public License
+// along with Genesis.  If not, see <http://www.gnu.org/licenses/>.
+#![feature(allocator)]
+#![allocator]
+#![no_std]
+
+#![deny(missing_docs,
+        missing_debug_implementations, missing_copy_implementations,
+        trivial_casts, trivial_numeric_casts,
+        unused_import_braces, unused_qualifications)]
+//! The Genesis allocator shim.
+//!
+//! An allocator crate may not use the allocation crates itself, so this
+//! only forwards the entry points `liballoc` links against to the kernel
+//! heap, which exports `genesis_heap_allocate` and
+//! `genesis_heap_deallocate`.
+
+use core::cmp;
+use core::ptr;
+
+extern "C" {
+    fn genesis_heap_allocate(size: usize, align: usize) -> *mut u8;
+    fn genesis_heap_deallocate(ptr: *mut u8, size: usize, align: usize);
+}
+
+/// Allocate `size` bytes aligned to `align`, null on failure
+#[no_mangle]
+pub extern "C" fn __rust_allocate(size: usize, align: usize) -> *mut u8 {
+    unsafe { genesis_heap_allocate(size, align) }
+}
+
+/// Free memory returned by `__rust_allocate` with the same size and align
+#[no_mangle]
+pub extern "C" fn __rust_deallocate(ptr: *mut u8, size: usize, align: usize) {
+    unsafe { genesis_heap_deallocate(ptr, size, align) }
+}
+
+/// Move an allocation to one of `size` bytes, null on failure
+///
+/// The heap cannot grow objects in place, so this always copies.
+#[no_mangle]
+pub extern "C" fn __rust_reallocate(ptr: *mut u8,
+                                    old_size: usize,
+                                    size: usize,
+                                    align: usize)
+                                    -> *mut u8 {
+    unsafe {
+        let new = genesis_heap_allocate(size, align);
+        if !new.is_null() {
+            ptr::copy_nonoverlapping(ptr, new, cmp::min(old_size, size));
+            genesis_heap_deallocate(ptr, old_size, align);
+        }
+        new
+    }
+}
+
+/// Resize an allocation without moving it, returns the resulting size
+#[no_mangle]
+pub extern "C" fn __rust_reallocate_inplace(_ptr: *mut u8,
+                                            old_size: usize,
+                                            _size: usize,
+                                            _align: usize)
+                                            -> usize {
+    old_size
+}
+
+/// Returns the size actually allocated for a request of `size` bytes
+#[no_mangle]
+pub extern "C" fn __rust_usable_size(size: usize, _align: usize) -> usize {
+    size
+}
@@ -33,3 +33,15 @@ pub use self::x86_64::arch_init;
 
 #[cfg(target_arch = "x86_64")]
 pub use self::x86_64::interrupt_handler;
+
+#[cfg(target_arch = "x86_64")]
+pub use self::x86_64::stop_other_cpus;
+
+#[cfg(target_arch = "x86_64")]
+pub use self::x86_64::other_cpus_stopped;
+
+#[cfg(target_arch = "x86_64")]
+pub use self::x86_64::syscall_handler;
+
+#[cfg(target_arch = "x86_64")]
+pub use self::x86_64::user_space;
@@ -14,9 +14,16 @@
 // along with Genesis.  If not, see <http://www.gnu.org/licenses/>.
 #![allow(dead_code)]
 
+use core::cmp;
+use core::sync::atomic::{ATOMIC_USIZE_INIT, AtomicUsize, Ordering};
 use super::ioport;
 
-const PORT_BASE: u16 = 0x3F8;
+/// I/O port of COM1, used unless another port is passed to `init`
+pub const DEFAULT_PORT: u16 = 0x3F8;
+/// Baud rate used unless another is passed to `init`
+pub const DEFAULT_BAUD: u32 = 115200;
+// The UART clock divided by 16
+const MAX_BAUD: u32 = 115200;
 // when DLAB = 0
 const DATA_REG: u16 = 0;
 const INT_ENABLE: u16 = 1;
@@ -32,32 +39,46 @@ const LINE_CTRL_REG_DLAB: u8 = 1 << 7;
 const LINE_STATUS_REG: u16 = 5;
 const LINE_STATUS_REG_THR_EMPTY: u8 = 1 << 5;
 
-/// Initialize the Serial Port
-pub fn init() {
+// 0 until init is called
+static PORT: AtomicUsize = ATOMIC_USIZE_INIT;
+
+fn port_base() -> u16 {
+    match PORT.load(Ordering::Relaxed) {
+        0 => DEFAULT_PORT,
+        port => port as u16,
+    }
+}
+
+/// Initialize the Serial Port at I/O port `port` with `baud`
+///
+/// Baud rates that do not divide 115200 are rounded down.
+pub fn init(port: u16, baud: u32) {
     assert_has_not_been_called!("serial::init() function \
                                  must only be called once");
+    PORT.store(port as usize, Ordering::Relaxed);
+    let divisor = MAX_BAUD / cmp::min(cmp::max(baud, 1), MAX_BAUD);
     unsafe {
-        ioport::out(PORT_BASE + INT_ENABLE, 0u8); // disable interrupts
+        ioport::out(port + INT_ENABLE, 0u8); // disable interrupts
 
         // enable dlab
-        ioport::out(PORT_BASE + LINE_CTRL_REG, LINE_CTRL_REG_DLAB);
-        // XXX: hard coded 115200 baud
-        ioport::out(PORT_BASE + BAUD_DIV_LSB, 1u8);
-        ioport::out(PORT_BASE + BAUD_DIV_MSB, 0u8);
+        ioport::out(port + LINE_CTRL_REG, LINE_CTRL_REG_DLAB);
+        ioport::out(port + BAUD_DIV_LSB, divisor as u8);
+        ioport::out(port + BAUD_DIV_MSB, (divisor >> 8) as u8);
 
         // XXX: hard coded as 8N1 (8 bits, no parity, one stop bit)
-        ioport::out(PORT_BASE + LINE_CTRL_REG, LINE_CTRL_REG_CHARLEN8);
+        ioport::out(port + LINE_CTRL_REG, LINE_CTRL_REG_CHARLEN8);
     }
 }
 
 unsafe fn is_transmit_empty() -> bool {
-    ioport::inb(PORT_BASE + LINE_STATUS_REG) & LINE_STATUS_REG_THR_EMPTY != 0
+    let status = ioport::inb(port_base() + LINE_STATUS_REG);
+    status & LINE_STATUS_REG_THR_EMPTY != 0
 }
 
 unsafe fn putc(c: u8) {
     while !is_transmit_empty() {}
 
-    ioport::out(PORT_BASE + DATA_REG, c);
+    ioport::out(port_base() + DATA_REG, c);
 }
 
 /// Write `str` to the Serial Port
@@ -0,0 +1,721 @@
+// Copyright Dan Schatzberg, 2016. This file is part of Genesis.
+
+// Genesis is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// Genesis is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+
+// You should have received a copy of the GNU Affero General Public License
+// along with Genesis.  If not, see <http://www.gnu.org/licenses/>.
+use core::cmp;
+use core::str;
+use fixedvec::FixedVec;
+use memory::PAddr;
+use spin;
+use super::ioapic::InterruptOverride;
+
+const RSDP_SIGNATURE: &'static [u8] = b"RSD PTR ";
+const RSDP_V1_LEN: usize = 20;
+const RSDP_V2_LEN: usize = 36;
+const HEADER_LEN: usize = 36;
+
+// The BIOS data area holds the EBDA segment here
+const EBDA_SEGMENT_PTR: u64 = 0x40e;
+const EBDA_SEARCH_LEN: usize = 1024;
+const BIOS_AREA_START: u64 = 0xe0000;
+const BIOS_AREA_LEN: usize = 0x20000;
+
+// MADT entry types
+const MADT_LOCAL_APIC: u8 = 0;
+const MADT_IO_APIC: u8 = 1;
+const MADT_INTERRUPT_OVERRIDE: u8 = 2;
+const MADT_LOCAL_APIC_ADDRESS: u8 = 5;
+const MADT_LOCAL_X2APIC: u8 = 9;
+// MADT local APIC flags
+const MADT_CPU_ENABLED: u32 = 1 << 0;
+
+// SRAT entry types
+const SRAT_CPU: u8 = 0;
+const SRAT_MEMORY: u8 = 1;
+const SRAT_X2APIC: u8 = 2;
+// SRAT entry flags
+const SRAT_ENABLED: u32 = 1 << 0;
+
+/// A processor described by the MADT
+#[derive(Clone, Copy, Debug)]
+pub struct LocalApic {
+    pub processor_uid: u32,
+    pub apic_id: u32,
+    pub enabled: bool,
+}
+
+/// An I/O APIC described by the MADT
+#[derive(Clone, Copy, Debug)]
+pub struct IoApicEntry {
+    pub id: u8,
+    pub addr: PAddr,
+    pub gsi_base: u32,
+}
+
+/// The HPET described by the HPET table
+#[derive(Clone, Copy, Debug)]
+pub struct Hpet {
+    pub addr: PAddr,
+    pub number: u8,
+    pub min_tick: u16,
+}
+
+/// A PCIe enhanced configuration space described by the MCFG
+#[derive(Clone, Copy, Debug)]
+pub struct McfgEntry {
+    pub base: PAddr,
+    pub segment: u16,
+    pub bus_start: u8,
+    pub bus_end: u8,
+}
+
+/// A range of memory on a NUMA node, from the SRAT
+#[derive(Clone, Copy, Debug)]
+pub struct MemoryAffinity {
+    pub start: PAddr,
+    pub end: PAddr,
+    pub node: u32,
+}
+
+/// The NUMA node of a processor, from the SRAT
+#[derive(Clone, Copy, Debug)]
+pub struct CpuAffinity {
+    pub apic_id: u32,
+    pub node: u32,
+}
+
+const TABLE_SIZE: usize = 256;
+
+type Table<T> = spin::RwLock<FixedVec<'static, T>>;
+type TableGuard<T> = spin::RwLockReadGuard<'static, FixedVec<'static, T>>;
+
+lazy_static! {
+    static ref CPUS: Table<LocalApic> = {
+        static mut CPUS_MEM: [LocalApic; TABLE_SIZE] =
+            [LocalApic { processor_uid: 0, apic_id: 0, enabled: false };
+             TABLE_SIZE];
+        // Unsafe to take a mutable reference of a static.
+        // We instantly store it behind a Rwlock, so this is safe
+        unsafe { spin::RwLock::new(FixedVec::new(&mut CPUS_MEM)) }
+    };
+    static ref IO_APICS: Table<IoApicEntry> = {
+        static mut IO_APICS_MEM: [IoApicEntry; 16] =
+            [IoApicEntry { id: 0, addr: PAddr::from_u64(0), gsi_base: 0 };
+             16];
+        unsafe { spin::RwLock::new(FixedVec::new(&mut IO_APICS_MEM)) }
+    };
+    static ref OVERRIDES: Table<InterruptOverride> = {
+        static mut OVERRIDES_MEM: [InterruptOverride; 16] =
+            [InterruptOverride::identity(0); 16];
+        unsafe { spin::RwLock::new(FixedVec::new(&mut OVERRIDES_MEM)) }
+    };
+    static ref MCFG: Table<McfgEntry> = {
+        static mut MCFG_MEM: [McfgEntry; 16] =
+            [McfgEntry {
+                base: PAddr::from_u64(0),
+                segment: 0,
+                bus_start: 0,
+                bus_end: 0,
+            }; 16];
+        unsafe { spin::RwLock::new(FixedVec::new(&mut MCFG_MEM)) }
+    };
+    static ref MEMORY_AFFINITY: Table<MemoryAffinity> = {
+        static mut MEMORY_AFFINITY_MEM: [MemoryAffinity; 16] =
+            [MemoryAffinity {
+                start: PAddr::from_u64(0),
+                end: PAddr::from_u64(0),
+                node: 0,
+            }; 16];
+        unsafe { spin::RwLock::new(FixedVec::new(&mut MEMORY_AFFINITY_MEM)) }
+    };
+    static ref CPU_AFFINITY: Table<CpuAffinity> = {
+        static mut CPU_AFFINITY_MEM: [CpuAffinity; TABLE_SIZE] =
+            [CpuAffinity { apic_id: 0, node: 0 }; TABLE_SIZE];
+        unsafe { spin::RwLock::new(FixedVec::new(&mut CPU_AFFINITY_MEM)) }
+    };
+}
+
+static LOCAL_APIC_ADDR: spin::RwLock<Option<PAddr>> = spin::RwLock::new(None);
+static HPET: spin::RwLock<Option<Hpet>> = spin::RwLock::new(None);
+
+/// Processors from the MADT
+pub fn cpus() -> TableGuard<LocalApic> {
+    CPUS.read()
+}
+
+/// I/O APICs from the MADT
+pub fn io_apics() -> TableGuard<IoApicEntry> {
+    IO_APICS.read()
+}
+
+/// Interrupt source overrides from the MADT
+pub fn overrides() -> TableGuard<InterruptOverride> {
+    OVERRIDES.read()
+}
+
+/// PCIe configuration spaces from the MCFG
+pub fn mcfg() -> TableGuard<McfgEntry> {
+    MCFG.read()
+}
+
+/// NUMA memory ranges from the SRAT, empty without NUMA
+pub fn memory_affinity() -> TableGuard<MemoryAffinity> {
+    MEMORY_AFFINITY.read()
+}
+
+/// NUMA nodes of processors from the SRAT, empty without NUMA
+pub fn cpu_affinity() -> TableGuard<CpuAffinity> {
+    CPU_AFFINITY.read()
+}
+
+/// Local APIC physical address from the MADT
+pub fn local_apic_addr() -> Option<PAddr> {
+    *LOCAL_APIC_ADDR.read()
+}
+
+/// The HPET, if there is one
+pub fn hpet() -> Option<Hpet> {
+    *HPET.read()
+}
+
+fn read_u16(bytes: &[u8], off: usize) -> u16 {
+    bytes[off] as u16 | (bytes[off + 1] as u16) << 8
+}
+
+fn read_u32(bytes: &[u8], off: usize) -> u32 {
+    read_u16(bytes, off) as u32 | (read_u16(bytes, off + 2) as u32) << 16
+}
+
+fn read_u64(bytes: &[u8], off: usize) -> u64 {
+    read_u32(bytes, off) as u64 | (read_u32(bytes, off + 4) as u64) << 32
+}
+
+fn checksum_ok(bytes: &[u8]) -> bool {
+    bytes.iter().fold(0u8, |sum, b| sum.wrapping_add(*b)) == 0
+}
+
+/// Search for and parse the ACPI tables
+///
+/// `paddr_to_slice` gives access to physical memory, the same way
+/// `Multiboot::new` expects. Returns false if no valid RSDP was found.
+pub fn init<'a, F>(paddr_to_slice: F) -> bool
+    where F: Fn(u64, usize) -> Option<&'a [u8]>
+{
+    assert_has_not_been_called!("acpi::init() function \
+                                 must only be called once");
+    match find_rsdp(&paddr_to_slice) {
+        Some(rsdp) => {
+            parse_rsdp(rsdp, &paddr_to_slice);
+            true
+        }
+        None => {
+            warn!("Could not find the ACPI RSDP");
+            false
+        }
+    }
+}
+
+/// Parse the ACPI tables starting from a copy of the RSDP
+pub fn init_from_rsdp<'a, F>(rsdp: &[u8], paddr_to_slice: F) -> bool
+    where F: Fn(u64, usize) -> Option<&'a [u8]>
+{
+    assert_has_not_been_called!("acpi::init_from_rsdp() function \
+                                 must only be called once");
+    if rsdp.len() < RSDP_V1_LEN || !valid_rsdp(rsdp) {
+        warn!("Invalid ACPI RSDP provided by the boot loader");
+        return false;
+    }
+    parse_rsdp(rsdp, &paddr_to_slice);
+    true
+}
+
+fn valid_rsdp(rsdp: &[u8]) -> bool {
+    if &rsdp[..8] != RSDP_SIGNATURE || !checksum_ok(&rsdp[..RSDP_V1_LEN]) {
+        return false;
+    }
+    if rsdp[15] >= 2 {
+        if rsdp.len() < RSDP_V2_LEN {
+            return false;
+        }
+        let len = read_u32(rsdp, 20) as usize;
+        len >= RSDP_V2_LEN && len <= rsdp.len() &&
+        checksum_ok(&rsdp[..len])
+    } else {
+        true
+    }
+}
+
+fn find_rsdp<'a, F>(paddr_to_slice: &F) -> Option<&'a [u8]>
+    where F: Fn(u64, usize) -> Option<&'a [u8]>
+{
+    let ebda = paddr_to_slice(EBDA_SEGMENT_PTR, 2)
+        .map(|seg| (read_u16(seg, 0) as u64) << 4);
+    let areas = [ebda.map(|addr| (addr, EBDA_SEARCH_LEN)),
+                 Some((BIOS_AREA_START, BIOS_AREA_LEN))];
+    for &(start, len) in areas.iter().filter_map(|a| a.as_ref()) {
+        let area = match paddr_to_slice(start, len) {
+            Some(area) => area,
+            None => continue,
+        };
+        // The RSDP is always 16 byte aligned
+        let mut off = 0;
+        while off + RSDP_V1_LEN <= area.len() {
+            let candidate = &area[off..];
+            // A v2 RSDP cut short by the end of the area fails validation
+            let len = if candidate[15] >= 2 {
+                cmp::min(RSDP_V2_LEN, candidate.len())
+            } else {
+                RSDP_V1_LEN
+            };
+            if &candidate[..8] == RSDP_SIGNATURE &&
+               valid_rsdp(&candidate[..len]) {
+                debug!("ACPI RSDP found at {:#X}", start + off as u64);
+                return Some(&candidate[..len]);
+            }
+            off += 16;
+        }
+    }
+    None
+}
+
+fn parse_rsdp<'a, F>(rsdp: &[u8], paddr_to_slice: &F)
+    where F: Fn(u64, usize) -> Option<&'a [u8]>
+{
+    let revision = rsdp[15];
+    let xsdt = if revision >= 2 {
+        read_u64(rsdp, 24)
+    } else {
+        0
+    };
+    let (root, entry_size) = if xsdt != 0 {
+        (xsdt, 8)
+    } else {
+        (read_u32(rsdp, 16) as u64, 4)
+    };
+    let root = match map_table(root, paddr_to_slice) {
+        Some(table) => table,
+        None => {
+            warn!("Could not access the ACPI root table at {:#X}", root);
+            return;
+        }
+    };
+    let mut off = HEADER_LEN;
+    while off + entry_size <= root.len() {
+        let addr = if entry_size == 8 {
+            read_u64(root, off)
+        } else {
+            read_u32(root, off) as u64
+        };
+        off += entry_size;
+        let table = match map_table(addr, paddr_to_slice) {
+            Some(table) => table,
+            None => continue,
+        };
+        let signature = str::from_utf8(&table[..4]).unwrap_or("????");
+        debug!("ACPI table {} at {:#X}", signature, addr);
+        let signature = &table[..4];
+        if signature == b"APIC" {
+            parse_madt(table);
+        } else if signature == b"HPET" {
+            parse_hpet(table);
+        } else if signature == b"MCFG" {
+            parse_mcfg(table);
+        } else if signature == b"SRAT" {
+            parse_srat(table);
+        }
+    }
+}
+
+/// Access the whole table at `addr`, checking its length and checksum
+fn map_table<'a, F>(addr: u64, paddr_to_slice: &F) -> Option<&'a [u8]>
+    where F: Fn(u64, usize) -> Option<&'a [u8]>
+{
+    let len = match paddr_to_slice(addr, HEADER_LEN) {
+        Some(header) => read_u32(header, 4) as usize,
+        None => {
+            warn!("ACPI table at {:#X} is not accessible", addr);
+            return None;
+        }
+    };
+    if len < HEADER_LEN {
+        warn!("ACPI table at {:#X} is too short", addr);
+        return None;
+    }
+    match paddr_to_slice(addr, len) {
+        Some(table) if checksum_ok(table) => Some(table),
+        Some(_) => {
+            warn!("Bad checksum on ACPI table at {:#X}", addr);
+            None
+        }
+        None => {
+            warn!("ACPI table at {:#X} is not accessible", addr);
+            None
+        }
+    }
+}
+
+/// A decoded MADT entry
+#[derive(Clone, Copy, Debug)]
+enum MadtEntry {
+    Cpu(LocalApic),
+    IoApic(IoApicEntry),
+    Override(InterruptOverride),
+    LocalApicAddress(PAddr),
+}
+
+/// Decode an MADT entry, `None` if its type is not used or it is too short
+/// for its type
+fn madt_entry(entry: &[u8]) -> Option<MadtEntry> {
+    let len = entry.len();
+    match entry[0] {
+        MADT_LOCAL_APIC if len >= 8 => {
+            Some(MadtEntry::Cpu(LocalApic {
+                processor_uid: entry[2] as u32,
+                apic_id: entry[3] as u32,
+                enabled: read_u32(entry, 4) & MADT_CPU_ENABLED != 0,
+            }))
+        }
+        MADT_LOCAL_X2APIC if len >= 16 => {
+            Some(MadtEntry::Cpu(LocalApic {
+                processor_uid: read_u32(entry, 12),
+                apic_id: read_u32(entry, 4),
+                enabled: read_u32(entry, 8) & MADT_CPU_ENABLED != 0,
+            }))
+        }
+        MADT_IO_APIC if len >= 12 => {
+            Some(MadtEntry::IoApic(IoApicEntry {
+                id: entry[2],
+                addr: PAddr::from_u64(read_u32(entry, 4) as u64),
+                gsi_base: read_u32(entry, 8),
+            }))
+        }
+        MADT_INTERRUPT_OVERRIDE if len >= 10 => {
+            let irq = entry[3];
+            let gsi = read_u32(entry, 4);
+            let flags = read_u16(entry, 8);
+            Some(MadtEntry::Override(InterruptOverride::from_mps_flags(irq,
+                                                                       gsi,
+                                                                       flags)))
+        }
+        MADT_LOCAL_APIC_ADDRESS if len >= 12 => {
+            Some(MadtEntry::LocalApicAddress(PAddr::from_u64(read_u64(entry,
+                                                                      4))))
+        }
+        _ => None,
+    }
+}
+
+fn parse_madt(madt: &[u8]) {
+    // The local APIC address and flags follow the header
+    if madt.len() < HEADER_LEN + 8 {
+        warn!("MADT too short");
+        return;
+    }
+    let local_apic_addr = read_u32(madt, 36) as u64;
+    *LOCAL_APIC_ADDR.write() = Some(PAddr::from_u64(local_apic_addr));
+    let mut cpus = CPUS.write();
+    let mut io_apics = IO_APICS.write();
+    let mut overrides = OVERRIDES.write();
+    let mut off = HEADER_LEN + 8;
+    while off + 2 <= madt.len() {
+        let len = madt[off + 1] as usize;
+        if len < 2 || off + len > madt.len() {
+            warn!("Malformed MADT entry at offset {}", off);
+            break;
+        }
+        let entry = &madt[off..off + len];
+        off += len;
+        let result = match madt_entry(entry) {
+            Some(MadtEntry::Cpu(cpu)) => cpus.push(cpu).map_err(|_| "CPU"),
+            Some(MadtEntry::IoApic(io_apic)) => {
+                io_apics.push(io_apic).map_err(|_| "I/O APIC")
+            }
+            Some(MadtEntry::Override(ov)) => {
+                overrides.push(ov).map_err(|_| "interrupt override")
+            }
+            Some(MadtEntry::LocalApicAddress(addr)) => {
+                *LOCAL_APIC_ADDR.write() = Some(addr);
+                Ok(())
+            }
+            None => Ok(()),
+        };
+        if let Err(what) = result {
+            warn!("No space to store MADT {} entry", what);
+        }
+    }
+    info!("MADT: {} CPUs, {} I/O APICs, {} interrupt overrides",
+          cpus.len(),
+          io_apics.len(),
+          overrides.len());
+}
+
+fn parse_hpet(hpet: &[u8]) {
+    if hpet.len() < HEADER_LEN + 20 {
+        warn!("HPET table too short");
+        return;
+    }
+    *HPET.write() = Some(Hpet {
+        addr: PAddr::from_u64(read_u64(hpet, 44)),
+        number: hpet[52],
+        min_tick: read_u16(hpet, 53),
+    });
+}
+
+fn parse_mcfg(mcfg: &[u8]) {
+    const ENTRY_LEN: usize = 16;
+    let mut entries = MCFG.write();
+    let mut off = HEADER_LEN + 8;
+    while off + ENTRY_LEN <= mcfg.len() {
+        let entry = McfgEntry {
+            base: PAddr::from_u64(read_u64(mcfg, off)),
+            segment: read_u16(mcfg, off + 8),
+            bus_start: mcfg[off + 10],
+            bus_end: mcfg[off + 11],
+        };
+        if entries.push(entry).is_err() {
+            warn!("No space to store MCFG entry {:?}", entry);
+        }
+        off += ENTRY_LEN;
+    }
+}
+
+fn parse_srat(srat: &[u8]) {
+    let mut memory = MEMORY_AFFINITY.write();
+    let mut cpus = CPU_AFFINITY.write();
+    // Skip the table revision and reserved bytes
+    let mut off = HEADER_LEN + 12;
+    while off + 2 <= srat.len() {
+        let entry_type = srat[off];
+        let len = srat[off + 1] as usize;
+        if len < 2 || off + len > srat.len() {
+            warn!("Malformed SRAT entry at offset {}", off);
+            break;
+        }
+        let entry = &srat[off..off + len];
+        off += len;
+        let result = match entry_type {
+            SRAT_CPU if len >= 16 => {
+                // The domain is split between bytes 2 and 9-11
+                let node = entry[2] as u32 | (entry[9] as u32) << 8 |
+                           (entry[10] as u32) << 16 |
+                           (entry[11] as u32) << 24;
+                if read_u32(entry, 4) & SRAT_ENABLED == 0 {
+                    continue;
+                }
+                cpus.push(CpuAffinity {
+                        apic_id: entry[3] as u32,
+                        node: node,
+                    })
+                    .map_err(|_| "CPU")
+            }
+            SRAT_X2APIC if len >= 24 => {
+                if read_u32(entry, 12) & SRAT_ENABLED == 0 {
+                    continue;
+                }
+                cpus.push(CpuAffinity {
+                        apic_id: read_u32(entry, 8),
+                        node: read_u32(entry, 4),
+                    })
+                    .map_err(|_| "CPU")
+            }
+            SRAT_MEMORY if len >= 40 => {
+                let start = read_u64(entry, 8);
+                let length = read_u64(entry, 16);
+                if read_u32(entry, 28) & SRAT_ENABLED == 0 || length == 0 {
+                    continue;
+                }
+                memory.push(MemoryAffinity {
+                        start: PAddr::from_u64(start),
+                        end: PAddr::from_u64(start.saturating_add(length)),
+                        node: read_u32(entry, 2),
+                    })
+                    .map_err(|_| "memory")
+            }
+            _ => Ok(()),
+        };
+        if let Err(what) = result {
+            warn!("No space to store SRAT {} entry", what);
+        }
+    }
+    info!("SRAT: {} memory ranges, {} CPUs", memory.len(), cpus.len());
+}
+
+#[cfg(test)]
+mod test {
+    use memory::PAddr;
+    use std::vec::Vec;
+    use super::{BIOS_AREA_LEN, BIOS_AREA_START, HEADER_LEN, MadtEntry,
+                RSDP_V1_LEN, checksum_ok, find_rsdp, madt_entry, map_table,
+                read_u16, read_u32, read_u64, valid_rsdp};
+    use super::super::ioapic::{Polarity, Trigger};
+
+    // Set the checksum byte at `off` so that `bytes` sums to zero
+    fn fix_checksum(bytes: &mut [u8], off: usize) {
+        bytes[off] = 0;
+        let sum = bytes.iter().fold(0u8, |sum, b| sum.wrapping_add(*b));
+        bytes[off] = 0u8.wrapping_sub(sum);
+    }
+
+    #[test]
+    fn test_read() {
+        let bytes = [1, 2, 3, 4, 5, 6, 7, 8, 9];
+        assert_eq!(read_u16(&bytes, 1), 0x0302);
+        assert_eq!(read_u32(&bytes, 0), 0x0403_0201);
+        assert_eq!(read_u64(&bytes, 1), 0x0908_0706_0504_0302);
+        let mut bytes = [0x12, 0x34, 0];
+        assert!(!checksum_ok(&bytes));
+        fix_checksum(&mut bytes, 2);
+        assert!(checksum_ok(&bytes));
+    }
+
+    #[test]
+    fn test_valid_rsdp() {
+        let mut rsdp = [0u8; 20];
+        rsdp[..8].copy_from_slice(b"RSD PTR ");
+        fix_checksum(&mut rsdp, 8);
+        assert!(valid_rsdp(&rsdp));
+        rsdp[9] = 1;
+        assert!(!valid_rsdp(&rsdp));
+        rsdp[0] = b'X';
+        fix_checksum(&mut rsdp, 8);
+        assert!(!valid_rsdp(&rsdp));
+    }
+
+    #[test]
+    fn test_valid_rsdp_v2() {
+        let mut rsdp = [0u8; 36];
+        rsdp[..8].copy_from_slice(b"RSD PTR ");
+        rsdp[15] = 2;
+        rsdp[20] = 36;
+        fix_checksum(&mut rsdp[..20], 8);
+        fix_checksum(&mut rsdp, 32);
+        assert!(valid_rsdp(&rsdp));
+        // Too short to hold the v2 fields
+        assert!(!valid_rsdp(&rsdp[..20]));
+        rsdp[20] = 40;
+        fix_checksum(&mut rsdp[..20], 8);
+        assert!(!valid_rsdp(&rsdp));
+    }
+
+    #[test]
+    fn test_find_rsdp() {
+        // A v1 RSDP in the last 32 bytes of the BIOS area
+        let mut area = vec![0u8; BIOS_AREA_LEN];
+        let off = BIOS_AREA_LEN - 32;
+        area[off..off + 8].copy_from_slice(b"RSD PTR ");
+        fix_checksum(&mut area[off..off + RSDP_V1_LEN], 8);
+        {
+            let bios = |addr, len| if addr == BIOS_AREA_START &&
+                                      len == BIOS_AREA_LEN {
+                Some(&area[..])
+            } else {
+                None
+            };
+            assert_eq!(find_rsdp(&bios), Some(&area[off..off + 20]));
+        }
+        // Claiming v2 without room for the v2 fields
+        area[off + 15] = 2;
+        fix_checksum(&mut area[off..off + RSDP_V1_LEN], 8);
+        let bios = |addr, len| if addr == BIOS_AREA_START &&
+                                  len == BIOS_AREA_LEN {
+            Some(&area[..])
+        } else {
+            None
+        };
+        assert_eq!(find_rsdp(&bios), None);
+    }
+
+    // A table of `len` bytes whose header claims `claimed` bytes
+    fn test_table(len: usize, claimed: u8) -> Vec<u8> {
+        let mut table = vec![0; len];
+        table[..4].copy_from_slice(b"TEST");
+        table[4] = claimed;
+        fix_checksum(&mut table, 9);
+        table
+    }
+
+    // Physical memory holding only `table`, at 0x1000
+    fn lookup(table: &[u8], addr: u64, len: usize) -> Option<&[u8]> {
+        if addr == 0x1000 && len <= table.len() {
+            Some(&table[..len])
+        } else {
+            None
+        }
+    }
+
+    #[test]
+    fn test_map_table() {
+        let table = test_table(HEADER_LEN + 4, HEADER_LEN as u8 + 4);
+        let f = |addr, len| lookup(&table, addr, len);
+        assert_eq!(map_table(0x1000, &f).map(|t| t.len()),
+                   Some(HEADER_LEN + 4));
+        assert!(map_table(0x2000, &f).is_none());
+
+        // Lengths past the accessible bytes or below the header
+        let long = test_table(HEADER_LEN + 4, HEADER_LEN as u8 + 8);
+        assert!(map_table(0x1000, &|a, l| lookup(&long, a, l)).is_none());
+        let short = test_table(HEADER_LEN + 4, 8);
+        assert!(map_table(0x1000, &|a, l| lookup(&short, a, l)).is_none());
+
+        let mut bad = test_table(HEADER_LEN, HEADER_LEN as u8);
+        bad[9] ^= 1;
+        assert!(map_table(0x1000, &|a, l| lookup(&bad, a, l)).is_none());
+    }
+
+    #[test]
+    fn test_madt_entry() {
+        match madt_entry(&[0, 8, 1, 2, 1, 0, 0, 0]) {
+            Some(MadtEntry::Cpu(cpu)) => {
+                assert_eq!((cpu.processor_uid, cpu.apic_id, cpu.enabled),
+                           (1, 2, true))
+            }
+            e => panic!("Unexpected {:?}", e),
+        }
+        match madt_entry(&[9, 16, 0, 0, 0, 1, 0, 0, 0, 0, 0, 0, 7, 0, 0, 0]) {
+            Some(MadtEntry::Cpu(cpu)) => {
+                assert_eq!((cpu.processor_uid, cpu.apic_id, cpu.enabled),
+                           (7, 0x100, false))
+            }
+            e => panic!("Unexpected {:?}", e),
+        }
+        match madt_entry(&[1, 12, 3, 0, 0, 0, 0xc0, 0xfe, 24, 0, 0, 0]) {
+            Some(MadtEntry::IoApic(io_apic)) => {
+                assert_eq!(io_apic.id, 3);
+                assert_eq!(io_apic.addr, PAddr::from_u64(0xfec0_0000));
+                assert_eq!(io_apic.gsi_base, 24);
+            }
+            e => panic!("Unexpected {:?}", e),
+        }
+        match madt_entry(&[2, 10, 0, 0, 2, 0, 0, 0, 0xf, 0]) {
+            Some(MadtEntry::Override(ov)) => {
+                assert_eq!((ov.isa_irq, ov.gsi), (0, 2));
+                assert_eq!(ov.polarity, Polarity::ActiveLow);
+                assert_eq!(ov.trigger, Trigger::Level);
+            }
+            e => panic!("Unexpected {:?}", e),
+        }
+        match madt_entry(&[5, 12, 0, 0, 0, 0, 0xe0, 0xfe, 0, 0, 0, 0]) {
+            Some(MadtEntry::LocalApicAddress(addr)) => {
+                assert_eq!(addr, PAddr::from_u64(0xfee0_0000))
+            }
+            e => panic!("Unexpected {:?}", e),
+        }
+        // Entries too short for their type are ignored
+        assert!(madt_entry(&[0, 4, 1, 2]).is_none());
+        assert!(madt_entry(&[9, 8, 0, 0, 0, 1, 0, 0]).is_none());
+        assert!(madt_entry(&[1, 8, 3, 0, 0, 0, 0xc0, 0xfe]).is_none());
+        assert!(madt_entry(&[2, 8, 0, 0, 2, 0, 0, 0]).is_none());
+        assert!(madt_entry(&[5, 4, 0, 0]).is_none());
+        assert!(madt_entry(&[0x7f, 2]).is_none());
+    }
+}
@@ -16,9 +16,14 @@
 
 use memory::*;
 use core::ptr;
+use core::sync::atomic::{ATOMIC_USIZE_INIT, AtomicUsize, Ordering};
+use spin;
 use x86::msr::*;
+use super::idt::{self, Priority, TrapFrame};
+use super::percpu;
 
 #[allow(dead_code)]
+#[derive(Clone, Copy, Debug)]
 enum Reg {
     ID = 0x20,
     LVR = 0x30,
@@ -48,43 +53,409 @@ enum Reg {
     SELF_IPI = 0x3f0,
 }
 
+const BASE_X2APIC_ENABLE: u64 = 1 << 10;
 const BASE_GLOBAL_ENABLE: u64 = 1 << 11;
 
+// CPUID.01H:ECX
+const CPUID_X2APIC: u32 = 1 << 21;
+
+// x2APIC registers are MSRs at this base plus the xAPIC offset / 16
+const X2APIC_MSR_BASE: u32 = 0x800;
+
 const SPIV_SOFTWARE_ENABLE: u32 = 1 << 8;
 
+// Interrupt Command Register fields
+const ICR_DELIVERY_FIXED: u64 = 0 << 8;
+const ICR_DELIVERY_NMI: u64 = 4 << 8;
+const ICR_DELIVERY_INIT: u64 = 5 << 8;
+const ICR_DELIVERY_STARTUP: u64 = 6 << 8;
+const ICR_DELIVERY_PENDING: u64 = 1 << 12;
+const ICR_LEVEL_ASSERT: u64 = 1 << 14;
+const ICR_SHORTHAND_SELF: u64 = 1 << 18;
+const ICR_SHORTHAND_ALL: u64 = 2 << 18;
+const ICR_SHORTHAND_ALL_BUT_SELF: u64 = 3 << 18;
+
+/// Vector for local APIC internal errors
+pub const ERROR_VECTOR: u8 = 0xfe;
+/// Vector for spurious interrupts, which must not be acknowledged
+pub const SPURIOUS_VECTOR: u8 = 0xff;
+
+bitflags! {
+    /// Error Status Register bits
+    pub flags ErrorStatus: u32 {
+        const ESR_SEND_CHECKSUM = 1 << 0,
+        const ESR_RECEIVE_CHECKSUM = 1 << 1,
+        const ESR_SEND_ACCEPT = 1 << 2,
+        const ESR_RECEIVE_ACCEPT = 1 << 3,
+        const ESR_REDIRECTABLE_IPI = 1 << 4,
+        const ESR_SEND_ILLEGAL_VECTOR = 1 << 5,
+        const ESR_RECEIVE_ILLEGAL_VECTOR = 1 << 6,
+        const ESR_ILLEGAL_REGISTER = 1 << 7,
+    }
+}
+
+static SPURIOUS: AtomicUsize = ATOMIC_USIZE_INIT;
+
+/// Returns the number of spurious interrupts received so far
+pub fn spurious_count() -> usize {
+    SPURIOUS.load(Ordering::Relaxed)
+}
+
+fn spurious_interrupt(_frame: &mut TrapFrame) {
+    SPURIOUS.fetch_add(1, Ordering::Relaxed);
+}
+
+fn error_interrupt(_frame: &mut TrapFrame) {
+    let status = local().error_status();
+    error!("Local APIC error: {:?}", status);
+}
+
+/// Set in an LVT entry to mask its interrupt
+pub const LVT_MASKED: u32 = 1 << 16;
+
+/// Local APIC timer modes, as encoded in `LVTT`
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TimerMode {
+    OneShot = 0,
+    Periodic = 1 << 17,
+    TscDeadline = 2 << 17,
+}
+
+/// Local APIC timer divide configuration, as encoded in `TMDCR`
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TimerDivide {
+    By1 = 0xb,
+    By2 = 0x0,
+    By4 = 0x1,
+    By8 = 0x2,
+    By16 = 0x3,
+    By32 = 0x8,
+    By64 = 0x9,
+    By128 = 0xa,
+}
+
+/// Which CPUs an inter-processor interrupt is sent to
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Destination {
+    /// The CPU with this local APIC ID
+    Cpu(u32),
+    /// The sending CPU
+    SelfOnly,
+    /// Every CPU, including the sender
+    All,
+    /// Every CPU except the sender
+    AllButSelf,
+}
+
+/// How an inter-processor interrupt is delivered
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Delivery {
+    /// As an ordinary interrupt on this vector
+    Fixed(u8),
+    /// As a non-maskable interrupt
+    Nmi,
+}
+
+/// Errors returned by `send_ipi`
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum IpiError {
+    /// NMIs can only be sent to a CPU or with `Destination::AllButSelf`
+    NmiShorthand,
+}
+
+/// How the local APIC registers are accessed
+#[derive(Clone, Copy, Debug)]
+enum Mode {
+    /// Memory mapped at `base_addr`
+    XApic { base_addr: VAddr },
+    /// Through MSRs
+    X2Apic,
+}
+
+/// Handle to the local APIC
+///
+/// Every CPU sees its own local APIC at the same address (or MSRs). Each
+/// CPU keeps its own handle in its `PerCpu`, see `local`.
+#[derive(Clone, Copy, Debug)]
 pub struct Apic {
-    base_addr: VAddr,
+    mode: Mode,
+}
+
+// How every CPU accesses its local APIC, chosen by the BSP
+static MODE: spin::Once<Mode> = spin::Once::new();
+
+/// Returns the calling CPU's local APIC, if its `PerCpu` is set up
+pub fn try_local() -> Option<&'static Apic> {
+    percpu::try_current().map(|percpu| percpu.apic())
+}
+
+/// Returns the calling CPU's local APIC
+pub fn local() -> &'static Apic {
+    try_local().expect("Local APIC used before initialization")
 }
 
 impl Apic {
+    /// Choose the access mode and enable the BSP's local APIC
     pub unsafe fn init<Allocator>(page_table: &mut PageTable,
                                   allocator: &Allocator)
+                                  -> Apic
         where Allocator: FrameAllocator
     {
+        assert_has_not_been_called!("Apic::init() function \
+                                     must only be called once");
         let apic_base = rdmsr(APIC_BASE);
-        let apic_paddr = PAddr::from_u64(apic_base & !0xfff);
-        let apic_vaddr = phys_to_virt(apic_paddr);
-        let frame = Frame::down(apic_paddr);
-        let page = Page::down(apic_vaddr);
-        page_table.map_device(page,
-                              frame,
-                              allocator,
-                              |f: Frame| frame_to_slice(f));
-        wrmsr(APIC_BASE, apic_base | BASE_GLOBAL_ENABLE);
-        let mut apic = Apic { base_addr: apic_vaddr };
-        apic.write(Reg::SPIV, SPIV_SOFTWARE_ENABLE);
-    }
-
-    fn get_ptr(&self, reg: Reg) -> *mut u32 {
-        unsafe {
-            (self.base_addr.as_usize() as *mut u8)
-                .offset(reg as isize) as *mut u32
+        let mode = if cpuid!(1).ecx & CPUID_X2APIC != 0 {
+            info!("Using x2APIC mode");
+            Mode::X2Apic
+        } else {
+            let apic_paddr = PAddr::from_u64(apic_base & !0xfff);
+            let apic_vaddr = phys_to_virt(apic_paddr);
+            let frame = Frame::down(apic_paddr);
+            let page = Page::down(apic_vaddr);
+            page_table.map_device(page,
+                                  frame,
+                                  allocator,
+                                  |f: Frame| frame_to_slice(f));
+            Mode::XApic { base_addr: apic_vaddr }
+        };
+        let apic = Apic { mode: *MODE.call_once(|| mode) };
+        apic.global_enable();
+        idt::register(SPURIOUS_VECTOR,
+                      spurious_interrupt,
+                      "apic",
+                      Priority::System)
+            .expect("Could not register the spurious interrupt handler");
+        idt::register_apic(ERROR_VECTOR,
+                           error_interrupt,
+                           "apic",
+                           Priority::System)
+            .expect("Could not register the APIC error handler");
+        apic.enable();
+        apic
+    }
+
+    /// Enable this CPU's local APIC in the mode chosen at `init`
+    ///
+    /// Going from disabled straight to x2APIC mode raises #GP, so xAPIC
+    /// mode is entered first.
+    unsafe fn global_enable(&self) {
+        let apic_base = rdmsr(APIC_BASE) | BASE_GLOBAL_ENABLE;
+        wrmsr(APIC_BASE, apic_base);
+        if let Mode::X2Apic = self.mode {
+            wrmsr(APIC_BASE, apic_base | BASE_X2APIC_ENABLE);
+        }
+    }
+
+    /// Enable the local APIC of an application processor
+    ///
+    /// `init` must have been called on the BSP.
+    pub unsafe fn init_ap() -> Apic {
+        let mode = *MODE.try().expect("AP started before the BSP's APIC");
+        let apic = Apic { mode: mode };
+        apic.global_enable();
+        apic.enable();
+        apic
+    }
+
+    /// Returns whether registers are accessed through x2APIC MSRs
+    pub fn is_x2apic(&self) -> bool {
+        match self.mode {
+            Mode::X2Apic => true,
+            Mode::XApic { .. } => false,
+        }
+    }
+
+    /// Returns this CPU's local APIC ID
+    pub fn id(&self) -> u32 {
+        match self.mode {
+            Mode::X2Apic => self.read(Reg::ID),
+            Mode::XApic { .. } => self.read(Reg::ID) >> 24,
         }
     }
 
-    fn write(&mut self, reg: Reg, value: u32) {
-        unsafe {
-            ptr::write_volatile(self.get_ptr(reg), value);
+    /// Software enable this CPU's local APIC and unmask its error interrupt
+    fn enable(&self) {
+        self.write(Reg::SPIV, SPIV_SOFTWARE_ENABLE | SPURIOUS_VECTOR as u32);
+        self.write(Reg::LVTERR, ERROR_VECTOR as u32);
+        // Clear any errors latched before the LVT was set up
+        self.error_status();
+    }
+
+    /// Signal the end of the interrupt being serviced
+    ///
+    /// This is called by the interrupt dispatch path, handlers should not
+    /// call it themselves.
+    pub fn eoi(&self) {
+        self.write(Reg::EOR, 0);
+    }
+
+    /// Returns whether `vector` was accepted by this local APIC and is
+    /// still waiting for an EOI
+    pub fn in_service(&self, vector: u8) -> bool {
+        let word = self.read_offset(Reg::ISR as usize +
+                                    0x10 * (vector as usize / 32));
+        word & (1 << (vector % 32)) != 0
+    }
+
+    /// Read and clear the Error Status Register
+    pub fn error_status(&self) -> ErrorStatus {
+        // The ESR is latched by a write
+        self.write(Reg::ESR, 0);
+        ErrorStatus::from_bits_truncate(self.read(Reg::ESR))
+    }
+
+    /// Program the timer LVT, divider and initial count
+    ///
+    /// An `initial` count of zero stops the timer. It is ignored in
+    /// `TimerMode::TscDeadline`, use `set_tsc_deadline` instead.
+    pub fn set_timer(&self,
+                     mode: TimerMode,
+                     vector: u8,
+                     masked: bool,
+                     divide: TimerDivide,
+                     initial: u32) {
+        let mut lvt = mode as u32 | vector as u32;
+        if masked {
+            lvt |= LVT_MASKED;
+        }
+        self.write(Reg::TMDCR, divide as u32);
+        self.write(Reg::LVTT, lvt);
+        if mode != TimerMode::TscDeadline {
+            self.write(Reg::TMICT, initial);
+        }
+    }
+
+    /// Arm the timer (in `TimerMode::TscDeadline`) to fire at `tsc`
+    pub fn set_tsc_deadline(&self, tsc: u64) {
+        unsafe { wrmsr(IA32_TSC_DEADLINE, tsc) };
+    }
+
+    /// Returns the timer's current count
+    pub fn timer_count(&self) -> u32 {
+        self.read(Reg::TMCCT)
+    }
+
+    fn get_ptr(base_addr: VAddr, offset: usize) -> *mut u32 {
+        (base_addr.as_usize() + offset) as *mut u32
+    }
+
+    fn msr(offset: usize) -> u32 {
+        X2APIC_MSR_BASE + (offset as u32 >> 4)
+    }
+
+    fn read(&self, reg: Reg) -> u32 {
+        self.read_offset(reg as usize)
+    }
+
+    // For the register arrays, such as the 8 ISR words following `Reg::ISR`
+    fn read_offset(&self, offset: usize) -> u32 {
+        match self.mode {
+            Mode::XApic { base_addr } => unsafe {
+                ptr::read_volatile(Apic::get_ptr(base_addr, offset))
+            },
+            Mode::X2Apic => unsafe { rdmsr(Apic::msr(offset)) as u32 },
+        }
+    }
+
+    fn write(&self, reg: Reg, value: u32) {
+        match self.mode {
+            Mode::XApic { base_addr } => unsafe {
+                ptr::write_volatile(Apic::get_ptr(base_addr, reg as usize),
+                                    value);
+            },
+            Mode::X2Apic => unsafe {
+                wrmsr(Apic::msr(reg as usize), value as u64)
+            },
+        }
+    }
+
+    /// Write the full Interrupt Command Register
+    ///
+    /// The destination is in the high 32 bits. Writing the low half is what
+    /// sends the interrupt, so it goes last in xAPIC mode.
+    fn write_icr(&self, value: u64) {
+        match self.mode {
+            Mode::XApic { .. } => {
+                self.write(Reg::ICR2, (value >> 32) as u32);
+                self.write(Reg::ICR, value as u32);
+            }
+            Mode::X2Apic => unsafe {
+                wrmsr(Apic::msr(Reg::ICR as usize), value)
+            },
+        }
+    }
+
+    /// Returns the ICR destination field addressing `apic_id`
+    fn icr_destination(&self, apic_id: u32) -> u64 {
+        match self.mode {
+            Mode::XApic { .. } => (apic_id as u64) << 56,
+            Mode::X2Apic => (apic_id as u64) << 32,
+        }
+    }
+
+    /// Wait for the last interrupt command to be accepted
+    ///
+    /// x2APIC ICR writes are not posted, so there is nothing to wait for.
+    fn wait_icr_idle(&self) {
+        if let Mode::XApic { .. } = self.mode {
+            while self.read(Reg::ICR) as u64 & ICR_DELIVERY_PENDING != 0 {
+                unsafe { asm!("pause" :::: "volatile") };
+            }
+        }
+    }
+
+    /// Send an inter-processor interrupt
+    ///
+    /// Returns once the local APIC has accepted the interrupt for delivery.
+    pub fn send_ipi(&self,
+                    dest: Destination,
+                    delivery: Delivery)
+                    -> Result<(), IpiError> {
+        let mode = match delivery {
+            Delivery::Fixed(vector) => {
+                assert!(vector >= idt::FIRST_EXTERNAL_VECTOR);
+                if dest == Destination::SelfOnly && self.is_x2apic() {
+                    // x2APIC has a dedicated, faster register for this
+                    self.write(Reg::SELF_IPI, vector as u32);
+                    return Ok(());
+                }
+                ICR_DELIVERY_FIXED | vector as u64
+            }
+            // The SDM makes NMIs with these shorthands invalid
+            Delivery::Nmi if dest == Destination::SelfOnly ||
+                             dest == Destination::All => {
+                return Err(IpiError::NmiShorthand);
+            }
+            Delivery::Nmi => ICR_DELIVERY_NMI,
         };
+        let dest = match dest {
+            Destination::Cpu(apic_id) => self.icr_destination(apic_id),
+            Destination::SelfOnly => ICR_SHORTHAND_SELF,
+            Destination::All => ICR_SHORTHAND_ALL,
+            Destination::AllButSelf => ICR_SHORTHAND_ALL_BUT_SELF,
+        };
+        // An interrupt handler sending its own IPI between the two xAPIC
+        // ICR writes would corrupt this one
+        idt::without_interrupts(|| {
+            self.write_icr(dest | mode | ICR_LEVEL_ASSERT);
+            self.wait_icr_idle();
+        });
+        Ok(())
+    }
+
+    /// Send an INIT IPI to the CPU with local APIC ID `apic_id`
+    pub fn send_init(&self, apic_id: u32) {
+        self.write_icr(self.icr_destination(apic_id) | ICR_DELIVERY_INIT |
+                       ICR_LEVEL_ASSERT);
+        self.wait_icr_idle();
+    }
+
+    /// Send a Startup IPI to the CPU with local APIC ID `apic_id`
+    ///
+    /// It starts executing in real mode at the start of physical `page`.
+    pub fn send_startup(&self, apic_id: u32, page: u8) {
+        self.write_icr(self.icr_destination(apic_id) |
+                       ICR_DELIVERY_STARTUP | page as u64);
+        self.wait_icr_idle();
     }
 }
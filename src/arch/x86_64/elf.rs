@@ -0,0 +1,216 @@
+// Copyright Dan Schatzberg, 2016. This file is part of Genesis.
+
+// Genesis is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// Genesis is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+
+// You should have received a copy of the GNU Affero General Public License
+// along with Genesis.  If not, see <http://www.gnu.org/licenses/>.
+use memory::{PAGE_SIZE, USER_END, VAddr};
+
+const EHDR_SIZE: usize = 64;
+const PHDR_SIZE: usize = 56;
+
+const ELFCLASS64: u8 = 2;
+const ELFDATA2LSB: u8 = 1;
+const EV_CURRENT: u8 = 1;
+const ET_EXEC: u16 = 2;
+const EM_X86_64: u16 = 62;
+
+const PT_LOAD: u32 = 1;
+const PF_X: u32 = 1 << 0;
+const PF_W: u32 = 1 << 1;
+
+/// Errors returned by `Elf::parse`
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ElfError {
+    /// The image is too short for the headers it describes
+    Truncated,
+    /// The image does not start with the ELF magic number
+    BadMagic,
+    /// The image is not a little endian x86_64 ELF64 executable
+    Unsupported,
+    /// A segment lies outside the image or outside user memory
+    BadSegment,
+    /// A segment is both writable and executable
+    WritableExecutable,
+    /// The entry point is not in an executable segment
+    BadEntry,
+}
+
+/// A validated ELF64 executable
+#[derive(Clone, Copy, Debug)]
+pub struct Elf<'a> {
+    image: &'a [u8],
+    entry: u64,
+    phoff: usize,
+    phnum: usize,
+}
+
+/// A loadable segment of an `Elf`
+#[derive(Clone, Copy, Debug)]
+pub struct Segment<'a> {
+    vaddr: u64,
+    memsz: u64,
+    flags: u32,
+    data: &'a [u8],
+}
+
+fn read_u16(image: &[u8], offset: usize) -> u16 {
+    image[offset] as u16 | (image[offset + 1] as u16) << 8
+}
+
+fn read_u32(image: &[u8], offset: usize) -> u32 {
+    read_u16(image, offset) as u32 | (read_u16(image, offset + 2) as u32) << 16
+}
+
+fn read_u64(image: &[u8], offset: usize) -> u64 {
+    read_u32(image, offset) as u64 | (read_u32(image, offset + 4) as u64) << 32
+}
+
+impl<'a> Elf<'a> {
+    /// Check the headers of `image` and every loadable segment
+    pub fn parse(image: &'a [u8]) -> Result<Elf<'a>, ElfError> {
+        if image.len() < EHDR_SIZE {
+            return Err(ElfError::Truncated);
+        }
+        if &image[..4] != b"\x7fELF" {
+            return Err(ElfError::BadMagic);
+        }
+        if image[4] != ELFCLASS64 || image[5] != ELFDATA2LSB ||
+           image[6] != EV_CURRENT ||
+           read_u16(image, 16) != ET_EXEC ||
+           read_u16(image, 18) != EM_X86_64 ||
+           read_u16(image, 54) as usize != PHDR_SIZE {
+            return Err(ElfError::Unsupported);
+        }
+        let elf = Elf {
+            image: image,
+            entry: read_u64(image, 24),
+            phoff: read_u64(image, 32) as usize,
+            phnum: read_u16(image, 56) as usize,
+        };
+        let phdrs_end = elf.phnum
+            .checked_mul(PHDR_SIZE)
+            .and_then(|size| size.checked_add(elf.phoff));
+        match phdrs_end {
+            Some(end) if end <= image.len() => {}
+            _ => return Err(ElfError::Truncated),
+        }
+        let mut entry_ok = false;
+        for i in 0..elf.phnum {
+            if let Some(segment) = try!(elf.segment(i)) {
+                if segment.writable() && segment.executable() {
+                    return Err(ElfError::WritableExecutable);
+                }
+                entry_ok |= segment.executable() &&
+                            segment.vaddr <= elf.entry &&
+                            elf.entry < segment.vaddr + segment.memsz;
+            }
+        }
+        if !entry_ok {
+            return Err(ElfError::BadEntry);
+        }
+        Ok(elf)
+    }
+
+    /// Returns the entry point
+    pub fn entry(&self) -> VAddr {
+        VAddr::from_usize(self.entry as usize)
+    }
+
+    /// Returns the loadable segments
+    pub fn segments(&self) -> Segments<'a> {
+        Segments {
+            elf: *self,
+            next: 0,
+        }
+    }
+
+    /// Returns program header `i` if it is a valid loadable segment
+    fn segment(&self, i: usize) -> Result<Option<Segment<'a>>, ElfError> {
+        let phdr = &self.image[self.phoff + i * PHDR_SIZE..];
+        if read_u32(phdr, 0) != PT_LOAD {
+            return Ok(None);
+        }
+        let offset = read_u64(phdr, 8);
+        let vaddr = read_u64(phdr, 16);
+        let filesz = read_u64(phdr, 32);
+        let memsz = read_u64(phdr, 40);
+        let file_end = offset.checked_add(filesz);
+        let mem_end = vaddr.checked_add(memsz);
+        let valid = match (file_end, mem_end) {
+            (Some(file_end), Some(mem_end)) => {
+                filesz <= memsz && file_end <= self.image.len() as u64 &&
+                vaddr >= PAGE_SIZE && mem_end <= USER_END as u64
+            }
+            _ => false,
+        };
+        if !valid {
+            return Err(ElfError::BadSegment);
+        }
+        let data = &self.image[offset as usize..(offset + filesz) as usize];
+        Ok(Some(Segment {
+            vaddr: vaddr,
+            memsz: memsz,
+            flags: read_u32(phdr, 4),
+            data: data,
+        }))
+    }
+}
+
+/// Iterator over the loadable segments of an `Elf`
+#[derive(Clone, Copy, Debug)]
+pub struct Segments<'a> {
+    elf: Elf<'a>,
+    next: usize,
+}
+
+impl<'a> Iterator for Segments<'a> {
+    type Item = Segment<'a>;
+
+    fn next(&mut self) -> Option<Segment<'a>> {
+        while self.next < self.elf.phnum {
+            let i = self.next;
+            self.next += 1;
+            // Every segment was checked by Elf::parse
+            if let Ok(Some(segment)) = self.elf.segment(i) {
+                return Some(segment);
+            }
+        }
+        None
+    }
+}
+
+impl<'a> Segment<'a> {
+    /// Returns the address the segment is loaded at
+    pub fn vaddr(&self) -> VAddr {
+        VAddr::from_usize(self.vaddr as usize)
+    }
+
+    /// Returns the size of the segment in memory, including its BSS
+    pub fn memsz(&self) -> usize {
+        self.memsz as usize
+    }
+
+    /// Returns the bytes loaded from the image, the rest is zero
+    pub fn data(&self) -> &'a [u8] {
+        self.data
+    }
+
+    /// Returns whether the segment is mapped writable
+    pub fn writable(&self) -> bool {
+        self.flags & PF_W != 0
+    }
+
+    /// Returns whether the segment is mapped executable
+    pub fn executable(&self) -> bool {
+        self.flags & PF_X != 0
+    }
+}
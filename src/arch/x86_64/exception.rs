@@ -0,0 +1,58 @@
+// Copyright Dan Schatzberg, 2016. This file is part of Genesis.
+
+// Genesis is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// Genesis is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+
+// You should have received a copy of the GNU Affero General Public License
+// along with Genesis.  If not, see <http://www.gnu.org/licenses/>.
+use x86::msr::*;
+use super::idt::{self, DOUBLE_FAULT_VECTOR, MACHINE_CHECK_VECTOR,
+                 NMI_VECTOR, Priority, TrapFrame};
+use super::ipi;
+
+/// Install handlers for the exceptions that run on IST stacks
+pub fn init() {
+    register(DOUBLE_FAULT_VECTOR, double_fault);
+    register(NMI_VECTOR, nmi);
+    register(MACHINE_CHECK_VECTOR, machine_check);
+}
+
+fn register(vector: u8, handler: idt::Handler) {
+    idt::register(vector, handler, "exception", Priority::Kernel)
+        .expect("Could not register exception handler");
+}
+
+fn halt() -> ! {
+    loop {
+        unsafe {
+            asm!("cli; hlt" :::: "volatile");
+        }
+    }
+}
+
+// Both are fatal and may hit while this CPU holds the logger lock, so the
+// other CPUs are stopped first, after which the logger takes no lock
+fn double_fault(frame: &mut TrapFrame) {
+    ipi::stop_others();
+    error!("Double Fault\n{}", frame);
+    halt();
+}
+
+// Stop NMIs from `ipi::stop_others` never get here
+fn nmi(frame: &mut TrapFrame) {
+    warn!("Non-maskable interrupt at {:#x}", frame.rip);
+}
+
+fn machine_check(frame: &mut TrapFrame) {
+    ipi::stop_others();
+    let status = unsafe { rdmsr(IA32_MCG_STATUS) };
+    error!("Machine Check (MCG_STATUS {:#x})\n{}", status, frame);
+    halt();
+}
@@ -0,0 +1,126 @@
+// Copyright Dan Schatzberg, 2016. This file is part of Genesis.
+
+// Genesis is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// Genesis is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+
+// You should have received a copy of the GNU Affero General Public License
+// along with Genesis.  If not, see <http://www.gnu.org/licenses/>.
+use cmdline;
+use core::cmp;
+use core::sync::atomic::{ATOMIC_USIZE_INIT, AtomicUsize, Ordering};
+use memory::*;
+use memory::frame_cache;
+use spin;
+use x86::time::rdtsc;
+use super::smp::{self, MAX_CPUS};
+
+/// Frames each CPU holds at once
+const FRAMES: usize = 16;
+/// Times each CPU allocates and then frees `FRAMES` frames per phase
+const ROUNDS: usize = 10000;
+
+// Every CPU runs the same loop once with the frame caches off, that is
+// with every operation taking the global allocator's lock, then once with
+// them on
+const PHASES: usize = 2;
+const CACHED: usize = 1;
+const PHASE_NAMES: [&'static str; PHASES] = ["Single lock", "Per-CPU cache"];
+
+static ALLOCATOR: spin::Once<&'static KernelAllocator> = spin::Once::new();
+// Number of phases started so far
+static STARTED: AtomicUsize = ATOMIC_USIZE_INIT;
+static FINISHED: [AtomicUsize; PHASES] = [ATOMIC_USIZE_INIT,
+                                          ATOMIC_USIZE_INIT];
+static CYCLES: spin::Mutex<[[u64; MAX_CPUS]; PHASES]> =
+    spin::Mutex::new([[0; MAX_CPUS]; PHASES]);
+
+/// Arm the benchmark if `frame_bench` is on the command line
+///
+/// Must be called before the APs are started, which then wait in
+/// `ap_run` for the BSP to call `run`.
+pub fn init(allocator: &'static KernelAllocator) {
+    if cmdline::get().flag("frame_bench") {
+        ALLOCATOR.call_once(|| allocator);
+    }
+}
+
+/// Take part in the benchmark as CPU `cpu`, if it is armed
+///
+/// Returns once the last phase is over on this CPU.
+pub fn ap_run(cpu: usize) {
+    let allocator = match ALLOCATOR.try() {
+        Some(allocator) => *allocator,
+        None => return,
+    };
+    for phase in 0..PHASES {
+        while STARTED.load(Ordering::SeqCst) <= phase {
+            unsafe { asm!("pause" :::: "volatile") };
+        }
+        run_phase(allocator, cpu, phase);
+    }
+}
+
+/// Run the benchmark on every online CPU, if it is armed, and log the
+/// results
+///
+/// Must be called on the BSP once `smp::init` has returned.
+pub fn run() {
+    let allocator = match ALLOCATOR.try() {
+        Some(allocator) => *allocator,
+        None => return,
+    };
+    let cpus = smp::online();
+    info!("Benchmarking the {} frame allocator on {} CPUs",
+          allocator.name(),
+          cpus);
+    let enabled = frame_cache::enabled();
+    for phase in 0..PHASES {
+        frame_cache::set_enabled(phase == CACHED);
+        STARTED.store(phase + 1, Ordering::SeqCst);
+        run_phase(allocator, 0, phase);
+        while FINISHED[phase].load(Ordering::SeqCst) < cpus {
+            unsafe { asm!("pause" :::: "volatile") };
+        }
+        report(phase, cpus);
+    }
+    frame_cache::set_enabled(enabled);
+    frame_cache::log_stats();
+}
+
+fn run_phase(allocator: &KernelAllocator, cpu: usize, phase: usize) {
+    let mut frames = [Frame::down(PAddr::from_u64(0)); FRAMES];
+    let start = unsafe { rdtsc() };
+    for _ in 0..ROUNDS {
+        for frame in frames.iter_mut() {
+            *frame = allocator.allocate_manual()
+                .expect("Out of frames in the frame benchmark");
+        }
+        for frame in frames.iter().rev() {
+            unsafe { allocator.free_manual(*frame) };
+        }
+    }
+    let cycles = unsafe { rdtsc() } - start;
+    if let Some(slot) = CYCLES.lock()[phase].get_mut(cpu) {
+        *slot = cycles;
+    }
+    FINISHED[phase].fetch_add(1, Ordering::SeqCst);
+}
+
+fn report(phase: usize, cpus: usize) {
+    let cycles = CYCLES.lock();
+    let cycles = &cycles[phase][..cmp::min(cpus, MAX_CPUS)];
+    let total = cycles.iter().fold(0, |total, &c| total + c);
+    let slowest = cycles.iter().fold(0, |slowest, &c| cmp::max(slowest, c));
+    let ops = (ROUNDS * FRAMES) as u64;
+    info!("{}: {} cycles per allocation and free, {} on the slowest CPU",
+          PHASE_NAMES[phase],
+          total / (ops * cycles.len() as u64),
+          slowest / ops);
+}
@@ -12,60 +12,136 @@
 
 // You should have received a copy of the GNU Affero General Public License
 // along with Genesis.  If not, see <http://www.gnu.org/licenses/>.
+use core::cell::UnsafeCell;
+use core::fmt;
 use core::u32;
 use core::mem;
-use spin;
+use core::ptr;
 use x86::dtables::*;
 use x86::segmentation::*;
 use x86::task::*;
-use memory::VAddr;
+use memory::{Frame, PAGE_SIZE, VAddr, frame_to_slice};
 
-lazy_static! {
-    static ref TSS: spin::RwLock<TaskStateSegment> = {
-        spin::RwLock::new(TaskStateSegment::new())
-    };
+const GDT_ENTRIES: usize = 7;
 
-    static ref GDT: [SegmentDescriptor; 7] = {
-        let tss_addr = {
-            let ptr: *const TaskStateSegment = &*TSS.write();
-            ptr as u64
-        };
+fn descriptors(tss_addr: u64) -> [SegmentDescriptor; GDT_ENTRIES] {
+    [SegmentDescriptor::empty(), // NULL Descriptor
+     SegmentDescriptor::new(0, u32::MAX) |
+     TYPE_C_ER | DESC_S | DESC_DPL0 | DESC_P | DESC_L | DESC_G,
+     SegmentDescriptor::new(0, u32::MAX) |
+     TYPE_D_RW | DESC_S | DESC_DPL0 | DESC_P | DESC_G,
+     SegmentDescriptor::new(0, u32::MAX) |
+     TYPE_D_RW | DESC_S | DESC_DPL3 | DESC_P,
+     SegmentDescriptor::new(0, u32::MAX) |
+     TYPE_C_ER | DESC_S | DESC_DPL3 | DESC_P | DESC_L | DESC_G,
+     {
+         SegmentDescriptor::new((tss_addr & 0xFFFF_FFFF) as u32,
+                                mem::size_of::<TaskStateSegment>() as u32)
+             | TYPE_SYS_TSS_AVAILABLE | DESC_DPL0 | DESC_P
+     },
+     unsafe { mem::transmute(tss_addr >> 32) }
+     ]
+}
+
+/// Kernel code selector
+pub const KERNEL_CS: u16 = 0x08;
+/// Kernel data and stack selector
+pub const KERNEL_DS: u16 = 0x10;
+/// User data and stack selector, with RPL 3
+pub const USER_DS: u16 = 0x1b;
+/// User code selector, with RPL 3
+pub const USER_CS: u16 = 0x23;
 
-        [SegmentDescriptor::empty(), // NULL Descriptor
-         SegmentDescriptor::new(0, u32::MAX) |
-         TYPE_C_ER | DESC_S | DESC_DPL0 | DESC_P | DESC_L | DESC_G,
-         SegmentDescriptor::new(0, u32::MAX) |
-         TYPE_D_RW | DESC_S | DESC_DPL0 | DESC_P | DESC_G,
-         SegmentDescriptor::new(0, u32::MAX) |
-         TYPE_D_RW | DESC_S | DESC_DPL3 | DESC_P,
-         SegmentDescriptor::new(0, u32::MAX) |
-         TYPE_C_ER | DESC_S | DESC_DPL3 | DESC_P | DESC_L | DESC_G,
-         {
-             SegmentDescriptor::new((tss_addr & 0xFFFF_FFFF) as u32,
-                                    mem::size_of::<TaskStateSegment>() as u32)
-                 | TYPE_SYS_TSS_AVAILABLE | DESC_DPL0 | DESC_P
-         },
-         unsafe { mem::transmute(tss_addr >> 32) }
-         ]
-    };
+/// Number of Interrupt Stack Table entries in use
+pub const IST_STACKS: usize = 3;
+/// IST index (1-based) of the double fault stack
+pub const DOUBLE_FAULT_IST: u8 = 1;
+/// IST index (1-based) of the NMI stack
+pub const NMI_IST: u8 = 2;
+/// IST index (1-based) of the machine check stack
+pub const MACHINE_CHECK_IST: u8 = 3;
+
+/// A GDT and the TSS it describes, one per CPU
+///
+/// The TSS holds the stacks the CPU switches to on interrupts, so it can
+/// be updated after the GDT is loaded.
+#[repr(C)]
+pub struct Gdt {
+    descriptors: [SegmentDescriptor; GDT_ENTRIES],
+    tss: UnsafeCell<TaskStateSegment>,
 }
 
-/// Reset the GDT
-pub unsafe fn reset(stack: VAddr) {
-    let gdt_ptr = {
-        let gdtp: *const _ = &*GDT;
-        let gdt_size = (mem::size_of::<SegmentDescriptor>() * GDT.len() -
-                        1) as u16;
-        DescriptorTablePointer {
-            limit: gdt_size,
-            base: gdtp as u64,
+impl Gdt {
+    /// Build a `Gdt` in `frame`
+    ///
+    /// `frame` must be unused and reachable through `PHYS_MAP`; it is owned
+    /// by the `Gdt` from then on. `stack` is used on entry from user mode
+    /// and `ist` are the Interrupt Stack Table stacks, indexed by the `*_IST`
+    /// constants less one.
+    pub unsafe fn new(frame: Frame,
+                      stack: VAddr,
+                      ist: &[VAddr; IST_STACKS])
+                      -> &'static Gdt {
+        debug_assert!(mem::size_of::<Gdt>() <= PAGE_SIZE as usize);
+        let gdt: *mut Gdt = frame_to_slice(frame).as_mut_ptr() as *mut _;
+        ptr::write(gdt,
+                   Gdt {
+                       descriptors: [SegmentDescriptor::empty(); GDT_ENTRIES],
+                       tss: UnsafeCell::new(TaskStateSegment::new()),
+                   });
+        let gdt = &mut *gdt;
+        gdt.descriptors = descriptors(gdt.tss.get() as u64);
+        gdt.set_rsp0(stack);
+        for (i, addr) in ist.iter().enumerate() {
+            gdt.set_ist(i as u8 + 1, *addr);
         }
-    };
-    let mut tss = TSS.write();
-    tss.rsp[0] = stack.as_usize() as u64;
+        gdt
+    }
+
+    /// Load the GDT and TSS on the calling CPU
+    ///
+    /// A `Gdt` must only be loaded on one CPU.
+    pub unsafe fn load(&'static self) {
+        let gdt_ptr = {
+            let gdtp: *const _ = &self.descriptors;
+            let gdt_size = (mem::size_of::<SegmentDescriptor>() *
+                            GDT_ENTRIES - 1) as u16;
+            DescriptorTablePointer {
+                limit: gdt_size,
+                base: gdtp as u64,
+            }
+        };
+        lgdt(&gdt_ptr);
+        load_cs(SegmentSelector::new(1));
+        load_ss(SegmentSelector::new(2));
+        load_ltr(SegmentSelector::new(5));
+    }
+
+    /// Returns the stack used on interrupts from user mode
+    pub fn rsp0(&self) -> VAddr {
+        let rsp0 = unsafe { (*self.tss.get()).rsp[0] };
+        VAddr::from_usize(rsp0 as usize)
+    }
+
+    /// Set the stack used on interrupts from user mode
+    ///
+    /// Only the CPU the `Gdt` is loaded on may call this, typically when
+    /// switching threads.
+    pub fn set_rsp0(&self, stack: VAddr) {
+        unsafe { (*self.tss.get()).rsp[0] = stack.as_usize() as u64 };
+    }
+
+    /// Set Interrupt Stack Table entry `ist` (1-based)
+    pub fn set_ist(&self, ist: u8, stack: VAddr) {
+        assert!(ist >= 1 && ist as usize <= IST_STACKS);
+        unsafe {
+            (*self.tss.get()).ist[ist as usize - 1] = stack.as_usize() as u64
+        };
+    }
+}
 
-    lgdt(&gdt_ptr);
-    load_cs(SegmentSelector::new(1));
-    load_ss(SegmentSelector::new(2));
-    load_ltr(SegmentSelector::new(5));
+impl fmt::Debug for Gdt {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Gdt {{ rsp0: {:#X} }}", self.rsp0())
+    }
 }
@@ -14,50 +14,356 @@
 // along with Genesis.  If not, see <http://www.gnu.org/licenses/>.
 #![allow(trivial_casts)]
 
+use core::fmt;
 use core::mem;
+use core::sync::atomic::{ATOMIC_USIZE_INIT, AtomicUsize, Ordering};
+use spin;
 use x86::dtables::*;
 use x86::irq::*;
+use super::apic;
+use super::gdt::{DOUBLE_FAULT_IST, MACHINE_CHECK_IST, NMI_IST};
+use super::ipi;
 use super::mem::VAddr;
+use super::user;
 
+/// Machine state saved on interrupt entry, in stack order
+///
+/// The general purpose registers are pushed by `int_common` (or
+/// `int_paranoid`), the vector and error code by the per-vector stub (zero
+/// for vectors without one) and the rest by the CPU. Changes made by a
+/// handler are restored by `iretq`.
 #[derive(Clone, Copy, Debug)]
-#[repr(C, packed)]
-struct ExceptionFrame {
-    pub rax: u64,
-    pub rbx: u64,
-    pub rcx: u64,
-    pub rdx: u64,
-    pub rsi: u64,
-    pub rdi: u64,
-    pub rbp: u64,
-    pub r8: u64,
-    pub r9: u64,
-    pub r10: u64,
-    pub r11: u64,
-    pub r12: u64,
-    pub r13: u64,
-    pub r14: u64,
+#[repr(C)]
+pub struct TrapFrame {
     pub r15: u64,
+    pub r14: u64,
+    pub r13: u64,
+    pub r12: u64,
+    pub r11: u64,
+    pub r10: u64,
+    pub r9: u64,
+    pub r8: u64,
+    pub rbp: u64,
+    pub rdi: u64,
+    pub rsi: u64,
+    pub rdx: u64,
+    pub rcx: u64,
+    pub rbx: u64,
+    pub rax: u64,
+    pub vector: u64,
+    pub error_code: u64,
+    pub rip: u64,
+    pub cs: u64,
+    pub rflags: u64,
+    pub rsp: u64,
+    pub ss: u64,
+}
+
+impl TrapFrame {
+    /// Returns the vector that caused this trap
+    pub fn vector(&self) -> u8 {
+        self.vector as u8
+    }
+
+    /// Returns whether the trap was taken from user mode
+    pub fn from_user(&self) -> bool {
+        self.cs & 3 == 3
+    }
+}
+
+impl fmt::Display for TrapFrame {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        try!(writeln!(f,
+                      "vector {} error {:#x}",
+                      self.vector,
+                      self.error_code));
+        try!(writeln!(f,
+                      "rip {:#018x} cs {:#06x} rflags {:#018x}",
+                      self.rip,
+                      self.cs,
+                      self.rflags));
+        try!(writeln!(f, "rsp {:#018x} ss {:#06x}", self.rsp, self.ss));
+        try!(writeln!(f,
+                      "rax {:#018x} rbx {:#018x} rcx {:#018x}",
+                      self.rax,
+                      self.rbx,
+                      self.rcx));
+        try!(writeln!(f,
+                      "rdx {:#018x} rsi {:#018x} rdi {:#018x}",
+                      self.rdx,
+                      self.rsi,
+                      self.rdi));
+        try!(writeln!(f,
+                      "rbp {:#018x} r8  {:#018x} r9  {:#018x}",
+                      self.rbp,
+                      self.r8,
+                      self.r9));
+        try!(writeln!(f,
+                      "r10 {:#018x} r11 {:#018x} r12 {:#018x}",
+                      self.r10,
+                      self.r11,
+                      self.r12));
+        write!(f,
+               "r13 {:#018x} r14 {:#018x} r15 {:#018x}",
+               self.r13,
+               self.r14,
+               self.r15)
+    }
+}
+
+/// An interrupt service routine
+pub type Handler = fn(&mut TrapFrame);
+
+/// Name of the subsystem that registered a handler
+pub type Owner = &'static str;
+
+/// Priority of a handler registration
+///
+/// A registration may only displace an existing one of strictly lower
+/// priority. The CPU exception vectors require `Priority::Kernel`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Priority {
+    Driver,
+    System,
+    Kernel,
+}
+
+/// Errors returned by `register` and `unregister`
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum InterruptError {
+    /// The vector requires a higher priority than was requested
+    Reserved,
+    /// The vector is held by `Owner` at an equal or higher priority
+    Busy(Owner),
+    /// The vector is held by `Owner`, not the caller
+    NotOwner(Owner),
+    /// No handler is registered for the vector
+    NotRegistered,
+}
+
+#[derive(Clone, Copy, Debug)]
+struct Registration {
+    handler: Handler,
+    owner: Owner,
+    priority: Priority,
+    // Whether the local APIC delivered the vector and waits for an EOI
+    eoi: bool,
+}
+
+/// Vectors below this are CPU exceptions
+pub const FIRST_EXTERNAL_VECTOR: u8 = 32;
+pub const NMI_VECTOR: u8 = 2;
+pub const DOUBLE_FAULT_VECTOR: u8 = 8;
+pub const PAGE_FAULT_VECTOR: u8 = 14;
+pub const MACHINE_CHECK_VECTOR: u8 = 18;
+
+static HANDLERS: spin::RwLock<[Option<Registration>; 256]> =
+    spin::RwLock::new([None; 256]);
+
+// NMI and #MC can arrive while this CPU holds `HANDLERS` for writing, so
+// their handlers are also published here and dispatched without the lock
+static NMI_HANDLER: AtomicUsize = ATOMIC_USIZE_INIT;
+static MACHINE_CHECK_HANDLER: AtomicUsize = ATOMIC_USIZE_INIT;
+
+fn unlocked_slot(vector: u8) -> Option<&'static AtomicUsize> {
+    match vector {
+        NMI_VECTOR => Some(&NMI_HANDLER),
+        MACHINE_CHECK_VECTOR => Some(&MACHINE_CHECK_HANDLER),
+        _ => None,
+    }
+}
+
+fn publish(vector: u8, handler: Option<Handler>) {
+    if let Some(slot) = unlocked_slot(vector) {
+        slot.store(handler.map_or(0, |h| h as usize), Ordering::SeqCst);
+    }
+}
+
+fn unlocked_handler(slot: &AtomicUsize) -> Option<Handler> {
+    match slot.load(Ordering::SeqCst) {
+        0 => None,
+        h => Some(unsafe { mem::transmute::<usize, Handler>(h) }),
+    }
+}
+
+/// Install `handler` for `vector`
+///
+/// For vectors not delivered by the local APIC, such as exceptions and
+/// software interrupts, which are not acknowledged. Returns the owner of any
+/// lower priority registration that was displaced.
+pub fn register(vector: u8,
+                handler: Handler,
+                owner: Owner,
+                priority: Priority)
+                -> Result<Option<Owner>, InterruptError> {
+    install(vector,
+            Registration {
+                handler: handler,
+                owner: owner,
+                priority: priority,
+                eoi: false,
+            })
+}
+
+/// Install `handler` for `vector`, which the local APIC delivers
+///
+/// That includes local interrupts, IPIs and I/O APIC routes. The local
+/// APIC is sent an EOI after `handler` runs.
+pub fn register_apic(vector: u8,
+                     handler: Handler,
+                     owner: Owner,
+                     priority: Priority)
+                     -> Result<Option<Owner>, InterruptError> {
+    install(vector,
+            Registration {
+                handler: handler,
+                owner: owner,
+                priority: priority,
+                eoi: true,
+            })
+}
+
+fn install(vector: u8,
+           registration: Registration)
+           -> Result<Option<Owner>, InterruptError> {
+    let (owner, priority) = (registration.owner, registration.priority);
+    if vector < FIRST_EXTERNAL_VECTOR && priority < Priority::Kernel {
+        return Err(InterruptError::Reserved);
+    }
+    without_interrupts(|| {
+        let mut handlers = HANDLERS.write();
+        let slot = &mut handlers[vector as usize];
+        let displaced = match *slot {
+            Some(ref existing) if existing.owner == owner => None,
+            Some(ref existing) if existing.priority >= priority => {
+                return Err(InterruptError::Busy(existing.owner));
+            }
+            Some(ref existing) => Some(existing.owner),
+            None => None,
+        };
+        if let Some(prev) = displaced {
+            warn!("Interrupt {}: {} displaced by {}", vector, prev, owner);
+        }
+        *slot = Some(registration);
+        publish(vector, Some(registration.handler));
+        Ok(displaced)
+    })
+}
+
+/// Remove the handler `owner` installed for `vector`
+pub fn unregister(vector: u8, owner: Owner) -> Result<(), InterruptError> {
+    without_interrupts(|| {
+        let mut handlers = HANDLERS.write();
+        let slot = &mut handlers[vector as usize];
+        match *slot {
+            Some(ref existing) if existing.owner != owner => {
+                return Err(InterruptError::NotOwner(existing.owner));
+            }
+            Some(_) => {}
+            None => return Err(InterruptError::NotRegistered),
+        }
+        *slot = None;
+        publish(vector, None);
+        Ok(())
+    })
 }
 
 /// Rust entry for all interrupts
+///
+/// `int_common` restores the saved registers and returns with `iretq` once
+/// this returns. Vectors registered with `register_apic` are acknowledged
+/// after their handler runs. Unhandled vectors are acknowledged if the
+/// local APIC delivered them, or they would block their priority class.
+/// NMI and #MC never take the handlers lock.
 #[no_mangle]
-pub extern "C" fn interrupt_handler(num: usize, ef: u64) -> ! {
-    if num < EXCEPTIONS.len() {
-        error!("Received Exception: {}", EXCEPTIONS[num]);
+pub extern "C" fn interrupt_handler(frame: &mut TrapFrame) {
+    let vector = frame.vector();
+    if vector == NMI_VECTOR {
+        // The CPU may have been stopped while holding any lock
+        ipi::stop_if_stopping();
+    }
+    let registration = match unlocked_slot(vector) {
+        // Neither is delivered as a fixed interrupt, so neither needs an EOI
+        Some(slot) => unlocked_handler(slot).map(|h| (h, false)),
+        None => HANDLERS.read()[vector as usize].map(|r| (r.handler, r.eoi)),
+    };
+    match registration {
+        Some((handler, eoi)) => {
+            handler(frame);
+            if eoi {
+                if let Some(apic) = apic::try_local() {
+                    apic.eoi();
+                }
+            }
+        }
+        None => {
+            unhandled(frame);
+            if vector >= FIRST_EXTERNAL_VECTOR &&
+               vector != apic::SPURIOUS_VECTOR {
+                if let Some(apic) = apic::try_local() {
+                    if apic.in_service(vector) {
+                        apic.eoi();
+                    }
+                }
+            }
+        }
+    }
+}
+
+fn unhandled(frame: &mut TrapFrame) {
+    let num = frame.vector() as usize;
+    if num < EXCEPTIONS.len() && frame.from_user() {
+        user::fault(frame, format_args!("{}", EXCEPTIONS[num]));
+    } else if num < EXCEPTIONS.len() {
+        panic!("Unhandled Exception: {}\n{}", EXCEPTIONS[num], frame);
     } else {
-        error!("Recieved interrupt {}", num);
+        warn!("Received interrupt {} with no handler", num);
+    }
+}
+
+fn interrupts_enabled() -> bool {
+    const RFLAGS_IF: u64 = 1 << 9;
+    let rflags: u64;
+    unsafe {
+        asm!("pushfq; popq $0"
+             : "=r" (rflags)
+             : // no input
+             : "memory"
+             : "volatile");
+    }
+    rflags & RFLAGS_IF != 0
+}
+
+/// Run `f` with interrupts disabled, restoring the previous state after
+pub fn without_interrupts<F, R>(f: F) -> R
+    where F: FnOnce() -> R
+{
+    let enabled = interrupts_enabled();
+    if enabled {
+        unsafe { disable() };
+    }
+    let ret = f();
+    if enabled {
+        unsafe { enable() };
     }
-    loop {}
+    ret
 }
 
 pub fn init() {
     assert_has_not_been_called!("idt::init() function \
                                  must only be called once");
     populate_idt();
+    load();
+}
+
+/// Load the IDT on this CPU
+///
+/// Every CPU shares the IDT populated by `init`.
+pub fn load() {
     let idt_ptr = {
         let idtp: *const _ = unsafe { &IDT };
         let idt_len = unsafe { IDT.len() };
-        let idt_size = (mem::size_of::<IdtEntry>() * idt_len) as u16;
+        let idt_size = (mem::size_of::<IdtEntry>() * idt_len - 1) as u16;
         DescriptorTablePointer {
             limit: idt_size,
             base: idtp as u64,
@@ -563,10 +869,21 @@ fn populate_idt() {
     populate_entry(idt, 253, &int253);
     populate_entry(idt, 254, &int254);
     populate_entry(idt, 255, &int255);
+
+    set_ist(idt, NMI_VECTOR as usize, NMI_IST);
+    set_ist(idt, DOUBLE_FAULT_VECTOR as usize, DOUBLE_FAULT_IST);
+    set_ist(idt, MACHINE_CHECK_VECTOR as usize, MACHINE_CHECK_IST);
 }
 
 fn populate_entry(idt: &mut [IdtEntry; 256], ind: usize, addr: &'static u8) {
     let vaddr = VAddr::from_usize(addr as *const _ as usize);
     idt[ind] = IdtEntry::interrupt_gate(8, vaddr);
 }
+
+/// Run the handler for `vector` on Interrupt Stack Table entry `ist`
+fn set_ist(idt: &mut [IdtEntry; 256], vector: usize, ist: u8) {
+    // The IST index is the low three bits of the fifth byte of the gate
+    let bytes: &mut [u8; 16] = unsafe { mem::transmute(&mut idt[vector]) };
+    bytes[4] = ist & 0x7;
+}
 static mut IDT: [IdtEntry; 256] = [IdtEntry::missing(); 256];
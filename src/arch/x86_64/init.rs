@@ -13,96 +13,283 @@
 // You should have received a copy of the GNU Affero General Public License
 // along with Genesis.  If not, see <http://www.gnu.org/licenses/>.
 use super::serial;
+use cmdline;
+use core::cmp;
 use core::mem;
 use core::slice;
+use core::str;
 use fixedvec::FixedVec;
 use memory::*;
-use memory::first_fit_allocator::FirstFitAllocator;
+use memory::address_space::{self, AddressSpace};
+use memory::buddy_allocator;
+use memory::frame_cache;
+use memory::frame_table;
+use memory::heap;
+use memory::zone;
 use multiboot::{self, MemoryType, Multiboot};
 use spin;
+use super::acpi;
 use super::apic;
+use super::exception;
+use super::frame_bench;
 use super::gdt;
 use super::idt;
+use super::ioapic;
+use super::ipi;
+use super::multiboot2::{self, Tag};
+use super::page_fault;
+use super::percpu;
 use super::pic;
+use super::smp;
+use super::stack::{KernelStack, STACK_PAGES};
 use super::syscall;
+use super::timer;
+use super::user;
+use log::LogLevelFilter;
 use logimpl;
 use x86::controlregs::*;
+use x86::irq;
 use x86::msr::*;
 
 struct InitParams {
-    stack: VAddr,
+    stack: KernelStack,
+    ist_stacks: [KernelStack; gdt::IST_STACKS],
     regions: spin::RwLockReadGuard<'static, RegionVec>,
-    allocator: &'static FirstFitAllocator<'static>,
+    allocator: &'static KernelAllocator,
 }
 
 static PARAMS: spin::RwLock<Option<InitParams>> = spin::RwLock::new(None);
 
 /// Initial Rust entry point.
+///
+/// `magic` tells whether `multiboot_addr` is a Multiboot or a Multiboot2
+/// information structure.
 #[no_mangle]
-pub extern "C" fn arch_init(multiboot_addr: PAddr) -> ! {
+pub extern "C" fn arch_init(multiboot_addr: PAddr, magic: u32) -> ! {
     assert_has_not_been_called!("arch_init() function \
                                  must only be called once");
+    // The command line configures the console, so it is read first
+    read_command_line(multiboot_addr, magic);
     initialize_console();
 
-    process_multiboot(multiboot_addr);
+    let rsdp = process_boot_info(multiboot_addr, magic);
+    let from_loader = rsdp.map_or(false, |rsdp| {
+        acpi::init_from_rsdp(rsdp, |p, sz| unsafe {
+            early_paddr_to_slice(p, sz)
+        })
+    });
+    if !from_loader {
+        acpi::init(|p, sz| unsafe { early_paddr_to_slice(p, sz) });
+    }
+
+    // Free frames are kept per zone, so zones come before the allocator
+    zone::init(acpi::memory_affinity()
+        .iter()
+        .map(|m| (m.start, m.end, m.node)));
 
     let regions = REGIONS.read();
-    let allocator = FirstFitAllocator::get();
+    // frame_allocator=buddy picks the buddy allocator
+    let buddy = cmdline::get().value("frame_allocator") == Some("buddy");
+    let allocator = init_allocator(buddy, INITIAL_VIRTUAL_OFFSET as usize);
+    info!("Using the {} frame allocator", allocator.name());
+    let untracked = memory_above(&*regions, buddy_allocator::MAX_MEMORY);
+    if buddy && untracked != 0 {
+        error!("{} MiB of memory above {} GiB cannot be used by the buddy \
+                allocator",
+               untracked >> 20,
+               buddy_allocator::MAX_MEMORY >> 30);
+    }
     populate_allocator(&*regions, allocator);
 
     let (page_table_frame, mut page_table) =
         create_runtime_pagetable(allocator);
 
     map_free_memory(&mut page_table, &*regions, allocator);
+    map_modules(&mut page_table, allocator);
     map_kernel(&mut page_table, allocator);
     let new_stack = map_stack(&mut page_table, allocator);
+    let ist_stacks = [map_stack(&mut page_table, allocator),
+                      map_stack(&mut page_table, allocator),
+                      map_stack(&mut page_table, allocator)];
 
     *PARAMS.write() = Some(InitParams {
         stack: new_stack,
+        ist_stacks: ist_stacks,
         regions: regions,
         allocator: allocator,
     });
     unsafe {
-        switch_to_runtime_pagetable(new_stack.as_usize() as u64,
+        switch_to_runtime_pagetable(new_stack.top().as_usize() as u64,
                                     page_table_frame.start_address().as_u64(),
                                     arch_continue_init);
     }
 }
 
 extern "C" fn arch_continue_init() -> ! {
-    let (stack, regions, allocator) = {
+    let (stack, ist_stacks, regions, allocator) = {
         let mut wlock = PARAMS.write();
         let p = wlock.take().unwrap();
-        (p.stack, p.regions, p.allocator)
+        (p.stack, p.ist_stacks, p.regions, p.allocator)
     };
     // Now that we are on the runtime page table, we can free boot and higher
     // memory to the allocator
+    unsafe { allocator.set_phys_offset(PHYS_MAP) };
     free_boot_memory(allocator);
     free_upper_memory(&regions, allocator);
+    frame_table::init(regions.iter().map(region_frames), allocator);
+    heap::init(allocator);
 
-    unsafe {
-        gdt::reset(stack);
-    }
-    idt::init();
-    let mut page_table = unsafe {
-        let pml4_phys = PAddr::from_u64(cr3());
-        let pml4: *mut _ = phys_to_virt(pml4_phys).as_usize() as *mut _;
-        PageTable::new(pml4)
+    let ist_tops = [ist_stacks[0].top(),
+                    ist_stacks[1].top(),
+                    ist_stacks[2].top()];
+    let gdt = unsafe {
+        let frame = allocator.allocate_manual()
+            .expect("Could not allocate frame for the GDT");
+        let gdt = gdt::Gdt::new(frame, stack.top(), &ist_tops);
+        gdt.load();
+        gdt
     };
+    idt::init();
+    exception::init();
+    let root = Frame::down(PAddr::from_u64(unsafe { cr3() }));
+    let kernel_space = address_space::init_kernel(root, allocator);
+    page_fault::init();
     pic::disable();
-    let apic = unsafe { apic::Apic::init(&mut page_table, allocator) };
+    let apic = unsafe {
+        apic::Apic::init(&mut kernel_space.page_table(), allocator)
+    };
+    let percpu = unsafe {
+        let frame = allocator.allocate_manual()
+            .expect("Could not allocate frame for per-CPU data");
+        percpu::init(frame, 0, apic, gdt)
+    };
+    let apic = percpu.apic();
+    // noframecache keeps every frame allocation on the global allocator
+    frame_cache::set_enabled(!cmdline::get().flag("noframecache"));
+    unsafe {
+        ioapic::init(&mut kernel_space.page_table(),
+                     allocator,
+                     apic.id());
+    }
+    ipi::init();
+    let timer = timer::init(apic);
+    percpu.set_timer(timer);
     syscall::init();
-    nx_enable();
-    fpu_enable();
-    pge_enable();
+    enable_cpu_features();
+    frame_bench::init(allocator);
+    smp::init(kernel_space, allocator, apic, &timer);
+    frame_bench::run();
+    timer.periodic(TICK_US);
+    unsafe { irq::enable() };
+    frame_table::log_usage();
     debug!("End");
-    loop {}
+    let (space, entry) = load_modules(allocator)
+        .unwrap_or_else(|| user::load_builtin(allocator));
+    let user_stack = user::add_stack(space)
+        .expect("Could not add the initial user stack");
+    // This stack is also the kernel stack for traps from user mode, which
+    // is fine as nothing on it is needed any more
+    unsafe { user::run(space, entry, user_stack) }
+}
+
+/// Period of the scheduling tick
+const TICK_US: u64 = 10000;
+
+/// Load every boot module as a user program and return the one to run
+///
+/// That is the module named by `init=<name>`, or else the first to load.
+/// There is no scheduler yet, so no other module will ever run.
+fn load_modules(allocator: &'static KernelAllocator)
+                -> Option<(&'static AddressSpace, VAddr)> {
+    let init = cmdline::get().value("init");
+    let mut first = None;
+    let mut named = None;
+    for module in MODULES.read().iter() {
+        let program = match user::load_elf(unsafe { module.bytes() },
+                                           allocator) {
+            Ok(program) => program,
+            Err(e) => {
+                warn!("Could not load module {}: {:?}", module.name(), e);
+                continue;
+            }
+        };
+        info!("Loaded module {} with entry {:#X}", module.name(), program.1);
+        if first.is_none() {
+            first = Some(program);
+        }
+        if named.is_none() && init.map_or(false, |n| module.is_named(n)) {
+            named = Some(program);
+        }
+    }
+    if let (Some(name), None) = (init, named) {
+        warn!("No module named {}", name);
+    }
+    named.or(first)
+}
+
+/// Copy the boot loader's command line, without logging
+fn read_command_line(multiboot_addr: PAddr, magic: u32) {
+    // A missing information structure is reported by process_boot_info
+    let cmdline = if magic == multiboot2::MAGIC {
+        multiboot2_info(multiboot_addr).and_then(|info| info.command_line())
+    } else {
+        let mb = unsafe {
+            Multiboot::new(multiboot_addr.as_u64(), early_paddr_to_slice)
+        };
+        mb.as_ref().and_then(|mb| mb.command_line())
+    };
+    cmdline::init(cmdline.unwrap_or(""));
 }
 
+fn multiboot2_info(addr: PAddr) -> Option<multiboot2::Info<'static>> {
+    multiboot2::Info::new(addr.as_u64(),
+                          |p, sz| unsafe { early_paddr_to_slice(p, sz) })
+}
+
+/// Start the serial console and logger as the command line asks
+///
+/// `serial=<port>` and `baud=<rate>` select the UART and
+/// `loglevel=<off|error|warn|info|debug|trace>` the most verbose records
+/// logged.
 fn initialize_console() {
-    serial::init();
-    logimpl::Logger::init();
+    let cmdline = cmdline::get();
+    let port = cmdline.number("serial")
+        .map_or(serial::DEFAULT_PORT, |port| port as u16);
+    let baud = cmdline.number("baud")
+        .map_or(serial::DEFAULT_BAUD, |baud| baud as u32);
+    serial::init(port, baud);
+    let (level, bad_level) = match cmdline.value("loglevel") {
+        Some(name) => {
+            match name.parse() {
+                Ok(level) => (level, None),
+                Err(_) => (LogLevelFilter::Debug, Some(name)),
+            }
+        }
+        None => (LogLevelFilter::Debug, None),
+    };
+    logimpl::Logger::init(level);
     debug!("Serial Initialized");
+    info!("Command line: {}", cmdline);
+    if cmdline.truncated() {
+        warn!("Command line truncated to {} bytes", cmdline::CMDLINE_LEN);
+    }
+    if let Some(name) = bad_level {
+        warn!("Unknown log level {}", name);
+    }
+}
+
+/// Record memory and modules from either boot protocol
+///
+/// Returns the copy of the ACPI RSDP that Multiboot2 loaders provide.
+fn process_boot_info(multiboot_addr: PAddr,
+                     magic: u32)
+                     -> Option<&'static [u8]> {
+    if magic == multiboot2::MAGIC {
+        process_multiboot2(multiboot_addr)
+    } else {
+        process_multiboot(multiboot_addr);
+        None
+    }
 }
 
 fn process_multiboot(multiboot_addr: PAddr) {
@@ -112,15 +299,88 @@ fn process_multiboot(multiboot_addr: PAddr) {
         }
         .expect("Could not access a Multiboot structure");
 
-    process_multiboot_memory(mb.memory_regions()
-        .expect("Could not find Multiboot memory map"));
+    // Modules must be known before memory so that they can be reserved
+    if let Some(modules) = mb.modules() {
+        record_modules(modules.map(|m| {
+            (m.start, m.end, m.string.unwrap_or(""))
+        }));
+    }
+    let mem_regions = mb.memory_regions()
+        .expect("Could not find Multiboot memory map");
+    record_memory(mem_regions.map(|r| {
+        (r.base_address(),
+         r.base_address() + r.length(),
+         r.memory_type() == MemoryType::RAM)
+    }));
+}
 
-    // TODO: process cmdline and modules
+fn process_multiboot2(multiboot_addr: PAddr) -> Option<&'static [u8]> {
+    debug!("Multiboot2 information loaded at {:#X}", multiboot_addr);
+    let info = multiboot2_info(multiboot_addr)
+        .expect("Could not access a Multiboot2 information structure");
+
+    // Modules must be known before memory so that they can be reserved
+    record_modules(info.tags().filter_map(|tag| match tag {
+        Tag::Module(m) => Some((m.start, m.end, m.string)),
+        _ => None,
+    }));
+    // The EFI memory map is only used if there is no BIOS style one
+    let bios_map = info.tags()
+        .filter_map(|tag| match tag {
+            Tag::MemoryMap(map) => Some(map),
+            _ => None,
+        })
+        .next();
+    let efi_map = info.tags()
+        .filter_map(|tag| match tag {
+            Tag::EfiMemoryMap(map) => Some(map),
+            _ => None,
+        })
+        .next();
+    let map = bios_map.or(efi_map)
+        .expect("Could not find Multiboot2 memory map");
+    record_memory(map.map(|area| (area.start, area.end, area.available)));
+
+    for tag in info.tags() {
+        if let Tag::Framebuffer(fb) = tag {
+            info!("Framebuffer {}x{} at {:#X}, {} bpp, pitch {}",
+                  fb.width,
+                  fb.height,
+                  fb.addr,
+                  fb.bpp,
+                  fb.pitch);
+        }
+    }
+    info.rsdp()
 }
 
-/// Discover available memory from the Multiboot structure and populate
-/// `REGIONS`
-fn process_multiboot_memory(mem_regions: multiboot::MemoryMapIter) {
+/// Record the modules loaded by the bootloader in `MODULES`
+///
+/// Each module is given as its start and end address and its string.
+fn record_modules<'a, I>(modules: I)
+    where I: Iterator<Item = (u64, u64, &'a str)>
+{
+    let mut boot_modules = MODULES.write();
+    for (start, end, name) in modules {
+        let start = PAddr::from_u64(start);
+        let end = PAddr::from_u64(end);
+        info!("{:#17X} - {:#17X}: Module {}", start, end, name);
+        if end <= start {
+            warn!("Ignoring empty module {}", name);
+            continue;
+        }
+        if let Err(e) = boot_modules.push(BootModule::new(start, end, name)) {
+            warn!("Could not store module {}: {:?}", name, e);
+        }
+    }
+}
+
+/// Populate `REGIONS` from the boot loader's memory map
+///
+/// Each area is given as its start and end address and whether it is RAM.
+fn record_memory<I>(mem_regions: I)
+    where I: Iterator<Item = (u64, u64, bool)>
+{
     let mut regions = REGIONS.write();
     // kbegin and kend are defined as symbols in the linker script
     let (kbegin, kend) = {
@@ -141,31 +401,70 @@ fn process_multiboot_memory(mem_regions: multiboot::MemoryMapIter) {
         (PAddr::from_u64(kbegin_addr - INITIAL_VIRTUAL_OFFSET),
          PAddr::from_u64(kend_addr - INITIAL_VIRTUAL_OFFSET))
     };
-    for region in mem_regions {
-        let start = PAddr::from_u64(region.base_address());
-        let end = PAddr::from_u64(region.base_address() + region.length());
-        let mem_type = match region.memory_type() {
-            MemoryType::RAM => "RAM",
-            MemoryType::Unusable => "Unusable",
-        };
+    // mem=<size> ignores memory above that physical address
+    let limit = cmdline::get().size("mem").map(PAddr::from_u64);
+    if let Some(limit) = limit {
+        info!("Limiting memory to {:#X}", limit);
+    }
+    for (start, end, ram) in mem_regions {
+        let start = PAddr::from_u64(start);
+        let end = PAddr::from_u64(end);
+        let mem_type = if ram { "RAM" } else { "Unusable" };
         info!("{:#17X} - {:#17X}: {}", start, end, mem_type);
-        if region.memory_type() == MemoryType::RAM {
+        if ram {
             let mut reg = MemoryRegion::new(start, end);
             reg.trim_below(kend);
             reg.trim_above(kbegin);
-            if reg.start < reg.end {
-                if let Err(e) = regions.push(reg) {
-                    warn!("Could not store usable region {:#?}: {:?}",
-                          region,
-                          e)
+            if let Some(limit) = limit {
+                reg.trim_above(limit);
+                if reg.start >= limit {
+                    continue;
                 }
             }
+            if reg.start < reg.end {
+                push_usable(&mut regions, reg, &MODULES.read());
+            }
+        }
+    }
+}
+
+/// Store the parts of `reg` not covered by a boot module in `regions`
+fn push_usable(regions: &mut RegionVec,
+               reg: MemoryRegion,
+               modules: &ModuleVec) {
+    let overlap = modules.iter()
+        .map(|m| m.frames())
+        .find(|f| {
+            f.lower().start_address() < reg.end &&
+            reg.start < f.upper().start_address()
+        });
+    match overlap {
+        Some(frames) => {
+            let (start, end) = (frames.lower().start_address(),
+                                frames.upper().start_address());
+            if reg.start < start {
+                push_usable(regions,
+                            MemoryRegion::new(reg.start, start),
+                            modules);
+            }
+            if end < reg.end {
+                push_usable(regions,
+                            MemoryRegion::new(end, reg.end),
+                            modules);
+            }
+        }
+        None => {
+            if let Err(e) = regions.push(reg) {
+                warn!("Could not store usable region {:#?}: {:?}", reg, e)
+            }
         }
     }
 }
 
 const INITIAL_VIRTUAL_OFFSET: u64 = 0xFFFFFFFFC0000000;
 
+// Only the first 1GiB of physical memory is mapped before the runtime page
+// table, anything above it is reported as inaccessible
 unsafe fn early_paddr_to_slice<'a>(p: multiboot::PAddr,
                                    sz: usize)
                                    -> Option<&'a [u8]> {
@@ -206,6 +505,87 @@ impl MemoryRegion {
 
 type RegionVec = FixedVec<'static, MemoryRegion>;
 
+const MODULE_NAME_LEN: usize = 64;
+
+/// A module loaded by the bootloader, reserved for the life of the kernel
+#[derive(Copy)]
+struct BootModule {
+    start: PAddr,
+    end: PAddr,
+    name: [u8; MODULE_NAME_LEN],
+    name_len: usize,
+}
+
+impl BootModule {
+    const fn empty() -> BootModule {
+        BootModule {
+            start: PAddr::from_u64(0),
+            end: PAddr::from_u64(0),
+            name: [0; MODULE_NAME_LEN],
+            name_len: 0,
+        }
+    }
+
+    /// The multiboot structure is not kept, so `name` is copied (and
+    /// truncated if need be)
+    fn new(start: PAddr, end: PAddr, name: &str) -> BootModule {
+        let mut module = BootModule::empty();
+        module.start = start;
+        module.end = end;
+        let mut len = cmp::min(name.len(), MODULE_NAME_LEN);
+        while !name.is_char_boundary(len) {
+            len -= 1;
+        }
+        module.name[..len].copy_from_slice(&name.as_bytes()[..len]);
+        module.name_len = len;
+        module
+    }
+
+    fn name(&self) -> &str {
+        str::from_utf8(&self.name[..self.name_len]).unwrap_or("")
+    }
+
+    /// Returns whether `name` is the module's path or its last component
+    fn is_named(&self, name: &str) -> bool {
+        let path = self.name().split(' ').next().unwrap_or("");
+        path == name || path.rsplit('/').next() == Some(name)
+    }
+
+    fn frames(&self) -> FrameRange {
+        FrameRange::new(Frame::down(self.start), Frame::up(self.end))
+    }
+
+    /// Returns the module contents, which are mapped at `PHYS_MAP` by
+    /// `map_modules`
+    unsafe fn bytes(&self) -> &'static [u8] {
+        let len = (self.end.as_u64() - self.start.as_u64()) as usize;
+        slice::from_raw_parts(phys_to_virt(self.start).as_usize() as *const u8,
+                              len)
+    }
+}
+
+// Arrays this long do not implement Clone
+impl Clone for BootModule {
+    fn clone(&self) -> BootModule {
+        *self
+    }
+}
+
+type ModuleVec = FixedVec<'static, BootModule>;
+
+lazy_static! {
+    static ref MODULES: spin::RwLock<ModuleVec> = {
+        const MODULES_SIZE: usize = 16;
+        static mut MODULES_MEM: [BootModule; MODULES_SIZE] =
+            [BootModule::empty(); MODULES_SIZE];
+        // Unsafe to take a mutable reference of a static.
+        // We instantly store it behind a Rwlock, so this is safe
+        unsafe {
+            spin::RwLock::new(FixedVec::new(&mut MODULES_MEM))
+        }
+    };
+}
+
 lazy_static! {
     static ref REGIONS: spin::RwLock<RegionVec> = {
         const REGIONS_SIZE: usize = 256;
@@ -254,6 +634,8 @@ fn kernel_segments() -> (FrameRange, FrameRange, FrameRange) {
 }
 
 const INITIAL_MAP: PAddr = PAddr::from_u64(1 << 30);
+// Memory below 1MB is left to firmware and the AP trampoline
+const LOW_MEMORY: PAddr = PAddr::from_u64(1 << 20);
 
 /// Populate the memory allocator with accessible frames
 fn populate_allocator<Allocator: FrameAllocator>(regions: &RegionVec,
@@ -269,10 +651,12 @@ fn populate_allocator<Allocator: FrameAllocator>(regions: &RegionVec,
         let mut region = *reg;
         region.trim_above(INITIAL_MAP);
         region.trim_above(boot_begin);
+        region.trim_below(LOW_MEMORY);
         let start_frame = Frame::up(region.start);
         let end_frame = Frame::down(region.end);
         let range = FrameRange::new(start_frame, end_frame);
-        if start_frame.start_address() >= INITIAL_MAP || range.nframes() == 0 {
+        if start_frame.start_address() >= INITIAL_MAP ||
+           region.end <= LOW_MEMORY || range.nframes() == 0 {
             None
         } else {
             Some(range)
@@ -335,6 +719,24 @@ fn map_free_memory<Allocator: FrameAllocator>(page_table: &mut PageTable,
     }
 }
 
+/// Map boot modules read-only at `PHYS_MAP`, as they are not in `REGIONS`
+fn map_modules<Allocator>(page_table: &mut PageTable, allocator: &Allocator)
+    where Allocator: FrameAllocator
+{
+    for module in MODULES.read().iter() {
+        let range = module.frames();
+        for offset in 0..range.nframes() {
+            let frame = range.lower() + offset;
+            let addr = frame.start_address().as_u64() as usize + PHYS_MAP;
+            page_table.map(Page::down(VAddr::from_usize(addr)),
+                           frame,
+                           PT_P | PT_G | PT_XD,
+                           allocator,
+                           initial_frame_to_slice);
+        }
+    }
+}
+
 fn map_kernel<Allocator>(page_table: &mut PageTable, allocator: &Allocator)
     where Allocator: FrameAllocator
 {
@@ -353,41 +755,32 @@ fn map_range<Allocator>(page_table: &mut PageTable,
                         allocator: &Allocator)
     where Allocator: FrameAllocator
 {
-    for offset in 0..range.nframes() {
-        let frame = range.lower() + offset;
-        let page = {
-            assert!(frame.start_address() < INITIAL_MAP);
-            let addr = frame.start_address().as_u64() + INITIAL_VIRTUAL_OFFSET;
-            Page::down(VAddr::from_usize(addr as usize))
-        };
-        page_table.map(page, frame, flags, allocator, initial_frame_to_slice);
-    }
+    assert!(range.upper().start_address() <= INITIAL_MAP);
+    let addr = range.lower().start_address().as_u64() + INITIAL_VIRTUAL_OFFSET;
+    page_table.map_range(Page::down(VAddr::from_usize(addr as usize)),
+                         range,
+                         flags,
+                         allocator,
+                         initial_frame_to_slice);
 }
 
-// map a 12K stack with a guard page below the kernel start
+// map a kernel stack with an unmapped guard page below it
 fn map_stack<Allocator>(page_table: &mut PageTable,
                         allocator: &Allocator)
-                        -> VAddr
+                        -> KernelStack
     where Allocator: FrameAllocator
 {
-    let kbegin_page = {
-        extern "C" {
-            static kbegin: u8;
-        }
-        let ptr: *const _ = &kbegin;
-        Page::down(VAddr::from_usize(ptr as usize))
-    };
-    for i in 1..3 {
+    let stack = KernelStack::reserve();
+    for i in 0..STACK_PAGES {
         let frame = allocator.allocate_manual()
             .expect("Could not allocate frame for stack");
-        let page = kbegin_page - i;
-        page_table.map(page,
+        page_table.map(stack.page(i),
                        frame,
                        PT_P | PT_RW | PT_G | PT_XD,
                        allocator,
                        initial_frame_to_slice);
     }
-    kbegin_page.start_address()
+    stack
 }
 
 extern "C" {
@@ -397,6 +790,23 @@ extern "C" {
                                    -> !;
 }
 
+/// Returns the number of bytes of `regions` above `limit`
+fn memory_above(regions: &RegionVec, limit: u64) -> u64 {
+    regions.iter()
+        .map(|reg| {
+            let start = cmp::max(reg.start.as_u64(), limit);
+            reg.end.as_u64().saturating_sub(start)
+        })
+        .fold(0, |total, bytes| total + bytes)
+}
+
+/// Returns the frames wholly inside `region`, possibly none
+fn region_frames(region: &MemoryRegion) -> FrameRange {
+    let start_frame = Frame::up(region.start);
+    let end_frame = cmp::max(start_frame, Frame::down(region.end));
+    FrameRange::new(start_frame, end_frame)
+}
+
 fn free_boot_memory<Allocator>(allocator: &Allocator)
     where Allocator: FrameAllocator
 {
@@ -442,6 +852,13 @@ fn free_upper_memory<Allocator>(regions: &RegionVec, allocator: &Allocator)
     }
 }
 
+/// Enable the CPU features the kernel relies on, on the calling CPU
+pub fn enable_cpu_features() {
+    nx_enable();
+    fpu_enable();
+    pge_enable();
+}
+
 fn nx_enable() {
     unsafe {
         let efer = rdmsr(IA32_EFER);
@@ -455,9 +872,9 @@ fn fpu_enable() {
         // enable Monitor co-processor
         cr0 |= 1 << 1;
         // disable EM
-        cr0 |= !(1 << 2);
-        // enable task switch
-        cr0 |= 1 << 3;
+        cr0 &= !(1 << 2);
+        // clear TS, nothing handles #NM so the FPU must be usable
+        cr0 &= !(1 << 3);
         // enable numeric error reporting
         cr0 |= 1 << 5;
         cr0_write(cr0);
@@ -0,0 +1,386 @@
+// Copyright Dan Schatzberg, 2016. This file is part of Genesis.
+
+// Genesis is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// Genesis is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+
+// You should have received a copy of the GNU Affero General Public License
+// along with Genesis.  If not, see <http://www.gnu.org/licenses/>.
+use core::ptr;
+use memory::*;
+use spin;
+use super::acpi;
+
+// Memory mapped registers
+const IOREGSEL: usize = 0x00;
+const IOWIN: usize = 0x10;
+
+// Indirect registers
+const IOAPICVER: u32 = 0x01;
+const IOREDTBL: u32 = 0x10;
+
+// Redirection entry fields
+const REDIR_POLARITY_LOW: u64 = 1 << 13;
+const REDIR_TRIGGER_LEVEL: u64 = 1 << 15;
+const REDIR_MASKED: u64 = 1 << 16;
+const REDIR_DEST_SHIFT: u64 = 56;
+// The destination field holds 8 bits of APIC ID; larger x2APIC IDs can
+// only be reached through interrupt remapping
+const REDIR_DEST_MAX: u32 = 0xff;
+
+/// Vector that ISA IRQ 0 is routed to, the rest follow it
+pub const ISA_VECTOR_BASE: u8 = 0x30;
+/// Number of legacy ISA IRQs
+pub const ISA_IRQS: u8 = 16;
+/// ISA IRQ of the PIT
+pub const ISA_IRQ_PIT: u8 = 0;
+/// ISA IRQ of the first serial port
+pub const ISA_IRQ_COM1: u8 = 4;
+
+/// Physical address of the first I/O APIC when firmware doesn't say
+pub const DEFAULT_IOAPIC_ADDR: PAddr = PAddr::from_u64(0xfec0_0000);
+
+const MAX_IOAPICS: usize = 8;
+
+/// Interrupt pin polarity
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Polarity {
+    ActiveHigh,
+    ActiveLow,
+}
+
+/// Interrupt pin trigger mode
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Trigger {
+    Edge,
+    Level,
+}
+
+/// Errors from routing operations
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum IoApicError {
+    /// No registered I/O APIC handles the GSI
+    NoSuchGsi(u32),
+    /// No space is left to register another I/O APIC
+    Full,
+    /// The APIC ID does not fit the redirection entry destination field
+    BadDestination(u32),
+}
+
+/// How an ISA IRQ is wired to a Global System Interrupt
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct InterruptOverride {
+    pub isa_irq: u8,
+    pub gsi: u32,
+    pub polarity: Polarity,
+    pub trigger: Trigger,
+}
+
+impl InterruptOverride {
+    /// ISA IRQs are identity mapped, edge triggered and active high unless
+    /// overridden
+    pub const fn identity(isa_irq: u8) -> InterruptOverride {
+        InterruptOverride {
+            isa_irq: isa_irq,
+            gsi: isa_irq as u32,
+            polarity: Polarity::ActiveHigh,
+            trigger: Trigger::Edge,
+        }
+    }
+
+    /// Construct an override from ACPI MPS INTI flags
+    ///
+    /// Fields that "conform to the bus specification" take the ISA default.
+    pub fn from_mps_flags(isa_irq: u8, gsi: u32, flags: u16)
+                          -> InterruptOverride {
+        InterruptOverride {
+            isa_irq: isa_irq,
+            gsi: gsi,
+            polarity: if flags & 0x3 == 0x3 {
+                Polarity::ActiveLow
+            } else {
+                Polarity::ActiveHigh
+            },
+            trigger: if (flags >> 2) & 0x3 == 0x3 {
+                Trigger::Level
+            } else {
+                Trigger::Edge
+            },
+        }
+    }
+}
+
+/// A single I/O APIC
+#[derive(Clone, Copy, Debug)]
+pub struct IoApic {
+    base_addr: VAddr,
+    gsi_base: u32,
+    nredir: u32,
+}
+
+static IOAPICS: spin::Mutex<[Option<IoApic>; MAX_IOAPICS]> =
+    spin::Mutex::new([None; MAX_IOAPICS]);
+
+type Overrides = [Option<InterruptOverride>; ISA_IRQS as usize];
+
+static OVERRIDES: spin::RwLock<Overrides> =
+    spin::RwLock::new([None; ISA_IRQS as usize]);
+
+impl IoApic {
+    /// Map the I/O APIC at `paddr`, whose first pin is `gsi_base`
+    ///
+    /// Every pin starts out masked.
+    pub unsafe fn new<Allocator>(paddr: PAddr,
+                                 gsi_base: u32,
+                                 page_table: &mut PageTable,
+                                 allocator: &Allocator)
+                                 -> IoApic
+        where Allocator: FrameAllocator
+    {
+        let vaddr = phys_to_virt(paddr);
+        page_table.map_device(Page::down(vaddr),
+                              Frame::down(paddr),
+                              allocator,
+                              |f: Frame| frame_to_slice(f));
+        let mut ioapic = IoApic {
+            base_addr: vaddr,
+            gsi_base: gsi_base,
+            nredir: 0,
+        };
+        ioapic.nredir = ((ioapic.read(IOAPICVER) >> 16) & 0xff) + 1;
+        for pin in 0..ioapic.nredir {
+            ioapic.write_entry(pin, REDIR_MASKED);
+        }
+        info!("I/O APIC at {:#X}: GSIs {} - {}",
+              paddr,
+              gsi_base,
+              gsi_base + ioapic.nredir - 1);
+        ioapic
+    }
+
+    fn handles(&self, gsi: u32) -> bool {
+        gsi >= self.gsi_base && gsi < self.gsi_base + self.nredir
+    }
+
+    fn reg_ptr(&self, offset: usize) -> *mut u32 {
+        (self.base_addr.as_usize() + offset) as *mut u32
+    }
+
+    fn read(&self, reg: u32) -> u32 {
+        unsafe {
+            ptr::write_volatile(self.reg_ptr(IOREGSEL), reg);
+            ptr::read_volatile(self.reg_ptr(IOWIN))
+        }
+    }
+
+    fn write(&mut self, reg: u32, value: u32) {
+        unsafe {
+            ptr::write_volatile(self.reg_ptr(IOREGSEL), reg);
+            ptr::write_volatile(self.reg_ptr(IOWIN), value);
+        }
+    }
+
+    fn read_entry(&self, pin: u32) -> u64 {
+        let lo = self.read(IOREDTBL + pin * 2) as u64;
+        let hi = self.read(IOREDTBL + pin * 2 + 1) as u64;
+        hi << 32 | lo
+    }
+
+    fn write_entry(&mut self, pin: u32, entry: u64) {
+        // Write the half holding the mask bit last when unmasking, first
+        // when masking, so a half-written entry is never live
+        if entry & REDIR_MASKED == 0 {
+            self.write(IOREDTBL + pin * 2 + 1, (entry >> 32) as u32);
+            self.write(IOREDTBL + pin * 2, entry as u32);
+        } else {
+            self.write(IOREDTBL + pin * 2, entry as u32);
+            self.write(IOREDTBL + pin * 2 + 1, (entry >> 32) as u32);
+        }
+    }
+}
+
+/// Set up the I/O APICs found by ACPI and route (masked) the ISA IRQs to
+/// `bsp`
+pub unsafe fn init<Allocator>(page_table: &mut PageTable,
+                              allocator: &Allocator,
+                              bsp: u32)
+    where Allocator: FrameAllocator
+{
+    assert_has_not_been_called!("ioapic::init() function \
+                                 must only be called once");
+    let entries = acpi::io_apics();
+    if entries.is_empty() {
+        warn!("No I/O APIC described by ACPI, assuming the default");
+        let ioapic =
+            IoApic::new(DEFAULT_IOAPIC_ADDR, 0, page_table, allocator);
+        add(ioapic).expect("Could not register the I/O APIC");
+    }
+    for entry in entries.iter() {
+        let ioapic =
+            IoApic::new(entry.addr, entry.gsi_base, page_table, allocator);
+        if let Err(e) = add(ioapic) {
+            warn!("Could not register I/O APIC {}: {:?}", entry.id, e);
+        }
+    }
+    for ov in acpi::overrides().iter() {
+        if ov.isa_irq < ISA_IRQS {
+            add_override(*ov);
+        }
+    }
+    for irq in 0..ISA_IRQS {
+        if shadowed(&OVERRIDES.read(), irq) {
+            debug!("ISA IRQ {} is not connected, its GSI is overridden",
+                   irq);
+            continue;
+        }
+        if let Err(e) = route_isa(irq, bsp) {
+            warn!("Could not route ISA IRQ {}: {:?}", irq, e);
+        }
+    }
+}
+
+/// Register an I/O APIC for routing
+pub fn add(ioapic: IoApic) -> Result<(), IoApicError> {
+    let mut ioapics = IOAPICS.lock();
+    match ioapics.iter_mut().find(|slot| slot.is_none()) {
+        Some(slot) => {
+            *slot = Some(ioapic);
+            Ok(())
+        }
+        None => Err(IoApicError::Full),
+    }
+}
+
+/// Record that ISA IRQ `ov.isa_irq` is wired as described by `ov`
+pub fn add_override(ov: InterruptOverride) {
+    assert!(ov.isa_irq < ISA_IRQS);
+    OVERRIDES.write()[ov.isa_irq as usize] = Some(ov);
+}
+
+/// Returns how ISA IRQ `irq` is wired
+pub fn isa_irq(irq: u8) -> InterruptOverride {
+    assert!(irq < ISA_IRQS);
+    OVERRIDES.read()[irq as usize]
+        .unwrap_or(InterruptOverride::identity(irq))
+}
+
+/// Returns whether identity routing ISA IRQ `irq` would take over the GSI
+/// another ISA IRQ is overridden to (e.g. IRQ 2 when IRQ 0 arrives on GSI 2)
+fn shadowed(overrides: &Overrides, irq: u8) -> bool {
+    overrides[irq as usize].is_none() &&
+    overrides.iter()
+        .filter_map(|ov| ov.as_ref())
+        .any(|ov| ov.isa_irq != irq && ov.gsi == irq as u32)
+}
+
+fn with_gsi<F, R>(gsi: u32, f: F) -> Result<R, IoApicError>
+    where F: FnOnce(&mut IoApic, u32) -> R
+{
+    let mut ioapics = IOAPICS.lock();
+    ioapics.iter_mut()
+        .filter_map(|slot| slot.as_mut())
+        .find(|ioapic| ioapic.handles(gsi))
+        .map(|ioapic| {
+            let pin = gsi - ioapic.gsi_base;
+            f(ioapic, pin)
+        })
+        .ok_or(IoApicError::NoSuchGsi(gsi))
+}
+
+/// Returns the masked redirection entry delivering fixed interrupt `vector`
+/// to the CPU with local APIC ID `dest`
+fn redir_entry(vector: u8,
+               dest: u32,
+               polarity: Polarity,
+               trigger: Trigger)
+               -> Result<u64, IoApicError> {
+    if dest > REDIR_DEST_MAX {
+        return Err(IoApicError::BadDestination(dest));
+    }
+    let mut entry = vector as u64 | REDIR_MASKED |
+                    (dest as u64) << REDIR_DEST_SHIFT;
+    if polarity == Polarity::ActiveLow {
+        entry |= REDIR_POLARITY_LOW;
+    }
+    if trigger == Trigger::Level {
+        entry |= REDIR_TRIGGER_LEVEL;
+    }
+    Ok(entry)
+}
+
+/// Deliver `gsi` as fixed interrupt `vector` to the CPU with local APIC ID
+/// `dest`. The pin is left masked.
+///
+/// The handler of `vector` must be installed with `idt::register_apic`.
+pub fn route(gsi: u32,
+             vector: u8,
+             dest: u32,
+             polarity: Polarity,
+             trigger: Trigger)
+             -> Result<(), IoApicError> {
+    let entry = try!(redir_entry(vector, dest, polarity, trigger));
+    with_gsi(gsi, |ioapic, pin| ioapic.write_entry(pin, entry))
+}
+
+/// Route ISA IRQ `irq` to its vector, honoring any override
+pub fn route_isa(irq: u8, dest: u32) -> Result<(), IoApicError> {
+    let ov = isa_irq(irq);
+    route(ov.gsi, ISA_VECTOR_BASE + irq, dest, ov.polarity, ov.trigger)
+}
+
+/// Stop `gsi` from being delivered
+pub fn mask(gsi: u32) -> Result<(), IoApicError> {
+    with_gsi(gsi, |ioapic, pin| {
+        let entry = ioapic.read_entry(pin);
+        ioapic.write_entry(pin, entry | REDIR_MASKED);
+    })
+}
+
+/// Allow `gsi` to be delivered
+pub fn unmask(gsi: u32) -> Result<(), IoApicError> {
+    with_gsi(gsi, |ioapic, pin| {
+        let entry = ioapic.read_entry(pin);
+        ioapic.write_entry(pin, entry & !REDIR_MASKED);
+    })
+}
+
+/// Returns the GSI ISA IRQ `irq` arrives on
+pub fn isa_gsi(irq: u8) -> u32 {
+    isa_irq(irq).gsi
+}
+
+#[cfg(test)]
+mod test {
+    use super::{InterruptOverride, IoApicError, Overrides, Polarity,
+                Trigger, redir_entry, shadowed};
+
+    #[test]
+    fn test_shadowed() {
+        let mut overrides: Overrides = [None; 16];
+        overrides[0] = Some(InterruptOverride::from_mps_flags(0, 2, 0));
+        overrides[9] = Some(InterruptOverride::from_mps_flags(9, 9, 0xf));
+        assert!(shadowed(&overrides, 2));
+        assert!(!shadowed(&overrides, 0));
+        assert!(!shadowed(&overrides, 9));
+        assert!(!shadowed(&overrides, 4));
+        // An IRQ with its own override is routed even if its GSI is shared
+        overrides[2] = Some(InterruptOverride::identity(2));
+        assert!(!shadowed(&overrides, 2));
+    }
+
+    #[test]
+    fn test_redir_entry() {
+        let entry = redir_entry(0x30, 3, Polarity::ActiveLow, Trigger::Level)
+            .unwrap();
+        assert_eq!(entry, 0x0300_0000_0001_a030);
+        assert_eq!(redir_entry(0x30, 0x100, Polarity::ActiveHigh,
+                               Trigger::Edge),
+                   Err(IoApicError::BadDestination(0x100)));
+    }
+}
@@ -0,0 +1,117 @@
+// Copyright Dan Schatzberg, 2016. This file is part of Genesis.
+
+// Genesis is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// Genesis is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+
+// You should have received a copy of the GNU Affero General Public License
+// along with Genesis.  If not, see <http://www.gnu.org/licenses/>.
+use core::sync::atomic::{ATOMIC_BOOL_INIT, AtomicBool, Ordering};
+use spin;
+use x86::controlregs::{cr3, cr3_write};
+use super::apic::{self, Delivery, Destination, IpiError};
+use super::idt::{self, Priority, TrapFrame};
+
+/// Vector of the first kernel IPI, the rest follow it
+pub const IPI_VECTOR_BASE: u8 = 0xf0;
+
+/// Kernel inter-processor interrupts
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Ipi {
+    /// Ask the receiving CPU to run its scheduler
+    Reschedule,
+    /// Ask the receiving CPU to flush its (non-global) TLB entries
+    TlbShootdown,
+}
+
+const IPIS: usize = 2;
+
+impl Ipi {
+    /// Returns the vector this IPI is delivered on
+    pub fn vector(&self) -> u8 {
+        IPI_VECTOR_BASE + *self as u8
+    }
+
+    fn from_vector(vector: u8) -> Option<Ipi> {
+        match vector.wrapping_sub(IPI_VECTOR_BASE) {
+            0 => Some(Ipi::Reschedule),
+            1 => Some(Ipi::TlbShootdown),
+            _ => None,
+        }
+    }
+}
+
+static CALLBACKS: spin::RwLock<[Option<fn()>; IPIS]> =
+    spin::RwLock::new([None; IPIS]);
+static STOPPING: AtomicBool = ATOMIC_BOOL_INIT;
+
+/// Install the handlers for kernel IPIs
+pub fn init() {
+    assert_has_not_been_called!("ipi::init() function \
+                                 must only be called once");
+    for ipi in [Ipi::Reschedule, Ipi::TlbShootdown].iter() {
+        idt::register_apic(ipi.vector(), receive, "ipi", Priority::System)
+            .expect("Could not register an IPI handler");
+    }
+}
+
+/// Call `f` on every CPU that receives `ipi`
+///
+/// `Ipi::TlbShootdown` flushes the TLB before calling `f`.
+pub fn set_callback(ipi: Ipi, f: fn()) {
+    idt::without_interrupts(|| CALLBACKS.write()[ipi as usize] = Some(f));
+}
+
+/// Send `ipi` to `dest`
+pub fn send(ipi: Ipi, dest: Destination) -> Result<(), IpiError> {
+    apic::local().send_ipi(dest, Delivery::Fixed(ipi.vector()))
+}
+
+/// Halt every other CPU with an NMI
+///
+/// Used when panicking, so it does nothing if the local APIC is not up yet
+/// and only the first caller sends anything. The stopped CPUs may hold any
+/// lock, so after this the logger writes without taking its own.
+pub fn stop_others() {
+    if STOPPING.swap(true, Ordering::SeqCst) {
+        return;
+    }
+    if let Some(apic) = apic::try_local() {
+        let _ = apic.send_ipi(Destination::AllButSelf, Delivery::Nmi);
+    }
+}
+
+/// Returns whether `stop_others` has been called
+pub fn stopping() -> bool {
+    STOPPING.load(Ordering::SeqCst)
+}
+
+/// Halt the calling CPU for good if `stop_others` has been called
+///
+/// Called first thing for every NMI, so it must not take any lock.
+pub fn stop_if_stopping() {
+    if stopping() {
+        loop {
+            unsafe { asm!("cli; hlt" :::: "volatile") };
+        }
+    }
+}
+
+fn receive(frame: &mut TrapFrame) {
+    let ipi = Ipi::from_vector(frame.vector())
+        .expect("IPI handler called for another vector");
+    if ipi == Ipi::TlbShootdown {
+        // Reloading CR3 drops every non-global translation
+        unsafe { cr3_write(cr3()) };
+    }
+    let callback = CALLBACKS.read()[ipi as usize];
+    if let Some(f) = callback {
+        f();
+    }
+}
@@ -14,6 +14,9 @@
 // along with Genesis.  If not, see <http://www.gnu.org/licenses/>.
 pub use x86::paging::*;
 use memory::{FrameAllocator, Page};
+use memory::frame_cache::CpuCache;
+use memory::frame_table::{self, FrameUsage};
+use super::percpu;
 
 use core::cmp::Ordering;
 use core::mem;
@@ -21,6 +24,8 @@ use core::ops::{Add, Sub};
 use core::ptr::Unique;
 
 pub const PHYS_MAP: usize = 0xFFFF_FF80_0000_0000;
+/// End of the lower half of the address space, which belongs to user mode
+pub const USER_END: usize = 0x0000_8000_0000_0000;
 pub const PHYS_LIMIT: u64 = 0x80_0000_0000;
 
 pub fn phys_to_virt(p: PAddr) -> VAddr {
@@ -28,6 +33,26 @@ pub fn phys_to_virt(p: PAddr) -> VAddr {
     VAddr::from_usize(p.as_u64() as usize + PHYS_MAP)
 }
 
+/// Returns the calling CPU's frame cache
+///
+/// The cache is found through GS, so the CPU's per-CPU data must be set up.
+pub fn cpu_frame_cache() -> &'static CpuCache {
+    percpu::current().frame_cache()
+}
+
+/// Returns the kernel's index for the calling CPU, if its per-CPU data is
+/// set up
+#[cfg(not(test))]
+pub fn cpu_index() -> Option<usize> {
+    percpu::try_current().map(|percpu| percpu.cpu())
+}
+
+// Host tests run without per-CPU data, reading the GS base MSR would fault
+#[cfg(test)]
+pub fn cpu_index() -> Option<usize> {
+    None
+}
+
 pub type PageSlice = [u8; PAGE_SIZE as usize];
 
 pub unsafe fn frame_to_slice<'a>(frame: Frame) -> &'a mut PageSlice {
@@ -151,10 +176,125 @@ impl PartialOrd for FrameRange {
     }
 }
 
+bitflags! {
+    /// Page fault error code pushed by the CPU
+    pub flags PageFaultError: u64 {
+        /// The fault was a protection violation on a present page
+        const PF_PRESENT = 1 << 0,
+        /// The faulting access was a write
+        const PF_WRITE = 1 << 1,
+        /// The faulting access came from user mode
+        const PF_USER = 1 << 2,
+        /// A reserved bit was set in a paging structure
+        const PF_RESERVED = 1 << 3,
+        /// The faulting access was an instruction fetch
+        const PF_INSTRUCTION = 1 << 4,
+    }
+}
+
+/// Start of the upper half of the address space, which belongs to the kernel
+pub const KERNEL_START: usize = 0xFFFF_8000_0000_0000;
+// Pages covered by one entry of a PD, PDPT and PML4
+const PD_ENTRY_PAGES: usize = 1 << 9;
+const PDPT_ENTRY_PAGES: usize = 1 << 18;
+const PML4_ENTRY_PAGES: usize = 1 << 27;
+
+/// A 4-level page table of 4 KiB mappings
+///
+/// Paging structures are reached through a function returning the
+/// contents of a `Frame`. Those emptied by `unmap` are handed back to the
+/// caller, except for the PDPTs of the kernel half, which are shared by
+/// every address space (see `share_kernel_half`).
 pub struct PageTable {
     table: Unique<PML4>,
 }
 
+/// The paging structures leading to one page
+struct Walk<'a> {
+    pml4_idx: usize,
+    pdpt: &'a mut PDPT,
+    pdpt_idx: usize,
+    pd: &'a mut PD,
+    pd_idx: usize,
+    pt: &'a mut PT,
+    pt_idx: usize,
+}
+
+/// Returns the paging structure held in `frame`
+fn table<'a, T, F>(frame: Frame, f: &F) -> &'a mut T
+    where F: Fn(Frame) -> &'a mut PageSlice
+{
+    unsafe { &mut *(f(frame) as *mut PageSlice as *mut T) }
+}
+
+/// Allocate a zeroed frame for a paging structure
+fn new_table<'a, Allocator, F>(allocator: &Allocator, f: &F) -> Frame
+    where Allocator: FrameAllocator,
+          F: Fn(Frame) -> &'a mut PageSlice
+{
+    let frame = allocator.allocate_manual()
+        .expect("Could not allocate frame for paging structure");
+    frame_table::set_usage(frame, FrameUsage::PageTable);
+    for b in f(frame).iter_mut() {
+        *b = 0;
+    }
+    frame
+}
+
+fn page_number(page: Page) -> usize {
+    page - Page::down(VAddr::from_usize(0))
+}
+
+fn page_at(number: usize) -> Page {
+    Page::down(VAddr::from_usize(number << PAGE_SHIFT))
+}
+
+/// Returns the first present page in [`next`, `end`), by page number, and
+/// its entry
+fn next_mapped<'a, F>(pml4: &PML4,
+                      mut next: usize,
+                      end: usize,
+                      f: &F)
+                      -> Option<(usize, PTEntry)>
+    where F: Fn(Frame) -> &'a mut PageSlice
+{
+    // The first page covered by the next entry of a structure whose
+    // entries cover `pages` pages
+    let skip = |next: usize, pages: usize| (next & !(pages - 1)) + pages;
+    while next < end {
+        let addr = VAddr::from_usize(next << PAGE_SHIFT);
+        if addr.as_usize() >= USER_END && addr.as_usize() < KERNEL_START {
+            // Non-canonical addresses alias the entries of the upper half
+            next = KERNEL_START >> PAGE_SHIFT;
+            continue;
+        }
+        let pml4e = pml4[pml4_index(addr)];
+        if pml4e.is_empty() {
+            next = skip(next, PML4_ENTRY_PAGES);
+            continue;
+        }
+        let pdpt: &mut PDPT = table(Frame::down(pml4e.get_address()), f);
+        let pdpte = pdpt[pdpt_index(addr)];
+        if pdpte.is_empty() {
+            next = skip(next, PDPT_ENTRY_PAGES);
+            continue;
+        }
+        let pd: &mut PD = table(Frame::down(pdpte.get_address()), f);
+        let pde = pd[pd_index(addr)];
+        if pde.is_empty() {
+            next = skip(next, PD_ENTRY_PAGES);
+            continue;
+        }
+        let pt: &mut PT = table(Frame::down(pde.get_address()), f);
+        let pte = pt[pt_index(addr)];
+        if pte.contains(PT_P) {
+            return Some((next, pte));
+        }
+        next += 1;
+    }
+    None
+}
+
 impl PageTable {
     pub unsafe fn new(table: *mut PML4) -> PageTable {
         PageTable { table: Unique::new(table) }
@@ -168,6 +308,15 @@ impl PageTable {
         unsafe { self.table.get_mut() }
     }
 
+    /// Share the kernel (upper) half of `kernel` with this table
+    ///
+    /// The PML4 entries themselves are copied, so later kernel mappings
+    /// are only seen here if they fall under an entry that was present.
+    pub fn share_kernel_half(&mut self, kernel: &PageTable) {
+        let first = pml4_index(VAddr::from_usize(USER_END));
+        self.get_mut()[first..].copy_from_slice(&kernel.get()[first..]);
+    }
+
     pub fn map<'a, Allocator, F>(&'a mut self,
                                  page: Page,
                                  frame: Frame,
@@ -177,50 +326,156 @@ impl PageTable {
         where Allocator: FrameAllocator,
               F: Fn(Frame) -> &'a mut [u8; PAGE_SIZE as usize]
     {
-        let pml4 = self.get_mut();
-        let pml4_idx = pml4_index(page.start_address());
-        if pml4[pml4_idx].is_empty() {
-            let frame = allocator.allocate_manual()
-                .expect("Could not allocate frame for PDPT");
-            for b in f(frame).iter_mut() {
-                *b = 0;
-            }
-            pml4[pml4_idx] = PML4Entry::new(frame.start_address(),
-                                            PML4_P | PML4_RW);
+        self.map_entry(page, frame, flags, allocator, &f)
+    }
+
+    /// Map `frames` at consecutive pages, starting with `page`
+    pub fn map_range<'a, Allocator, F>(&mut self,
+                                       page: Page,
+                                       frames: FrameRange,
+                                       flags: PTEntry,
+                                       allocator: &Allocator,
+                                       f: F)
+        where Allocator: FrameAllocator,
+              F: Fn(Frame) -> &'a mut PageSlice
+    {
+        for i in 0..frames.nframes() {
+            self.map_entry(page + i as usize,
+                           frames.lower() + i,
+                           flags,
+                           allocator,
+                           &f);
         }
-        let pdpt: &mut PDPT = unsafe {
-            mem::transmute(f(Frame::down(pml4[pml4_idx].get_address())))
-        };
-        let pdpt_idx = pdpt_index(page.start_address());
-        if pdpt[pdpt_idx].is_empty() {
-            let frame = allocator.allocate_manual()
-                .expect("Could not allocate frame for PD");
-            for b in f(frame).iter_mut() {
-                *b = 0;
-            }
-            pdpt[pdpt_idx] = PDPTEntry::new(frame.start_address(),
-                                            PDPT_P | PDPT_RW);
+    }
+
+    /// Remove the mapping of `page` and return the frame it mapped
+    ///
+    /// Paging structures left empty are unlinked and passed to `retired`.
+    /// Any CPU may still hold them in its paging-structure caches, so the
+    /// caller must flush `page` from the TLB of every CPU using this table
+    /// before freeing them.
+    pub fn unmap<'a, F, G>(&mut self,
+                           page: Page,
+                           f: F,
+                           mut retired: G)
+                           -> Option<Frame>
+        where F: Fn(Frame) -> &'a mut PageSlice,
+              G: FnMut(Frame)
+    {
+        self.unmap_entry(page, &f, &mut retired)
+    }
+
+    /// Remove every mapping of [`start`, `end`) and return how many there
+    /// were
+    ///
+    /// `unmapped` is called with each page and the frame it mapped. As with
+    /// `unmap` the caller must shoot down the range on every CPU before
+    /// freeing the frames passed to `retired`.
+    pub fn unmap_range<'a, F, G, H>(&mut self,
+                                    start: Page,
+                                    end: Page,
+                                    f: F,
+                                    mut unmapped: G,
+                                    mut retired: H)
+                                    -> usize
+        where F: Fn(Frame) -> &'a mut PageSlice,
+              G: FnMut(Page, Frame),
+              H: FnMut(Frame)
+    {
+        let mut count = 0;
+        let mut next = page_number(start);
+        while let Some((number, _)) =
+                  next_mapped(self.get(), next, page_number(end), &f) {
+            let page = page_at(number);
+            let frame = self.unmap_entry(page, &f, &mut retired)
+                .expect("Mapped page has no frame");
+            unmapped(page, frame);
+            count += 1;
+            next = number + 1;
         }
+        count
+    }
 
-        let pd: &mut PD = unsafe {
-            mem::transmute(f(Frame::down(pdpt[pdpt_idx].get_address())))
-        };
-        let pd_idx = pd_index(page.start_address());
-        if pd[pd_idx].is_empty() {
-            let frame = allocator.allocate_manual()
-                .expect("Could not allocate frame for PT");
-            for b in f(frame).iter_mut() {
-                *b = 0;
+    /// Change the flags of the mapping of `page`, keeping its frame
+    ///
+    /// Returns the old flags, or `None` if `page` is not mapped. `flags`
+    /// must include `PT_P`. The caller must flush `page` from the TLB.
+    pub fn protect<'a, F>(&mut self,
+                          page: Page,
+                          flags: PTEntry,
+                          f: F)
+                          -> Option<PTEntry>
+        where F: Fn(Frame) -> &'a mut PageSlice
+    {
+        self.protect_entry(page, flags, &f)
+    }
+
+    /// Change the flags of every mapping of [`start`, `end`) and return how
+    /// many there were
+    ///
+    /// As with `protect` the caller must flush the TLB.
+    pub fn protect_range<'a, F>(&mut self,
+                                start: Page,
+                                end: Page,
+                                flags: PTEntry,
+                                f: F)
+                                -> usize
+        where F: Fn(Frame) -> &'a mut PageSlice
+    {
+        let mut count = 0;
+        let mut next = page_number(start);
+        while let Some((number, _)) =
+                  next_mapped(self.get(), next, page_number(end), &f) {
+            self.protect_entry(page_at(number), flags, &f);
+            count += 1;
+            next = number + 1;
+        }
+        count
+    }
+
+    /// Returns the physical address `addr` is mapped to
+    pub fn translate<'a, F>(&self, addr: VAddr, f: F) -> Option<PAddr>
+        where F: Fn(Frame) -> &'a mut PageSlice
+    {
+        self.walk(Page::down(addr), &f).and_then(|walk| {
+            let entry = walk.pt[walk.pt_idx];
+            if entry.contains(PT_P) {
+                let offset = addr.as_usize() as u64 & (PAGE_SIZE - 1);
+                Some(PAddr::from_u64(entry.get_address().as_u64() + offset))
+            } else {
+                None
             }
-            pd[pd_idx] = PDEntry::new(frame.start_address(), PD_P | PD_RW);
+        })
+    }
+
+    /// Returns an iterator over the mapped pages of [`start`, `end`) and
+    /// their entries, in address order
+    pub fn mappings<'a, 'b, F>(&'a self,
+                               start: Page,
+                               end: Page,
+                               f: F)
+                               -> Mappings<'a, F>
+        where F: Fn(Frame) -> &'b mut PageSlice
+    {
+        Mappings {
+            pml4: self.get(),
+            f: f,
+            next: page_number(start),
+            end: page_number(end),
         }
+    }
 
-        let pt: &mut PT = unsafe {
-            mem::transmute(f(Frame::down(pd[pd_idx].get_address())))
-        };
-        let pt_idx = pt_index(page.start_address());
-        assert!(pt[pt_idx].is_empty());
-        pt[pt_idx] = PTEntry::new(frame.start_address(), flags);
+    /// Returns the `PTEntry` for `page`, if its paging structures exist
+    pub fn entry_mut<'a, F>(&'a mut self,
+                            page: Page,
+                            f: F)
+                            -> Option<&'a mut PTEntry>
+        where F: Fn(Frame) -> &'a mut [u8; PAGE_SIZE as usize]
+    {
+        self.walk(page, &f).map(|walk| {
+            let Walk { pt, pt_idx, .. } = walk;
+            &mut pt[pt_idx]
+        })
     }
 
     pub fn map_device<'a, Allocator, F>(&'a mut self,
@@ -233,4 +488,385 @@ impl PageTable {
     {
         self.map(page, frame, PT_P | PT_G | PT_RW | PT_PCD, allocator, f)
     }
+
+    /// Returns the paging structures leading to `page`, if they all exist
+    fn walk<'a, F>(&self, page: Page, f: &F) -> Option<Walk<'a>>
+        where F: Fn(Frame) -> &'a mut PageSlice
+    {
+        let addr = page.start_address();
+        let pml4_idx = pml4_index(addr);
+        let pml4e = self.get()[pml4_idx];
+        if pml4e.is_empty() {
+            return None;
+        }
+        let pdpt: &mut PDPT = table(Frame::down(pml4e.get_address()), f);
+        let pdpt_idx = pdpt_index(addr);
+        if pdpt[pdpt_idx].is_empty() {
+            return None;
+        }
+        let pd: &mut PD = table(Frame::down(pdpt[pdpt_idx].get_address()), f);
+        let pd_idx = pd_index(addr);
+        if pd[pd_idx].is_empty() {
+            return None;
+        }
+        let pt: &mut PT = table(Frame::down(pd[pd_idx].get_address()), f);
+        Some(Walk {
+            pml4_idx: pml4_idx,
+            pdpt: pdpt,
+            pdpt_idx: pdpt_idx,
+            pd: pd,
+            pd_idx: pd_idx,
+            pt: pt,
+            pt_idx: pt_index(addr),
+        })
+    }
+
+    fn map_entry<'a, Allocator, F>(&mut self,
+                                   page: Page,
+                                   frame: Frame,
+                                   flags: PTEntry,
+                                   allocator: &Allocator,
+                                   f: &F)
+        where Allocator: FrameAllocator,
+              F: Fn(Frame) -> &'a mut PageSlice
+    {
+        // User pages must be reachable through user paging structures
+        let user = flags.contains(PT_US);
+        let addr = page.start_address();
+        let pml4 = self.get_mut();
+        let pml4_idx = pml4_index(addr);
+        if pml4[pml4_idx].is_empty() {
+            let new = new_table(allocator, f);
+            pml4[pml4_idx] = PML4Entry::new(new.start_address(),
+                                            PML4_P | PML4_RW);
+        }
+        if user {
+            pml4[pml4_idx].insert(PML4_US);
+        }
+        let pdpt: &mut PDPT = table(Frame::down(pml4[pml4_idx].get_address()),
+                                    f);
+        let pdpt_idx = pdpt_index(addr);
+        if pdpt[pdpt_idx].is_empty() {
+            let new = new_table(allocator, f);
+            pdpt[pdpt_idx] = PDPTEntry::new(new.start_address(),
+                                            PDPT_P | PDPT_RW);
+        }
+        if user {
+            pdpt[pdpt_idx].insert(PDPT_US);
+        }
+
+        let pd: &mut PD = table(Frame::down(pdpt[pdpt_idx].get_address()), f);
+        let pd_idx = pd_index(addr);
+        if pd[pd_idx].is_empty() {
+            let new = new_table(allocator, f);
+            pd[pd_idx] = PDEntry::new(new.start_address(), PD_P | PD_RW);
+        }
+        if user {
+            pd[pd_idx].insert(PD_US);
+        }
+
+        let pt: &mut PT = table(Frame::down(pd[pd_idx].get_address()), f);
+        let pt_idx = pt_index(addr);
+        assert!(pt[pt_idx].is_empty());
+        pt[pt_idx] = PTEntry::new(frame.start_address(), flags);
+    }
+
+    fn unmap_entry<'a, F, G>(&mut self,
+                             page: Page,
+                             f: &F,
+                             retired: &mut G)
+                             -> Option<Frame>
+        where F: Fn(Frame) -> &'a mut PageSlice,
+              G: FnMut(Frame)
+    {
+        let Walk { pml4_idx, pdpt, pdpt_idx, pd, pd_idx, pt, pt_idx } =
+            match self.walk(page, f) {
+                Some(walk) => walk,
+                None => return None,
+            };
+        if !pt[pt_idx].contains(PT_P) {
+            return None;
+        }
+        let frame = Frame::down(pt[pt_idx].get_address());
+        pt[pt_idx] = PTEntry::empty();
+        if pt.iter().any(|e| !e.is_empty()) {
+            return Some(frame);
+        }
+        let pt_frame = Frame::down(pd[pd_idx].get_address());
+        pd[pd_idx] = PDEntry::empty();
+        retired(pt_frame);
+        if pd.iter().any(|e| !e.is_empty()) {
+            return Some(frame);
+        }
+        let pd_frame = Frame::down(pdpt[pdpt_idx].get_address());
+        pdpt[pdpt_idx] = PDPTEntry::empty();
+        retired(pd_frame);
+        let user = pml4_idx < pml4_index(VAddr::from_usize(USER_END));
+        if user && pdpt.iter().all(|e| e.is_empty()) {
+            let pml4 = self.get_mut();
+            let pdpt_frame = Frame::down(pml4[pml4_idx].get_address());
+            pml4[pml4_idx] = PML4Entry::empty();
+            retired(pdpt_frame);
+        }
+        Some(frame)
+    }
+
+    fn protect_entry<'a, F>(&mut self,
+                            page: Page,
+                            flags: PTEntry,
+                            f: &F)
+                            -> Option<PTEntry>
+        where F: Fn(Frame) -> &'a mut PageSlice
+    {
+        debug_assert!(flags.contains(PT_P));
+        let Walk { pml4_idx, pdpt, pdpt_idx, pd, pd_idx, pt, pt_idx } =
+            match self.walk(page, f) {
+                Some(walk) => walk,
+                None => return None,
+            };
+        let entry = &mut pt[pt_idx];
+        if !entry.contains(PT_P) {
+            return None;
+        }
+        let old = PTEntry::from_bits_truncate(entry.bits());
+        *entry = PTEntry::new(entry.get_address(), flags);
+        if flags.contains(PT_US) {
+            self.get_mut()[pml4_idx].insert(PML4_US);
+            pdpt[pdpt_idx].insert(PDPT_US);
+            pd[pd_idx].insert(PD_US);
+        }
+        Some(old)
+    }
+}
+
+/// Iterator over the mapped pages of a `PageTable`, see
+/// `PageTable::mappings`
+pub struct Mappings<'a, F> {
+    pml4: &'a PML4,
+    f: F,
+    next: usize,
+    end: usize,
+}
+
+impl<'a, 'b, F> Iterator for Mappings<'a, F>
+    where F: Fn(Frame) -> &'b mut PageSlice
+{
+    type Item = (Page, PTEntry);
+
+    fn next(&mut self) -> Option<(Page, PTEntry)> {
+        match next_mapped(self.pml4, self.next, self.end, &self.f) {
+            Some((number, entry)) => {
+                self.next = number + 1;
+                Some((page_at(number), entry))
+            }
+            None => {
+                self.next = self.end;
+                None
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use memory::{FrameAllocator, Page};
+    use memory::zone::Constraint;
+    use spin;
+    use std::vec::Vec;
+    use super::*;
+
+    // Frames backed by host memory, frame 0 holds the PML4
+    const FRAMES: u64 = 32;
+
+    struct Tables {
+        free: spin::Mutex<Vec<Frame>>,
+    }
+
+    impl Tables {
+        fn new() -> Tables {
+            Tables { free: spin::Mutex::new((1..FRAMES).map(frame).collect()) }
+        }
+
+        fn free(&self) -> u64 {
+            self.free.lock().len() as u64
+        }
+    }
+
+    impl FrameAllocator for Tables {
+        fn allocate_manual(&self) -> Option<Frame> {
+            self.free.lock().pop()
+        }
+
+        unsafe fn free_manual(&self, frame: Frame) {
+            assert!(frame.start_address().as_u64() >> PAGE_SHIFT < FRAMES);
+            self.free.lock().push(frame);
+        }
+
+        fn allocate_range_manual(&self, _: u64) -> Option<FrameRange> {
+            None
+        }
+
+        unsafe fn free_range_manual(&self, _: FrameRange) {}
+
+        fn allocate_constrained_manual(&self,
+                                       _: u64,
+                                       _: u64,
+                                       _: Constraint)
+                                       -> Option<FrameRange> {
+            None
+        }
+    }
+
+    fn frame(number: u64) -> Frame {
+        Frame::down(PAddr::from_u64(number << PAGE_SHIFT))
+    }
+
+    fn page(addr: usize) -> Page {
+        Page::down(VAddr::from_usize(addr))
+    }
+
+    fn map(table: &mut PageTable,
+           tables: &Tables,
+           memory: *mut [u64; 512],
+           addr: usize,
+           frame: Frame,
+           flags: PTEntry) {
+        table.map(page(addr), frame, flags, tables, |f| slice(memory, f));
+    }
+
+    fn slice<'a>(memory: *mut [u64; 512], frame: Frame) -> &'a mut PageSlice {
+        let index = frame.start_address().as_u64() >> PAGE_SHIFT;
+        assert!(index < FRAMES);
+        unsafe { &mut *(memory.offset(index as isize) as *mut PageSlice) }
+    }
+
+    #[test]
+    fn test_map_translate() {
+        let mut memory = vec![[0u64; 512]; FRAMES as usize];
+        let base = memory.as_mut_ptr();
+        let f = |frame: Frame| slice(base, frame);
+        let tables = Tables::new();
+        let mut table = unsafe { PageTable::new(base as *mut PML4) };
+
+        map(&mut table, &tables, base, 0x40_0000, frame(100), PT_P | PT_RW);
+        assert_eq!(tables.free(), FRAMES - 4);
+        assert_eq!(table.translate(VAddr::from_usize(0x40_0123), &f),
+                   Some(PAddr::from_u64((100 << PAGE_SHIFT) + 0x123)));
+        assert_eq!(table.translate(VAddr::from_usize(0x40_1000), &f), None);
+        assert_eq!(table.translate(VAddr::from_usize(0x80_0000_0000), &f),
+                   None);
+
+        table.map_range(page(0x1000),
+                        FrameRange::new(frame(200), frame(203)),
+                        PT_P,
+                        &tables,
+                        &f);
+        assert_eq!(tables.free(), FRAMES - 5);
+        assert_eq!(table.translate(VAddr::from_usize(0x3fff), &f),
+                   Some(PAddr::from_u64((203 << PAGE_SHIFT) - 1)));
+    }
+
+    #[test]
+    fn test_unmap() {
+        let mut memory = vec![[0u64; 512]; FRAMES as usize];
+        let base = memory.as_mut_ptr();
+        let f = |frame: Frame| slice(base, frame);
+        let tables = Tables::new();
+        let mut table = unsafe { PageTable::new(base as *mut PML4) };
+
+        let retire = |frame| unsafe { tables.free_manual(frame) };
+
+        map(&mut table, &tables, base, 0x40_0000, frame(100), PT_P | PT_US);
+        map(&mut table, &tables, base, 0x40_1000, frame(101), PT_P | PT_US);
+        assert_eq!(table.unmap(page(0x40_0000), &f, &retire),
+                   Some(frame(100)));
+        assert_eq!(tables.free(), FRAMES - 4);
+        assert_eq!(table.unmap(page(0x40_0000), &f, &retire), None);
+        // The last mapping takes its paging structures with it
+        let mut retired = Vec::new();
+        assert_eq!(table.unmap(page(0x40_1000), &f, |t| retired.push(t)),
+                   Some(frame(101)));
+        assert_eq!(retired.len(), 3);
+        assert_eq!(tables.free(), FRAMES - 4);
+        for &t in &retired {
+            retire(t);
+        }
+        assert_eq!(tables.free(), FRAMES - 1);
+        assert!(table.get()[0].is_empty());
+
+        // Kernel PDPTs are shared and stay around
+        map(&mut table, &tables, base, KERNEL_START, frame(102), PT_P);
+        assert_eq!(table.unmap(page(KERNEL_START), &f, &retire),
+                   Some(frame(102)));
+        assert_eq!(tables.free(), FRAMES - 2);
+        assert!(!table.get()[256].is_empty());
+    }
+
+    #[test]
+    fn test_protect() {
+        let mut memory = vec![[0u64; 512]; FRAMES as usize];
+        let base = memory.as_mut_ptr();
+        let f = |frame: Frame| slice(base, frame);
+        let tables = Tables::new();
+        let mut table = unsafe { PageTable::new(base as *mut PML4) };
+
+        map(&mut table, &tables, base, 0x1000, frame(100), PT_P | PT_RW);
+        map(&mut table, &tables, base, 0x2000, frame(101), PT_P | PT_RW);
+        assert_eq!(table.protect(page(0x1000), PT_P | PT_US, &f),
+                   Some(PT_P | PT_RW));
+        assert_eq!(table.protect(page(0x3000), PT_P, &f), None);
+        let (_, entry) = table.mappings(page(0x1000), page(0x2000), &f)
+            .next()
+            .unwrap();
+        assert!(entry.contains(PT_US) && !entry.contains(PT_RW));
+        assert_eq!(entry.get_address(), frame(100).start_address());
+        assert!(table.get()[0].contains(PML4_US));
+
+        assert_eq!(table.protect_range(page(0), page(0x10_0000), PT_P, &f),
+                   2);
+        assert_eq!(table.protect(page(0x2000), PT_P, &f), Some(PT_P));
+    }
+
+    #[test]
+    fn test_mappings() {
+        let mut memory = vec![[0u64; 512]; FRAMES as usize];
+        let base = memory.as_mut_ptr();
+        let f = |frame: Frame| slice(base, frame);
+        let tables = Tables::new();
+        let mut table = unsafe { PageTable::new(base as *mut PML4) };
+
+        let addrs = [0x1000,
+                     0x40_0000,
+                     0x80_0000_0000,
+                     KERNEL_START + 0x2000,
+                     0xFFFF_FFFF_FFFF_F000];
+        for (i, &addr) in addrs.iter().enumerate() {
+            map(&mut table, &tables, base, addr, frame(100 + i as u64), PT_P);
+        }
+        let end = page(0xFFFF_FFFF_FFFF_F000) + 1;
+        let mapped: Vec<_> = table.mappings(page(0), end, &f)
+            .map(|(page, entry)| {
+                (page.start_address().as_usize(), entry.get_address())
+            })
+            .collect();
+        let expected: Vec<_> = addrs.iter()
+            .enumerate()
+            .map(|(i, &addr)| (addr, frame(100 + i as u64).start_address()))
+            .collect();
+        assert_eq!(mapped, expected);
+        assert_eq!(table.mappings(page(0x2000), page(0x40_0000), &f).count(),
+                   0);
+
+        let mut unmapped = Vec::new();
+        assert_eq!(table.unmap_range(page(0),
+                                     end,
+                                     &f,
+                                     |_, frame| unmapped.push(frame),
+                                     |t| unsafe { tables.free_manual(t) }),
+                   addrs.len());
+        assert_eq!(unmapped, (100..105).map(frame).collect::<Vec<_>>());
+        assert_eq!(table.mappings(page(0), end, &f).count(), 0);
+        // Only the two kernel PDPTs are left
+        assert_eq!(tables.free(), FRAMES - 3);
+    }
 }
@@ -14,17 +14,47 @@
 // along with Genesis.  If not, see <http://www.gnu.org/licenses/>.
 pub use super::x86::serial;
 
+/// ACPI table discovery
+mod acpi;
 mod apic;
+/// ELF64 executable parsing
+mod elf;
+/// Handlers for fatal and non-maskable exceptions
+mod exception;
+/// Boot-time benchmark of the frame allocator
+mod frame_bench;
 /// Loading and manipulating the x86_64 Global Descriptor Table
 mod gdt;
 /// Loading and manipulating the x86_64 Interrupt Descriptor Table
 mod idt;
 /// Architecture specific boot code.
 mod init;
+/// I/O APIC driver and external interrupt routing
+mod ioapic;
+/// Inter-processor interrupts
+mod ipi;
 /// Memory management routines
 pub mod mem;
+/// Multiboot2 boot information parsing
+mod multiboot2;
+/// Page fault decoding and dispatch
+mod page_fault;
+/// Per-CPU data reached through the GS base
+mod percpu;
 mod pic;
+/// Application processor startup
+mod smp;
+/// Kernel stack layout
+mod stack;
 mod syscall;
+/// Local APIC timer driver
+mod timer;
+/// Running user mode programs
+mod user;
 
 pub use self::init::arch_init;
 pub use self::idt::interrupt_handler;
+pub use self::ipi::stop_others as stop_other_cpus;
+pub use self::ipi::stopping as other_cpus_stopped;
+pub use self::syscall::syscall_handler;
+pub use self::user::current_space as user_space;
@@ -0,0 +1,479 @@
+// Copyright Dan Schatzberg, 2016. This file is part of Genesis.
+
+// Genesis is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// Genesis is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+
+// You should have received a copy of the GNU Affero General Public License
+// along with Genesis.  If not, see <http://www.gnu.org/licenses/>.
+use core::str;
+
+/// Value of `%eax` when booted by a Multiboot2 loader
+pub const MAGIC: u32 = 0x36D7_6289;
+
+const TAG_END: u32 = 0;
+const TAG_CMDLINE: u32 = 1;
+const TAG_MODULE: u32 = 3;
+const TAG_MEMORY_MAP: u32 = 6;
+const TAG_FRAMEBUFFER: u32 = 8;
+const TAG_RSDP_V1: u32 = 14;
+const TAG_RSDP_V2: u32 = 15;
+const TAG_EFI_MEMORY_MAP: u32 = 17;
+
+// Size of the fixed part of the information structure and of a tag header
+const HEADER_LEN: usize = 8;
+
+const MEMORY_AVAILABLE: u32 = 1;
+
+// EFI memory types that are free once boot services have exited
+const EFI_LOADER_CODE: u32 = 1;
+const EFI_LOADER_DATA: u32 = 2;
+const EFI_BOOT_SERVICES_CODE: u32 = 3;
+const EFI_BOOT_SERVICES_DATA: u32 = 4;
+const EFI_CONVENTIONAL_MEMORY: u32 = 7;
+const EFI_PAGE_SIZE: u64 = 4096;
+
+fn read_u32(data: &[u8], offset: usize) -> u32 {
+    data[offset] as u32 | (data[offset + 1] as u32) << 8 |
+    (data[offset + 2] as u32) << 16 | (data[offset + 3] as u32) << 24
+}
+
+fn read_u64(data: &[u8], offset: usize) -> u64 {
+    read_u32(data, offset) as u64 | (read_u32(data, offset + 4) as u64) << 32
+}
+
+/// Returns the NUL terminated string at the start of `data`
+fn read_str(data: &[u8]) -> &str {
+    let len = data.iter().position(|b| *b == 0).unwrap_or(data.len());
+    str::from_utf8(&data[..len]).unwrap_or("")
+}
+
+/// The Multiboot2 boot information structure
+#[derive(Clone, Copy, Debug)]
+pub struct Info<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> Info<'a> {
+    /// Access the information structure at physical address `addr`
+    ///
+    /// `paddr_to_slice` must return the memory at a physical address.
+    pub fn new<F>(addr: u64, paddr_to_slice: F) -> Option<Info<'a>>
+        where F: Fn(u64, usize) -> Option<&'a [u8]>
+    {
+        let header = match paddr_to_slice(addr, HEADER_LEN) {
+            Some(header) => header,
+            None => return None,
+        };
+        let total_size = read_u32(header, 0) as usize;
+        if total_size < HEADER_LEN {
+            return None;
+        }
+        paddr_to_slice(addr, total_size).map(|data| Info { data: data })
+    }
+
+    /// Returns every tag, in the order the boot loader wrote them
+    pub fn tags(&self) -> Tags<'a> {
+        Tags {
+            data: self.data,
+            offset: HEADER_LEN,
+        }
+    }
+
+    /// Returns the kernel command line
+    pub fn command_line(&self) -> Option<&'a str> {
+        self.tags()
+            .filter_map(|tag| match tag {
+                Tag::CommandLine(cmdline) => Some(cmdline),
+                _ => None,
+            })
+            .next()
+    }
+
+    /// Returns the newest copy of the ACPI RSDP
+    pub fn rsdp(&self) -> Option<&'a [u8]> {
+        let mut rsdp = None;
+        for tag in self.tags() {
+            match tag {
+                Tag::RsdpV2(v2) => return Some(v2),
+                Tag::RsdpV1(v1) => rsdp = Some(v1),
+                _ => {}
+            }
+        }
+        rsdp
+    }
+}
+
+/// A boot information tag
+#[derive(Clone, Copy, Debug)]
+pub enum Tag<'a> {
+    /// The kernel command line
+    CommandLine(&'a str),
+    /// A module loaded alongside the kernel
+    Module(Module<'a>),
+    /// The BIOS memory map
+    MemoryMap(MemoryMap<'a>),
+    /// The framebuffer set up by the boot loader
+    Framebuffer(Framebuffer),
+    /// A copy of an ACPI 1.0 RSDP
+    RsdpV1(&'a [u8]),
+    /// A copy of an ACPI 2.0+ RSDP
+    RsdpV2(&'a [u8]),
+    /// The EFI memory map
+    EfiMemoryMap(MemoryMap<'a>),
+    /// A tag that is not parsed, with its type
+    Other(u32),
+}
+
+/// Iterator over the tags of an `Info`
+#[derive(Clone, Copy, Debug)]
+pub struct Tags<'a> {
+    data: &'a [u8],
+    offset: usize,
+}
+
+impl<'a> Iterator for Tags<'a> {
+    type Item = Tag<'a>;
+
+    fn next(&mut self) -> Option<Tag<'a>> {
+        if self.offset + HEADER_LEN > self.data.len() {
+            return None;
+        }
+        let typ = read_u32(self.data, self.offset);
+        let size = read_u32(self.data, self.offset + 4) as usize;
+        if typ == TAG_END || size < HEADER_LEN ||
+           self.offset + size > self.data.len() {
+            self.offset = self.data.len();
+            return None;
+        }
+        let body = &self.data[self.offset + HEADER_LEN..self.offset + size];
+        // Tags are 8 byte aligned
+        self.offset += (size + 7) & !7;
+        Some(parse_tag(typ, body).unwrap_or(Tag::Other(typ)))
+    }
+}
+
+/// Parse the tag body `body` of type `typ`, `None` if it is malformed
+fn parse_tag(typ: u32, body: &[u8]) -> Option<Tag> {
+    let tag = match typ {
+        TAG_CMDLINE => Tag::CommandLine(read_str(body)),
+        TAG_MODULE if body.len() >= 8 => {
+            Tag::Module(Module {
+                start: read_u32(body, 0) as u64,
+                end: read_u32(body, 4) as u64,
+                string: read_str(&body[8..]),
+            })
+        }
+        TAG_MEMORY_MAP if body.len() >= 8 => {
+            return MemoryMap::new(MapKind::Bios, body).map(Tag::MemoryMap);
+        }
+        TAG_EFI_MEMORY_MAP if body.len() >= 8 => {
+            return MemoryMap::new(MapKind::Efi, body).map(Tag::EfiMemoryMap);
+        }
+        TAG_FRAMEBUFFER if body.len() >= 22 => {
+            Tag::Framebuffer(Framebuffer {
+                addr: read_u64(body, 0),
+                pitch: read_u32(body, 8),
+                width: read_u32(body, 12),
+                height: read_u32(body, 16),
+                bpp: body[20],
+                kind: body[21],
+            })
+        }
+        TAG_RSDP_V1 => Tag::RsdpV1(body),
+        TAG_RSDP_V2 => Tag::RsdpV2(body),
+        TAG_MODULE | TAG_MEMORY_MAP | TAG_EFI_MEMORY_MAP |
+        TAG_FRAMEBUFFER => return None,
+        _ => Tag::Other(typ),
+    };
+    Some(tag)
+}
+
+/// A module loaded alongside the kernel
+#[derive(Clone, Copy, Debug)]
+pub struct Module<'a> {
+    /// Physical address of the first byte
+    pub start: u64,
+    /// Physical address one past the last byte
+    pub end: u64,
+    /// The string the module was given, usually its path and arguments
+    pub string: &'a str,
+}
+
+/// Information about a linear framebuffer
+#[derive(Clone, Copy, Debug)]
+pub struct Framebuffer {
+    /// Physical address of the framebuffer
+    pub addr: u64,
+    /// Bytes per line
+    pub pitch: u32,
+    /// Width in pixels (or characters in text mode)
+    pub width: u32,
+    /// Height in pixels (or characters in text mode)
+    pub height: u32,
+    /// Bits per pixel
+    pub bpp: u8,
+    /// 0 for indexed color, 1 for direct RGB and 2 for EGA text
+    pub kind: u8,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum MapKind {
+    Bios,
+    Efi,
+}
+
+/// A BIOS or EFI memory map
+#[derive(Clone, Copy, Debug)]
+pub struct MemoryMap<'a> {
+    kind: MapKind,
+    entry_size: usize,
+    entries: &'a [u8],
+}
+
+impl<'a> MemoryMap<'a> {
+    fn new(kind: MapKind, body: &'a [u8]) -> Option<MemoryMap<'a>> {
+        let entry_size = read_u32(body, 0) as usize;
+        let min_size = match kind {
+            MapKind::Bios => 24,
+            MapKind::Efi => 40,
+        };
+        if entry_size < min_size {
+            return None;
+        }
+        Some(MemoryMap {
+            kind: kind,
+            entry_size: entry_size,
+            entries: &body[8..],
+        })
+    }
+}
+
+/// A range of physical memory from a `MemoryMap`
+#[derive(Clone, Copy, Debug)]
+pub struct MemoryArea {
+    /// Physical address of the first byte
+    pub start: u64,
+    /// Physical address one past the last byte
+    pub end: u64,
+    /// Whether the kernel may use the memory
+    pub available: bool,
+}
+
+impl<'a> Iterator for MemoryMap<'a> {
+    type Item = MemoryArea;
+
+    fn next(&mut self) -> Option<MemoryArea> {
+        if self.entries.len() < self.entry_size {
+            return None;
+        }
+        let entry = self.entries;
+        self.entries = &self.entries[self.entry_size..];
+        Some(match self.kind {
+            MapKind::Bios => {
+                let start = read_u64(entry, 0);
+                MemoryArea {
+                    start: start,
+                    end: start.saturating_add(read_u64(entry, 8)),
+                    available: read_u32(entry, 16) == MEMORY_AVAILABLE,
+                }
+            }
+            MapKind::Efi => {
+                let start = read_u64(entry, 8);
+                let pages = read_u64(entry, 24);
+                let available = match read_u32(entry, 0) {
+                    EFI_LOADER_CODE | EFI_LOADER_DATA |
+                    EFI_BOOT_SERVICES_CODE | EFI_BOOT_SERVICES_DATA |
+                    EFI_CONVENTIONAL_MEMORY => true,
+                    _ => false,
+                };
+                MemoryArea {
+                    start: start,
+                    end: start.saturating_add(pages
+                        .saturating_mul(EFI_PAGE_SIZE)),
+                    available: available,
+                }
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::vec::Vec;
+    use super::{Info, MemoryArea, Tag};
+
+    // Append a tag and pad the structure to the next 8 byte boundary
+    fn push_tag(info: &mut Vec<u8>, typ: u32, body: &[u8]) {
+        push_u32(info, typ);
+        push_u32(info, (8 + body.len()) as u32);
+        info.extend_from_slice(body);
+        while info.len() % 8 != 0 {
+            info.push(0xff);
+        }
+    }
+
+    fn push_u32(data: &mut Vec<u8>, value: u32) {
+        for i in 0..4 {
+            data.push((value >> (i * 8)) as u8);
+        }
+    }
+
+    fn push_u64(data: &mut Vec<u8>, value: u64) {
+        push_u32(data, value as u32);
+        push_u32(data, (value >> 32) as u32);
+    }
+
+    // An information structure holding `tags`, which end with an end tag
+    fn info_block(tags: &[(u32, Vec<u8>)]) -> Vec<u8> {
+        let mut info = vec![0; 8];
+        for &(typ, ref body) in tags {
+            push_tag(&mut info, typ, body);
+        }
+        push_tag(&mut info, 0, &[]);
+        let len = info.len() as u32;
+        info[..4].copy_from_slice(&[len as u8,
+                                    (len >> 8) as u8,
+                                    (len >> 16) as u8,
+                                    (len >> 24) as u8]);
+        info
+    }
+
+    // Physical memory holding only `info`, at 0x1000
+    fn info(data: &[u8]) -> Option<Info> {
+        Info::new(0x1000, |addr, len| if addr == 0x1000 &&
+                                         len <= data.len() {
+            Some(&data[..len])
+        } else {
+            None
+        })
+    }
+
+    fn areas(tag: Tag) -> Vec<MemoryArea> {
+        match tag {
+            Tag::MemoryMap(map) |
+            Tag::EfiMemoryMap(map) => map.collect(),
+            tag => panic!("Expected a memory map, got {:?}", tag),
+        }
+    }
+
+    #[test]
+    fn test_tags_aligned() {
+        let data = info_block(&[(1, b"abc\0".to_vec()),
+                                (14, vec![0x11; 20]),
+                                (42, vec![0; 3])]);
+        let info = info(&data).unwrap();
+        let tags: Vec<Tag> = info.tags().collect();
+        assert_eq!(tags.len(), 3);
+        match tags[0] {
+            Tag::CommandLine(cmdline) => assert_eq!(cmdline, "abc"),
+            tag => panic!("Expected the command line, got {:?}", tag),
+        }
+        match tags[1] {
+            Tag::RsdpV1(rsdp) => assert_eq!(rsdp, &[0x11; 20][..]),
+            tag => panic!("Expected an RSDP, got {:?}", tag),
+        }
+        match tags[2] {
+            Tag::Other(typ) => assert_eq!(typ, 42),
+            tag => panic!("Expected an unknown tag, got {:?}", tag),
+        }
+        assert_eq!(info.command_line(), Some("abc"));
+    }
+
+    #[test]
+    fn test_malformed_tags() {
+        // A tag running past the end of the structure
+        let mut data = info_block(&[(1, b"abc\0".to_vec())]);
+        data[12] = 64;
+        assert_eq!(info(&data).unwrap().tags().count(), 0);
+        // A tag too small for its own header
+        let mut data = info_block(&[(1, b"abc\0".to_vec()),
+                                    (42, Vec::new())]);
+        data[12] = 4;
+        assert_eq!(info(&data).unwrap().tags().count(), 0);
+        // A structure cut short in a tag header
+        let mut data = info_block(&[(1, b"abc\0".to_vec())]);
+        data.truncate(20);
+        data[0] = 20;
+        assert_eq!(info(&data).unwrap().tags().count(), 1);
+    }
+
+    #[test]
+    fn test_info_too_small() {
+        let mut data = info_block(&[]);
+        data[0] = 4;
+        assert!(info(&data).is_none());
+        data[0] = 64;
+        assert!(info(&data).is_none());
+    }
+
+    #[test]
+    fn test_bios_memory_map() {
+        // Entries larger than the 24 bytes parsed are skipped over whole
+        let mut body = Vec::new();
+        push_u32(&mut body, 32);
+        push_u32(&mut body, 0);
+        for &(start, len, typ) in &[(0, 0x9f000, 1), (0xf0000, 0x10000, 2)] {
+            push_u64(&mut body, start);
+            push_u64(&mut body, len);
+            push_u32(&mut body, typ);
+            body.extend_from_slice(&[0xff; 12]);
+        }
+        let data = info_block(&[(6, body)]);
+        let areas = areas(info(&data).unwrap().tags().next().unwrap());
+        assert_eq!(areas.len(), 2);
+        assert_eq!((areas[0].start, areas[0].end, areas[0].available),
+                   (0, 0x9f000, true));
+        assert_eq!((areas[1].start, areas[1].end, areas[1].available),
+                   (0xf0000, 0x100000, false));
+
+        let mut body = Vec::new();
+        push_u32(&mut body, 16);
+        push_u32(&mut body, 0);
+        body.extend_from_slice(&[0; 16]);
+        let data = info_block(&[(6, body)]);
+        match info(&data).unwrap().tags().next().unwrap() {
+            Tag::Other(typ) => assert_eq!(typ, 6),
+            tag => panic!("Expected a rejected map, got {:?}", tag),
+        }
+    }
+
+    #[test]
+    fn test_efi_memory_map() {
+        let mut body = Vec::new();
+        push_u32(&mut body, 48);
+        push_u32(&mut body, 1);
+        // Conventional, reserved, boot services data and ACPI reclaim
+        for (i, &typ) in [7, 0, 4, 9].iter().enumerate() {
+            push_u32(&mut body, typ);
+            push_u32(&mut body, 0);
+            push_u64(&mut body, i as u64 * 0x100000);
+            push_u64(&mut body, 0);
+            push_u64(&mut body, 2);
+            push_u64(&mut body, 0);
+            body.extend_from_slice(&[0xff; 8]);
+        }
+        let data = info_block(&[(17, body)]);
+        let areas = areas(info(&data).unwrap().tags().next().unwrap());
+        let available: Vec<bool> = areas.iter().map(|a| a.available).collect();
+        assert_eq!(available, [true, false, true, false]);
+        assert_eq!((areas[2].start, areas[2].end), (0x200000, 0x202000));
+    }
+
+    #[test]
+    fn test_rsdp_prefers_v2() {
+        let data = info_block(&[(14, vec![1; 20]), (15, vec![2; 36])]);
+        assert_eq!(info(&data).unwrap().rsdp(), Some(&[2; 36][..]));
+        let data = info_block(&[(15, vec![2; 36]), (14, vec![1; 20])]);
+        assert_eq!(info(&data).unwrap().rsdp(), Some(&[2; 36][..]));
+        let data = info_block(&[(14, vec![1; 20])]);
+        assert_eq!(info(&data).unwrap().rsdp(), Some(&[1; 20][..]));
+        assert_eq!(info(&info_block(&[])).unwrap().rsdp(), None);
+    }
+}
@@ -0,0 +1,67 @@
+// Copyright Dan Schatzberg, 2016. This file is part of Genesis.
+
+// Genesis is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// Genesis is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+
+// You should have received a copy of the GNU Affero General Public License
+// along with Genesis.  If not, see <http://www.gnu.org/licenses/>.
+use memory::*;
+use memory::address_space::{self, AddressSpace, FaultResolution};
+use x86::controlregs::cr2;
+use super::idt::{self, PAGE_FAULT_VECTOR, Priority, TrapFrame};
+use super::stack;
+use super::user;
+
+pub fn init() {
+    idt::register(PAGE_FAULT_VECTOR,
+                  page_fault,
+                  "page_fault",
+                  Priority::Kernel)
+        .expect("Could not register the page fault handler");
+}
+
+/// Find the `AddressSpace` responsible for `addr`
+fn owning_space(addr: VAddr) -> Option<&'static AddressSpace> {
+    if addr.as_usize() >= KERNEL_START {
+        address_space::kernel_space()
+    } else {
+        user::current_space()
+    }
+}
+
+fn page_fault(frame: &mut TrapFrame) {
+    let addr = VAddr::from_usize(unsafe { cr2() } as usize);
+    let error = PageFaultError::from_bits_truncate(frame.error_code);
+    let resolution = if stack::is_guard(Page::down(addr)) {
+        FaultResolution::GuardPage
+    } else {
+        owning_space(addr).map_or(FaultResolution::Unhandled,
+                                  |space| space.handle_fault(addr, error))
+    };
+    match resolution {
+        FaultResolution::Resolved => {}
+        FaultResolution::GuardPage if frame.from_user() => {
+            user::fault(frame,
+                        format_args!("guard page hit at {:#X}", addr))
+        }
+        FaultResolution::Unhandled if frame.from_user() => {
+            user::fault(frame,
+                        format_args!("page fault at {:#X} ({:?})",
+                                     addr,
+                                     error))
+        }
+        FaultResolution::GuardPage => {
+            panic!("Guard page hit at {:#X} ({:?})\n{}", addr, error, frame)
+        }
+        FaultResolution::Unhandled => {
+            panic!("Page fault at {:#X} ({:?})\n{}", addr, error, frame)
+        }
+    }
+}
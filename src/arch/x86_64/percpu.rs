@@ -0,0 +1,193 @@
+// Copyright Dan Schatzberg, 2016. This file is part of Genesis.
+
+// Genesis is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// Genesis is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+
+// You should have received a copy of the GNU Affero General Public License
+// along with Genesis.  If not, see <http://www.gnu.org/licenses/>.
+use core::cell::Cell;
+use core::mem;
+use core::ptr;
+use memory::*;
+use memory::address_space::AddressSpace;
+use memory::frame_cache::{self, CpuCache};
+use x86::msr::*;
+use super::apic::Apic;
+use super::gdt::Gdt;
+use super::timer::Timer;
+
+// Offsets used by the entry code in syscall.S
+const KERNEL_STACK_OFFSET: usize = 8;
+const USER_RSP_OFFSET: usize = 16;
+
+/// State private to one CPU, reached through the GS base
+///
+/// While the CPU runs kernel code `IA32_GS_BASE` points here and
+/// `IA32_KERNEL_GSBASE` holds the user value; the entry paths `swapgs`
+/// on every transition to or from user mode. Only the owning CPU may use
+/// its block, so fields are plain `Cell`s, except for the frame cache
+/// whose counters are read by other CPUs.
+#[repr(C)]
+#[derive(Debug)]
+pub struct PerCpu {
+    /// Points at this block, so that `%gs:0` yields its address
+    self_ptr: *const PerCpu,
+    /// Loaded into `%rsp` by `syscall_entry`
+    kernel_stack: Cell<u64>,
+    /// Scratch slot where `syscall_entry` parks the user `%rsp`
+    user_rsp: Cell<u64>,
+    cpu: usize,
+    apic: Apic,
+    timer: Cell<Option<Timer>>,
+    gdt: &'static Gdt,
+    current_thread: Cell<usize>,
+    user_space: Cell<Option<&'static AddressSpace>>,
+    frame_cache: CpuCache,
+}
+
+/// Set up the calling CPU's `PerCpu` in `frame` and point GS at it
+///
+/// `frame` must be unused and reachable through `PHYS_MAP`; it is owned by
+/// this CPU from then on. `cpu` is the kernel's index for the CPU (the BSP
+/// is 0) and `gdt` the `Gdt` loaded on it, whose `rsp0` is also used for
+/// system calls.
+pub unsafe fn init(frame: Frame,
+                   cpu: usize,
+                   apic: Apic,
+                   gdt: &'static Gdt)
+                   -> &'static PerCpu {
+    debug_assert!(mem::size_of::<PerCpu>() <= PAGE_SIZE as usize);
+    let percpu: *mut PerCpu = frame_to_slice(frame).as_mut_ptr() as *mut _;
+    ptr::write(percpu,
+               PerCpu {
+                   self_ptr: percpu,
+                   kernel_stack: Cell::new(gdt.rsp0().as_usize() as u64),
+                   user_rsp: Cell::new(0),
+                   cpu: cpu,
+                   apic: apic,
+                   timer: Cell::new(None),
+                   gdt: gdt,
+                   current_thread: Cell::new(0),
+                   user_space: Cell::new(None),
+                   frame_cache: CpuCache::new(),
+               });
+    let percpu = &*percpu;
+    frame_cache::register(cpu, &percpu.frame_cache);
+    debug_assert_eq!(percpu.offset_of(&percpu.kernel_stack),
+                     KERNEL_STACK_OFFSET);
+    debug_assert_eq!(percpu.offset_of(&percpu.user_rsp), USER_RSP_OFFSET);
+    percpu.reset_gs();
+    percpu
+}
+
+/// Returns the calling CPU's `PerCpu`
+///
+/// Must not be called before `init` has run on this CPU.
+pub fn current() -> &'static PerCpu {
+    unsafe {
+        let percpu: *const PerCpu;
+        asm!("mov %gs:0, $0" : "=r"(percpu));
+        &*percpu
+    }
+}
+
+/// Returns the calling CPU's `PerCpu`, if `init` has run on it
+///
+/// `None` is also returned while GS holds the user value, which is never a
+/// kernel address since user mode cannot write the GS base.
+pub fn try_current() -> Option<&'static PerCpu> {
+    if unsafe { rdmsr(IA32_GS_BASE) } < KERNEL_START as u64 {
+        None
+    } else {
+        Some(current())
+    }
+}
+
+impl PerCpu {
+    fn offset_of(&self, field: &Cell<u64>) -> usize {
+        let field: *const _ = field;
+        field as usize - self.self_ptr as usize
+    }
+
+    /// Point GS at this block and clear the user GS base
+    ///
+    /// For the calling CPU's block when it leaves user mode for good,
+    /// without going through the `swapgs` of an exit path.
+    pub unsafe fn reset_gs(&self) {
+        wrmsr(IA32_GS_BASE, self.self_ptr as u64);
+        wrmsr(IA32_KERNEL_GSBASE, 0);
+    }
+
+    /// Returns the kernel's index for this CPU, the BSP is 0
+    pub fn cpu(&self) -> usize {
+        self.cpu
+    }
+
+    /// Returns this CPU's local APIC
+    pub fn apic(&self) -> &Apic {
+        &self.apic
+    }
+
+    /// Returns this CPU's timer, once it is set up
+    pub fn timer(&self) -> Option<Timer> {
+        self.timer.get()
+    }
+
+    /// Record the timer driving this CPU's local APIC
+    pub fn set_timer(&self, timer: Timer) {
+        self.timer.set(Some(timer));
+    }
+
+    /// Returns this CPU's `Gdt`
+    pub fn gdt(&self) -> &'static Gdt {
+        self.gdt
+    }
+
+    /// Returns the stack used on entry from user mode
+    pub fn kernel_stack(&self) -> VAddr {
+        VAddr::from_usize(self.kernel_stack.get() as usize)
+    }
+
+    /// Set the stack used on entry from user mode
+    ///
+    /// This covers both system calls and interrupts (through the TSS), so
+    /// it is what a context switch calls with the incoming thread's stack.
+    pub fn set_kernel_stack(&self, stack: VAddr) {
+        self.kernel_stack.set(stack.as_usize() as u64);
+        self.gdt.set_rsp0(stack);
+    }
+
+    /// Returns an opaque handle to the running thread, 0 if there is none
+    pub fn current_thread(&self) -> usize {
+        self.current_thread.get()
+    }
+
+    /// Record the running thread
+    pub fn set_current_thread(&self, thread: usize) {
+        self.current_thread.set(thread);
+    }
+
+    /// Returns the `AddressSpace` owning the lower half, if any
+    pub fn user_space(&self) -> Option<&'static AddressSpace> {
+        self.user_space.get()
+    }
+
+    /// Record the `AddressSpace` owning the lower half
+    ///
+    /// This does not switch page tables, see `AddressSpace::activate`.
+    pub fn set_user_space(&self, space: Option<&'static AddressSpace>) {
+        self.user_space.set(space);
+    }
+
+    /// Returns the frames cached for this CPU
+    pub fn frame_cache(&self) -> &CpuCache {
+        &self.frame_cache
+    }
+}
@@ -0,0 +1,310 @@
+// Copyright Dan Schatzberg, 2016. This file is part of Genesis.
+
+// Genesis is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// Genesis is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+
+// You should have received a copy of the GNU Affero General Public License
+// along with Genesis.  If not, see <http://www.gnu.org/licenses/>.
+use cmdline;
+use core::ptr;
+use core::sync::atomic::{ATOMIC_USIZE_INIT, AtomicUsize, Ordering};
+use memory::*;
+use memory::address_space::AddressSpace;
+use spin;
+use x86::controlregs::cr3;
+use x86::irq;
+use super::acpi;
+use super::apic::Apic;
+use super::frame_bench;
+use super::gdt;
+use super::idt;
+use super::init;
+use super::percpu;
+use super::stack::{KernelStack, STACK_PAGES};
+use super::syscall;
+use super::timer::{self, Timer};
+
+/// Maximum number of CPUs brought up
+pub const MAX_CPUS: usize = 64;
+
+// Must match TRAMPOLINE_BASE in smp.S
+const TRAMPOLINE_ADDR: u64 = 0x8000;
+
+// Delays from the MultiProcessor Specification
+const INIT_DELAY_US: u64 = 10000;
+const STARTUP_DELAY_US: u64 = 200;
+// How long to wait for an AP to reach Rust before giving up on it
+const START_TIMEOUT_US: u64 = 100000;
+
+/// Values read by the trampoline, must match smp.S
+#[repr(C)]
+#[derive(Clone, Copy, Debug)]
+struct TrampolineParams {
+    cr3: u64,
+    stack: u64,
+    entry: u64,
+}
+
+/// How far an AP has got with its `ApBoot`
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum BootState {
+    /// Waiting for the AP with the matching APIC ID
+    Pending,
+    /// Taken by the AP, which is setting itself up
+    Claimed,
+    /// The AP finished initialization
+    Online,
+}
+
+/// What an AP needs to set itself up, handed over by the BSP
+#[derive(Clone, Copy, Debug)]
+struct ApBoot {
+    apic_id: u32,
+    state: BootState,
+    cpu: usize,
+    stack: KernelStack,
+    ist_stacks: [KernelStack; gdt::IST_STACKS],
+    tables: Frame,
+    percpu: Frame,
+}
+
+// Indexed by CPU number. An AP only uses the record with its own APIC ID,
+// so one that wakes up after the BSP gave up on it finds nothing to use.
+static AP_BOOTS: spin::RwLock<[Option<ApBoot>; MAX_CPUS]> =
+    spin::RwLock::new([None; MAX_CPUS]);
+// The BSP counts itself
+static ONLINE: AtomicUsize = ATOMIC_USIZE_INIT;
+
+/// Returns the number of CPUs that have finished initialization
+pub fn online() -> usize {
+    ONLINE.load(Ordering::SeqCst)
+}
+
+/// Start every enabled CPU described by ACPI
+///
+/// APs are started one at a time and this returns once each has either
+/// reached `ap_entry` or timed out.
+pub fn init(kernel_space: &'static AddressSpace,
+            allocator: &'static KernelAllocator,
+            apic: &Apic,
+            timer: &Timer) {
+    assert_has_not_been_called!("smp::init() function \
+                                 must only be called once");
+    ONLINE.store(1, Ordering::SeqCst);
+    if cmdline::get().flag("nosmp") {
+        info!("Not starting application processors (nosmp)");
+        return;
+    }
+    let bsp_id = apic.id();
+    let cpus = acpi::cpus();
+    if cpus.iter().all(|cpu| !cpu.enabled || cpu.apic_id == bsp_id) {
+        info!("No application processors to start");
+        return;
+    }
+    install_trampoline(kernel_space, allocator);
+
+    let mut next_cpu = 1;
+    for cpu in cpus.iter().filter(|c| c.enabled && c.apic_id != bsp_id) {
+        if next_cpu == MAX_CPUS {
+            warn!("Ignoring CPUs beyond the first {}", MAX_CPUS);
+            break;
+        }
+        let boot = ApBoot {
+            apic_id: cpu.apic_id,
+            state: BootState::Pending,
+            cpu: next_cpu,
+            stack: map_stack(kernel_space, allocator),
+            ist_stacks: [map_stack(kernel_space, allocator),
+                         map_stack(kernel_space, allocator),
+                         map_stack(kernel_space, allocator)],
+            tables: allocator.allocate_manual()
+                .expect("Could not allocate frame for AP tables"),
+            percpu: allocator.allocate_manual()
+                .expect("Could not allocate frame for per-CPU data"),
+        };
+        if start_ap(apic, timer, boot) {
+            next_cpu += 1;
+        } else {
+            // The AP may still wake up later, so its stacks and tables
+            // are leaked rather than reused
+            warn!("CPU with APIC ID {} did not start", cpu.apic_id);
+        }
+    }
+    info!("{} CPUs online", online());
+}
+
+/// Copy the trampoline below 1MB and identity map it so it survives
+/// enabling paging
+fn install_trampoline(kernel_space: &AddressSpace,
+                      allocator: &'static KernelAllocator) {
+    extern "C" {
+        static smp_trampoline: u8;
+        static smp_trampoline_end: u8;
+    }
+    let start: *const u8 = unsafe { &smp_trampoline };
+    let end: *const u8 = unsafe { &smp_trampoline_end };
+    let len = end as usize - start as usize;
+    assert!(len <= PAGE_SIZE as usize);
+    let paddr = PAddr::from_u64(TRAMPOLINE_ADDR);
+    unsafe {
+        let dest = phys_to_virt(paddr).as_usize() as *mut u8;
+        ptr::copy_nonoverlapping(start, dest, len);
+    }
+    let page = Page::down(VAddr::from_usize(TRAMPOLINE_ADDR as usize));
+    kernel_space.page_table().map(page,
+                                  Frame::down(paddr),
+                                  PT_P | PT_RW,
+                                  allocator,
+                                  |f: Frame| unsafe { frame_to_slice(f) });
+    let params = TrampolineParams {
+        cr3: unsafe { cr3() },
+        stack: 0,
+        entry: ap_entry as usize as u64,
+    };
+    unsafe { ptr::write_volatile(trampoline_params(), params) };
+}
+
+/// Returns the parameters in the installed copy of the trampoline
+fn trampoline_params() -> *mut TrampolineParams {
+    extern "C" {
+        static smp_trampoline: u8;
+        static smp_trampoline_params: u8;
+    }
+    let start: *const u8 = unsafe { &smp_trampoline };
+    let params: *const u8 = unsafe { &smp_trampoline_params };
+    let offset = params as usize - start as usize;
+    let base = phys_to_virt(PAddr::from_u64(TRAMPOLINE_ADDR)).as_usize();
+    (base + offset) as *mut TrampolineParams
+}
+
+/// Map a kernel stack in the runtime page table
+fn map_stack(kernel_space: &AddressSpace,
+             allocator: &'static KernelAllocator)
+             -> KernelStack {
+    let stack = KernelStack::reserve();
+    {
+        let mut page_table = kernel_space.page_table();
+        for i in 0..STACK_PAGES {
+            let frame = allocator.allocate_manual()
+                .expect("Could not allocate frame for stack");
+            page_table.map(stack.page(i),
+                           frame,
+                           PT_P | PT_RW | PT_G | PT_XD,
+                           allocator,
+                           |f: Frame| unsafe { frame_to_slice(f) });
+        }
+    }
+    stack
+}
+
+/// Run the INIT-SIPI-SIPI sequence on the AP `boot` is for, returns
+/// whether it started
+fn start_ap(apic: &Apic, timer: &Timer, boot: ApBoot) -> bool {
+    let (apic_id, cpu) = (boot.apic_id, boot.cpu);
+    AP_BOOTS.write()[cpu] = Some(boot);
+    let started = || {
+        AP_BOOTS.read()[cpu].map_or(false, |b| b.state == BootState::Online)
+    };
+    let params = trampoline_params();
+    unsafe {
+        let mut p = ptr::read_volatile(params);
+        p.stack = boot.stack.top().as_usize() as u64;
+        ptr::write_volatile(params, p);
+    }
+    let page = (TRAMPOLINE_ADDR >> PAGE_SHIFT) as u8;
+    apic.send_init(apic_id);
+    timer.spin_us(INIT_DELAY_US);
+    for _ in 0..2 {
+        apic.send_startup(apic_id, page);
+        timer.spin_us(STARTUP_DELAY_US);
+        if started() {
+            break;
+        }
+    }
+    let mut waited = 0;
+    while !started() && waited < START_TIMEOUT_US {
+        timer.spin_us(100);
+        waited += 100;
+    }
+    if !started() && !withdraw(cpu) {
+        // The AP took its record just in time, so it is coming up
+        while !started() {
+            timer.spin_us(100);
+        }
+    }
+    started()
+}
+
+/// Remove the record for `cpu` unless its AP already took it
+///
+/// Returns whether the record was removed.
+fn withdraw(cpu: usize) -> bool {
+    let mut boots = AP_BOOTS.write();
+    match boots[cpu] {
+        Some(ref b) if b.state != BootState::Pending => return false,
+        _ => {}
+    }
+    boots[cpu] = None;
+    true
+}
+
+/// Take the record the BSP left for the AP with `apic_id`
+fn claim(apic_id: u32) -> Option<ApBoot> {
+    let mut boots = AP_BOOTS.write();
+    boots.iter_mut()
+        .filter_map(|b| b.as_mut())
+        .find(|b| b.apic_id == apic_id && b.state == BootState::Pending)
+        .map(|b| {
+            b.state = BootState::Claimed;
+            *b
+        })
+}
+
+/// First Rust code run by an AP, called from the trampoline on its stack
+extern "C" fn ap_entry() -> ! {
+    let local_apic = unsafe { Apic::init_ap() };
+    let boot = match claim(local_apic.id()) {
+        Some(boot) => boot,
+        None => {
+            // Started too late, the BSP gave up on this CPU
+            loop {
+                unsafe { asm!("cli; hlt" :::: "volatile") };
+            }
+        }
+    };
+    let ist_tops = [boot.ist_stacks[0].top(),
+                    boot.ist_stacks[1].top(),
+                    boot.ist_stacks[2].top()];
+    let gdt = unsafe {
+        let gdt = gdt::Gdt::new(boot.tables, boot.stack.top(), &ist_tops);
+        gdt.load();
+        gdt
+    };
+    idt::load();
+    init::enable_cpu_features();
+    syscall::init();
+    let percpu = unsafe {
+        percpu::init(boot.percpu, boot.cpu, local_apic, gdt)
+    };
+    let apic = percpu.apic();
+    percpu.set_timer(timer::init_ap(apic));
+    info!("CPU {} online, APIC ID {}", boot.cpu, apic.id());
+    ONLINE.fetch_add(1, Ordering::SeqCst);
+    // The BSP moves on to the next AP from here
+    if let Some(ref mut b) = AP_BOOTS.write()[boot.cpu] {
+        b.state = BootState::Online;
+    }
+
+    frame_bench::ap_run(boot.cpu);
+    unsafe { irq::enable() };
+    loop {
+        unsafe { asm!("hlt" :::: "volatile") };
+    }
+}
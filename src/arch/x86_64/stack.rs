@@ -0,0 +1,73 @@
+// Copyright Dan Schatzberg, 2016. This file is part of Genesis.
+
+// Genesis is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// Genesis is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+
+// You should have received a copy of the GNU Affero General Public License
+// along with Genesis.  If not, see <http://www.gnu.org/licenses/>.
+use core::sync::atomic::{ATOMIC_USIZE_INIT, AtomicUsize, Ordering};
+use memory::*;
+
+/// Number of mapped pages in a kernel stack
+pub const STACK_PAGES: usize = 2;
+
+static NSTACKS: AtomicUsize = ATOMIC_USIZE_INIT;
+
+/// Virtual space for a kernel stack with an unmapped guard page below it
+///
+/// Stacks are laid out downwards from the start of the kernel image.
+#[derive(Clone, Copy, Debug)]
+pub struct KernelStack {
+    guard: Page,
+}
+
+fn kbegin_page() -> Page {
+    extern "C" {
+        static kbegin: u8;
+    }
+    let ptr: *const _ = &kbegin;
+    Page::down(VAddr::from_usize(ptr as usize))
+}
+
+/// Returns whether `page` is the guard page of a reserved stack
+///
+/// Stack guards are found from the layout rather than registered as
+/// `Region`s, so the number of stacks is not bounded by the kernel
+/// `AddressSpace`.
+pub fn is_guard(page: Page) -> bool {
+    let kbegin_page = kbegin_page();
+    if page >= kbegin_page {
+        return false;
+    }
+    let distance = kbegin_page - page;
+    let index = distance / (STACK_PAGES + 1);
+    distance % (STACK_PAGES + 1) == 0 &&
+    index <= NSTACKS.load(Ordering::Relaxed)
+}
+
+impl KernelStack {
+    /// Reserve virtual space for a new stack; the caller maps its pages
+    pub fn reserve() -> KernelStack {
+        let index = NSTACKS.fetch_add(1, Ordering::Relaxed);
+        let top = kbegin_page() - index * (STACK_PAGES + 1);
+        KernelStack { guard: top - (STACK_PAGES + 1) }
+    }
+
+    /// The `i`th page of the stack, counting up from the bottom
+    pub fn page(&self, i: usize) -> Page {
+        assert!(i < STACK_PAGES);
+        self.guard + 1 + i
+    }
+
+    /// The initial stack pointer
+    pub fn top(&self) -> VAddr {
+        (self.guard + 1 + STACK_PAGES).start_address()
+    }
+}
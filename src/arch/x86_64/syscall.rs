@@ -12,16 +12,63 @@
 
 // You should have received a copy of the GNU Affero General Public License
 // along with Genesis.  If not, see <http://www.gnu.org/licenses/>.
+use syscall;
+use super::user;
 use x86::msr::*;
 use x86::segmentation::*;
 use x86::rflags::*;
 
+/// User registers saved by `syscall_entry`, in stack order
+///
+/// `rax` holds the system call number on entry and the return value on
+/// exit. `rip` and `rflags` are what the CPU left in `%rcx` and `%r11`.
+#[derive(Clone, Copy, Debug)]
+#[repr(C)]
+pub struct SyscallFrame {
+    pub r15: u64,
+    pub r14: u64,
+    pub r13: u64,
+    pub r12: u64,
+    pub rbp: u64,
+    pub rbx: u64,
+    pub r9: u64,
+    pub r8: u64,
+    pub r10: u64,
+    pub rdx: u64,
+    pub rsi: u64,
+    pub rdi: u64,
+    pub rax: u64,
+    pub rip: u64,
+    pub rflags: u64,
+    pub rsp: u64,
+}
+
+impl SyscallFrame {
+    /// Returns the system call number
+    pub fn number(&self) -> u64 {
+        self.rax
+    }
+
+    /// Returns the arguments, in System V order with `%r10` for `%rcx`
+    pub fn args(&self) -> [u64; 6] {
+        [self.rdi, self.rsi, self.rdx, self.r10, self.r8, self.r9]
+    }
+
+    /// Set the value returned to user mode in `%rax`
+    pub fn set_return(&mut self, value: u64) {
+        self.rax = value;
+    }
+}
+
+/// Program the `syscall` MSRs on the calling CPU
 pub fn init() {
-    let call_cs = (SegmentSelector::new(0x8) | RPL_0 | TI_GDT).bits() as u64;
-    let ret_cs = (SegmentSelector::new(0x10) | RPL_3 | TI_GDT).bits() as u64;
+    // syscall loads CS from STAR[47:32] and SS from the next descriptor.
+    // sysretq loads SS from STAR[63:48] + 8 and CS from STAR[63:48] + 16,
+    // which are the user data and code descriptors.
+    let call_cs = (SegmentSelector::new(1) | RPL_0 | TI_GDT).bits() as u64;
+    let ret_cs = (SegmentSelector::new(2) | RPL_3 | TI_GDT).bits() as u64;
     unsafe {
-        let star = rdmsr(IA32_STAR);
-        wrmsr(IA32_STAR, star | call_cs << 32 | ret_cs << 48);
+        wrmsr(IA32_STAR, call_cs << 32 | ret_cs << 48);
     }
     extern "C" {
         static syscall_entry: u8;
@@ -34,3 +81,25 @@ pub fn init() {
         wrmsr(IA32_EFER, efer | 0x1);
     }
 }
+
+/// Returns whether `addr` is a canonical (sign extended 48 bit) address
+fn is_canonical(addr: u64) -> bool {
+    ((addr << 16) as i64 >> 16) as u64 == addr
+}
+
+/// Rust entry point for system calls, called by `syscall_entry`
+///
+/// A `syscall` in the last bytes of the lower half would return to a
+/// non-canonical address, which faults in ring 0 with the user's GS base,
+/// so such programs are killed instead.
+#[no_mangle]
+pub extern "C" fn syscall_handler(frame: &mut SyscallFrame) {
+    if !is_canonical(frame.rip) {
+        user::syscall_fault(frame,
+                            format_args!("system call returning to \
+                                          non-canonical {:#X}",
+                                         frame.rip));
+    }
+    let ret = syscall::dispatch(frame.number(), frame.args());
+    frame.set_return(ret);
+}
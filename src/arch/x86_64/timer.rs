@@ -0,0 +1,238 @@
+// Copyright Dan Schatzberg, 2016. This file is part of Genesis.
+
+// Genesis is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// Genesis is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+
+// You should have received a copy of the GNU Affero General Public License
+// along with Genesis.  If not, see <http://www.gnu.org/licenses/>.
+use core::cmp;
+use core::sync::atomic::{ATOMIC_USIZE_INIT, AtomicUsize, Ordering};
+use spin;
+use x86::io;
+use x86::time::rdtsc;
+use super::apic::{Apic, TimerDivide, TimerMode};
+use super::idt::{self, Priority, TrapFrame};
+use super::percpu;
+
+/// Vector the local APIC timer is delivered on
+pub const TIMER_VECTOR: u8 = 32;
+
+// The PIT runs at 1.193182 MHz regardless of the CPU
+const PIT_FREQUENCY: u32 = 1193182;
+const PIT_CHANNEL2: u16 = 0x42;
+const PIT_COMMAND: u16 = 0x43;
+// Channel 2, lobyte/hibyte access, mode 0 (interrupt on terminal count)
+const PIT_CHANNEL2_ONESHOT: u8 = 0xb0;
+// Gate and output of PIT channel 2 are routed through port 0x61
+const PIT_GATE_PORT: u16 = 0x61;
+const PIT_GATE: u8 = 1 << 0;
+const PIT_SPEAKER: u8 = 1 << 1;
+const PIT_OUT: u8 = 1 << 5;
+
+const CALIBRATION_MS: u32 = 10;
+const DIVIDE: TimerDivide = TimerDivide::By16;
+
+// CPUID.01H:ECX
+const CPUID_TSC_DEADLINE: u32 = 1 << 24;
+
+/// A CPU's local APIC timer, calibrated against the PIT
+///
+/// Each CPU keeps its own in its `PerCpu`, see `get`. They all use the
+/// rates measured on the BSP.
+#[derive(Clone, Copy, Debug)]
+pub struct Timer {
+    apic: &'static Apic,
+    /// APIC timer ticks (after `DIVIDE`) per millisecond
+    ticks_per_ms: u32,
+    /// Time stamp counter ticks per millisecond
+    tsc_per_ms: u64,
+    tsc_deadline: bool,
+}
+
+// The BSP's timer, which the APs copy their rates from
+static CALIBRATED: spin::Once<Timer> = spin::Once::new();
+static TICKS: AtomicUsize = ATOMIC_USIZE_INIT;
+static CALLBACK: spin::RwLock<Option<fn()>> = spin::RwLock::new(None);
+
+/// Calibrate the BSP's local APIC timer and install its interrupt handler
+pub fn init(apic: &'static Apic) -> Timer {
+    assert_has_not_been_called!("timer::init() function \
+                                 must only be called once");
+    let (ticks_per_ms, tsc_per_ms) = calibrate(apic);
+    let tsc_deadline = cpuid!(1).ecx & CPUID_TSC_DEADLINE != 0;
+    info!("APIC timer: {} ticks/ms, TSC: {} ticks/ms{}",
+          ticks_per_ms,
+          tsc_per_ms,
+          if tsc_deadline { ", TSC-deadline supported" } else { "" });
+    idt::register_apic(TIMER_VECTOR, tick, "timer", Priority::System)
+        .expect("Could not register the timer interrupt");
+    *CALIBRATED.call_once(|| {
+        Timer {
+            apic: apic,
+            ticks_per_ms: ticks_per_ms,
+            tsc_per_ms: tsc_per_ms,
+            tsc_deadline: tsc_deadline,
+        }
+    })
+}
+
+/// Returns the timer of an AP's local APIC `apic`
+///
+/// `init` must have been called on the BSP.
+pub fn init_ap(apic: &'static Apic) -> Timer {
+    let bsp = CALIBRATED.try().expect("AP started before the BSP's timer");
+    Timer { apic: apic, ..*bsp }
+}
+
+/// Returns the calling CPU's timer, if it has been initialized
+pub fn get() -> Option<Timer> {
+    percpu::try_current().and_then(|percpu| percpu.timer())
+}
+
+/// Returns the number of timer interrupts received so far
+pub fn ticks() -> usize {
+    TICKS.load(Ordering::Relaxed)
+}
+
+/// Call `f` on every timer interrupt
+pub fn set_callback(f: fn()) {
+    idt::without_interrupts(|| *CALLBACK.write() = Some(f));
+}
+
+/// Measure APIC timer and TSC ticks across `CALIBRATION_MS` of the PIT
+fn calibrate(apic: &Apic) -> (u32, u64) {
+    let pit_count = PIT_FREQUENCY / (1000 / CALIBRATION_MS);
+    unsafe {
+        // Disable the speaker and hold the gate low while programming
+        let gate = io::inb(PIT_GATE_PORT) & !(PIT_SPEAKER | PIT_GATE);
+        io::outb(PIT_GATE_PORT, gate);
+        io::outb(PIT_COMMAND, PIT_CHANNEL2_ONESHOT);
+        io::outb(PIT_CHANNEL2, pit_count as u8);
+        io::outb(PIT_CHANNEL2, (pit_count >> 8) as u8);
+
+        apic.set_timer(TimerMode::OneShot,
+                       TIMER_VECTOR,
+                       true,
+                       DIVIDE,
+                       u32::max_value());
+        let tsc_start = rdtsc();
+        // Raising the gate starts the countdown
+        io::outb(PIT_GATE_PORT, gate | PIT_GATE);
+        while io::inb(PIT_GATE_PORT) & PIT_OUT == 0 {}
+        let tsc_end = rdtsc();
+        let elapsed = u32::max_value() - apic.timer_count();
+        apic.set_timer(TimerMode::OneShot, TIMER_VECTOR, true, DIVIDE, 0);
+        io::outb(PIT_GATE_PORT, gate);
+
+        (elapsed / CALIBRATION_MS,
+         (tsc_end - tsc_start) / CALIBRATION_MS as u64)
+    }
+}
+
+fn tick(_frame: &mut TrapFrame) {
+    TICKS.fetch_add(1, Ordering::Relaxed);
+    let callback = *CALLBACK.read();
+    if let Some(f) = callback {
+        f();
+    }
+}
+
+/// Returns `value * mul / div` without overflowing in between, saturated
+fn mul_div(value: u64, mul: u64, div: u64) -> u64 {
+    let whole = (value / div).saturating_mul(mul);
+    whole.saturating_add(value % div * mul / div)
+}
+
+impl Timer {
+    fn us_to_ticks(&self, us: u64) -> u32 {
+        let ticks = mul_div(us, self.ticks_per_ms as u64, 1000);
+        cmp::min(cmp::max(ticks, 1), u32::max_value() as u64) as u32
+    }
+
+    /// Interrupt every `period_us` microseconds
+    pub fn periodic(&self, period_us: u64) {
+        self.apic.set_timer(TimerMode::Periodic,
+                            TIMER_VECTOR,
+                            false,
+                            DIVIDE,
+                            self.us_to_ticks(period_us));
+    }
+
+    /// Interrupt once, after `delay_us` microseconds
+    pub fn one_shot(&self, delay_us: u64) {
+        self.apic.set_timer(TimerMode::OneShot,
+                            TIMER_VECTOR,
+                            false,
+                            DIVIDE,
+                            self.us_to_ticks(delay_us));
+    }
+
+    /// Interrupt once the time stamp counter reaches `tsc`
+    ///
+    /// Uses TSC-deadline mode when available and falls back to a one-shot
+    /// countdown otherwise.
+    pub fn deadline(&self, tsc: u64) {
+        if self.tsc_deadline {
+            self.apic.set_timer(TimerMode::TscDeadline,
+                                TIMER_VECTOR,
+                                false,
+                                DIVIDE,
+                                0);
+            self.apic.set_tsc_deadline(tsc);
+        } else {
+            let now = unsafe { rdtsc() };
+            let delay_us = mul_div(tsc.saturating_sub(now),
+                                   1000,
+                                   self.tsc_per_ms);
+            self.one_shot(delay_us);
+        }
+    }
+
+    /// Stop the timer
+    pub fn stop(&self) {
+        self.apic.set_timer(TimerMode::OneShot, TIMER_VECTOR, true, DIVIDE, 0);
+    }
+
+    /// Returns whether TSC-deadline mode is available
+    pub fn has_tsc_deadline(&self) -> bool {
+        self.tsc_deadline
+    }
+
+    /// Convert microseconds to time stamp counter ticks
+    pub fn us_to_tsc(&self, us: u64) -> u64 {
+        mul_div(us, self.tsc_per_ms, 1000)
+    }
+
+    /// Returns the time stamp counter
+    pub fn now(&self) -> u64 {
+        unsafe { rdtsc() }
+    }
+
+    /// Busy wait for `us` microseconds
+    pub fn spin_us(&self, us: u64) {
+        let end = self.now().saturating_add(self.us_to_tsc(us));
+        while self.now() < end {
+            unsafe { asm!("pause" :::: "volatile") };
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::mul_div;
+
+    #[test]
+    fn test_mul_div() {
+        assert_eq!(mul_div(2500, 3, 1000), 7);
+        assert_eq!(mul_div(u64::max_value() / 10, 1000, 3_000_000),
+                   u64::max_value() / 30_000);
+        assert_eq!(mul_div(u64::max_value(), 1000, 1), u64::max_value());
+    }
+}
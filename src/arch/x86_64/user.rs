@@ -0,0 +1,244 @@
+// Copyright Dan Schatzberg, 2016. This file is part of Genesis.
+
+// Genesis is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// Genesis is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+
+// You should have received a copy of the GNU Affero General Public License
+// along with Genesis.  If not, see <http://www.gnu.org/licenses/>.
+use core::cmp;
+use core::fmt;
+use core::ptr;
+use memory::*;
+use memory::address_space::{self, AddressSpace, Region, RegionError,
+                            RegionKind};
+use memory::frame_table::{self, FrameUsage};
+use super::elf::{Elf, ElfError, Segment};
+use super::gdt::{KERNEL_CS, KERNEL_DS};
+use super::idt::TrapFrame;
+use super::percpu::{self, PerCpu};
+use super::syscall::SyscallFrame;
+
+/// Where the built-in program is loaded
+pub const USER_CODE_BASE: usize = 0x40_0000;
+/// Top of the initial user stack
+pub const USER_STACK_TOP: usize = 0x7FFF_FFFF_F000;
+/// Maximum size of the initial user stack, in pages
+pub const USER_STACK_PAGES: usize = 16;
+
+// IF and the always-set bit 1
+const RFLAGS_DEFAULT: u64 = 0x202;
+
+/// Errors returned by `load_elf`
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LoadError {
+    /// The image is not a valid executable
+    Elf(ElfError),
+    /// Two segments share a page
+    Overlap,
+    /// Frames or region slots ran out
+    OutOfMemory,
+}
+
+impl From<RegionError> for LoadError {
+    fn from(e: RegionError) -> LoadError {
+        match e {
+            RegionError::Overlap => LoadError::Overlap,
+            RegionError::Full => LoadError::OutOfMemory,
+        }
+    }
+}
+
+/// Add the initial stack to `space` and return its top
+///
+/// Stack pages are allocated on first touch and the page below the stack
+/// is a guard.
+pub fn add_stack(space: &AddressSpace) -> Result<VAddr, RegionError> {
+    let top = Page::down(VAddr::from_usize(USER_STACK_TOP));
+    let bottom = top - USER_STACK_PAGES;
+    try!(space.add_region(Region::new(bottom,
+                                      top,
+                                      RegionKind::Lazy,
+                                      PT_US | PT_RW | PT_XD)));
+    try!(space.add_region(Region::guard(bottom - 1, bottom)));
+    Ok(top.start_address())
+}
+
+/// Create a user address space holding the ELF executable `image`
+///
+/// Returns the address space and the entry point. Pages holding file data
+/// are copied in now and the rest of each segment is zero filled on first
+/// touch. Segments must not share pages, which keeps writable and
+/// executable mappings apart. On failure the partly built address space is
+/// leaked.
+pub fn load_elf(image: &[u8],
+                allocator: &'static KernelAllocator)
+                -> Result<(&'static AddressSpace, VAddr), LoadError> {
+    let elf = try!(Elf::parse(image).map_err(LoadError::Elf));
+    let space = try!(address_space::new_user(allocator)
+        .ok_or(LoadError::OutOfMemory));
+    for segment in elf.segments() {
+        try!(load_segment(space, &segment, allocator));
+    }
+    Ok((space, elf.entry()))
+}
+
+fn load_segment(space: &AddressSpace,
+                segment: &Segment,
+                allocator: &'static KernelAllocator)
+                -> Result<(), LoadError> {
+    let mut flags = PT_US;
+    if segment.writable() {
+        flags.insert(PT_RW);
+    }
+    if !segment.executable() {
+        flags.insert(PT_XD);
+    }
+    let vaddr = segment.vaddr().as_usize();
+    let data = segment.data();
+    let start = Page::down(segment.vaddr());
+    let end = Page::up(VAddr::from_usize(vaddr + segment.memsz()));
+    try!(space.add_region(Region::new(start, end, RegionKind::Lazy, flags)));
+
+    let data_end = Page::up(VAddr::from_usize(vaddr + data.len()));
+    for i in 0..(data_end - start) {
+        let page = start + i;
+        let frame = try!(allocator.allocate_manual()
+            .ok_or(LoadError::OutOfMemory));
+        frame_table::set_usage(frame, FrameUsage::User);
+        let dest = unsafe { frame_to_slice(frame) };
+        for b in dest.iter_mut() {
+            *b = 0;
+        }
+        // Copy the part of the file data that falls in this page
+        let page_addr = page.start_address().as_usize();
+        let from = cmp::max(page_addr, vaddr);
+        let to = cmp::min(page_addr + PAGE_SIZE as usize, vaddr + data.len());
+        dest[from - page_addr..to - page_addr]
+            .copy_from_slice(&data[from - vaddr..to - vaddr]);
+        space.page_table().map(page,
+                               frame,
+                               flags | PT_P,
+                               allocator,
+                               |f: Frame| unsafe { frame_to_slice(f) });
+    }
+    Ok(())
+}
+
+/// Create a user address space holding the built-in program
+///
+/// Returns the address space and the program's entry point. The program
+/// prints a greeting and then yields forever.
+pub fn load_builtin(allocator: &'static KernelAllocator)
+                    -> (&'static AddressSpace, VAddr) {
+    extern "C" {
+        static user_hello_start: u8;
+        static user_hello_end: u8;
+    }
+    let start: *const u8 = unsafe { &user_hello_start };
+    let end: *const u8 = unsafe { &user_hello_end };
+    let len = end as usize - start as usize;
+    assert!(len <= PAGE_SIZE as usize);
+    let space = address_space::new_user(allocator)
+        .expect("Could not allocate a user address space");
+    let frame = allocator.allocate_manual()
+        .expect("Could not allocate frame for user code");
+    frame_table::set_usage(frame, FrameUsage::User);
+    unsafe {
+        let dest = frame_to_slice(frame);
+        for b in dest.iter_mut() {
+            *b = 0;
+        }
+        ptr::copy_nonoverlapping(start, dest.as_mut_ptr(), len);
+    }
+    let entry = VAddr::from_usize(USER_CODE_BASE);
+    space.page_table().map(Page::down(entry),
+                           frame,
+                           PT_P | PT_US,
+                           allocator,
+                           |f: Frame| unsafe { frame_to_slice(f) });
+    (space, entry)
+}
+
+/// Switch to `space` and run user code at `entry` with the stack `stack`
+///
+/// Traps from user mode arrive at the top of `PerCpu::kernel_stack`, so
+/// the caller must not be running on anything it expects to keep there.
+pub unsafe fn run(space: &'static AddressSpace,
+                  entry: VAddr,
+                  stack: VAddr)
+                  -> ! {
+    extern "C" {
+        fn enter_user(rip: u64, rsp: u64) -> !;
+    }
+    percpu::current().set_user_space(Some(space));
+    space.activate();
+    enter_user(entry.as_usize() as u64, stack.as_usize() as u64)
+}
+
+/// Returns the `AddressSpace` of the user program running on this CPU
+pub fn current_space() -> Option<&'static AddressSpace> {
+    percpu::try_current().and_then(|percpu| percpu.user_space())
+}
+
+/// Kill the user program that took the trap in `frame`
+///
+/// The calling CPU switches back to the kernel address space and `frame`
+/// is rewritten so that returning from the trap lands in the idle loop.
+/// The user address space is leaked.
+pub fn fault(frame: &mut TrapFrame, reason: fmt::Arguments) {
+    debug_assert!(frame.from_user());
+    error!("Killing user program: {}\n{}", reason, frame);
+    let percpu = percpu::current();
+    kill(percpu);
+    frame.rip = idle as usize as u64;
+    frame.cs = KERNEL_CS as u64;
+    frame.rflags = RFLAGS_DEFAULT;
+    frame.rsp = percpu.kernel_stack().as_usize() as u64;
+    frame.ss = KERNEL_DS as u64;
+}
+
+/// Kill the user program that made the system call in `frame`
+///
+/// As with `fault`, the calling CPU continues in the idle loop, here on a
+/// fresh copy of its kernel stack.
+pub fn syscall_fault(frame: &SyscallFrame, reason: fmt::Arguments) -> ! {
+    error!("Killing user program: {}\n{:?}", reason, frame);
+    let percpu = percpu::current();
+    kill(percpu);
+    unsafe {
+        asm!("mov $0, %rsp
+              sti
+              jmp *$1"
+             :
+             : "r"(percpu.kernel_stack().as_usize()), "r"(idle as usize)
+             : "memory"
+             : "volatile");
+    }
+    unreachable!()
+}
+
+/// Forget the user program running on this CPU and return to the kernel
+/// address space
+///
+/// The caller resumes in kernel mode, so the exit path will not `swapgs`
+/// and the GS base MSRs are set for kernel mode here.
+fn kill(percpu: &PerCpu) {
+    percpu.set_user_space(None);
+    unsafe { percpu.reset_gs() };
+    if let Some(kernel) = address_space::kernel_space() {
+        unsafe { kernel.activate() };
+    }
+}
+
+extern "C" fn idle() -> ! {
+    loop {
+        unsafe { asm!("hlt" :::: "volatile") };
+    }
+}
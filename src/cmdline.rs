@@ -0,0 +1,227 @@
+// Copyright Dan Schatzberg, 2016. This file is part of Genesis.
+
+// Genesis is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// Genesis is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+
+// You should have received a copy of the GNU Affero General Public License
+// along with Genesis.  If not, see <http://www.gnu.org/licenses/>.
+use core::cmp;
+use core::fmt;
+use core::str::{self, FromStr};
+use spin;
+
+/// Longest command line kept, the rest is dropped
+pub const CMDLINE_LEN: usize = 256;
+
+/// The kernel command line
+///
+/// Options are separated by spaces or tabs and are either flags (`nosmp`)
+/// or `key=value` pairs (`loglevel=info`). There is no quoting. When a key
+/// is repeated the last value wins.
+pub struct CommandLine {
+    buf: [u8; CMDLINE_LEN],
+    len: usize,
+    truncated: bool,
+}
+
+static CMDLINE: spin::Once<CommandLine> = spin::Once::new();
+static EMPTY: CommandLine = CommandLine {
+    buf: [0; CMDLINE_LEN],
+    len: 0,
+    truncated: false,
+};
+
+/// Copy `s` as the kernel command line
+///
+/// This runs before the console is up, so it must not log.
+pub fn init(s: &str) {
+    assert_has_not_been_called!("cmdline::init() function \
+                                 must only be called once");
+    CMDLINE.call_once(|| CommandLine::new(s));
+}
+
+/// Returns the kernel command line, empty if `init` has not been called
+pub fn get() -> &'static CommandLine {
+    CMDLINE.try().unwrap_or(&EMPTY)
+}
+
+fn is_separator(c: char) -> bool {
+    c == ' ' || c == '\t'
+}
+
+/// Parse a decimal or `0x` prefixed hexadecimal number
+fn parse_number(s: &str) -> Option<u64> {
+    if s.starts_with("0x") || s.starts_with("0X") {
+        u64::from_str_radix(&s[2..], 16).ok()
+    } else {
+        u64::from_str_radix(s, 10).ok()
+    }
+}
+
+impl CommandLine {
+    /// Construct a `CommandLine` from a copy of `s`
+    pub fn new(s: &str) -> CommandLine {
+        let mut len = cmp::min(s.len(), CMDLINE_LEN);
+        while !s.is_char_boundary(len) {
+            len -= 1;
+        }
+        let mut cmdline = CommandLine {
+            buf: [0; CMDLINE_LEN],
+            len: len,
+            truncated: len < s.len(),
+        };
+        cmdline.buf[..len].copy_from_slice(&s.as_bytes()[..len]);
+        cmdline
+    }
+
+    /// Returns the command line as given, less anything past `CMDLINE_LEN`
+    pub fn as_str(&self) -> &str {
+        // new() only cuts on character boundaries
+        str::from_utf8(&self.buf[..self.len]).unwrap_or("")
+    }
+
+    /// Returns whether the command line was longer than `CMDLINE_LEN`
+    pub fn truncated(&self) -> bool {
+        self.truncated
+    }
+
+    /// Returns every option in order, with its value if it has one
+    pub fn options(&self) -> Options {
+        Options { rest: self.as_str() }
+    }
+
+    /// Returns the value of `key`, if it was given one
+    pub fn value(&self, key: &str) -> Option<&str> {
+        self.options()
+            .filter(|&(k, _)| k == key)
+            .last()
+            .and_then(|(_, v)| v)
+    }
+
+    /// Returns whether `key` was given as a flag
+    pub fn flag(&self, key: &str) -> bool {
+        self.options().any(|(k, v)| k == key && v.is_none())
+    }
+
+    /// Returns the value of `key` parsed as a `T`
+    pub fn parse<T: FromStr>(&self, key: &str) -> Option<T> {
+        self.value(key).and_then(|v| v.parse().ok())
+    }
+
+    /// Returns the value of `key` as a decimal or `0x` prefixed number
+    pub fn number(&self, key: &str) -> Option<u64> {
+        self.value(key).and_then(parse_number)
+    }
+
+    /// Returns the value of `key` as a size in bytes
+    ///
+    /// The number may have a `K`, `M` or `G` suffix.
+    pub fn size(&self, key: &str) -> Option<u64> {
+        let value = match self.value(key) {
+            Some(value) if !value.is_empty() => value,
+            _ => return None,
+        };
+        let (digits, shift) = match value.as_bytes()[value.len() - 1] {
+            b'K' | b'k' => (&value[..value.len() - 1], 10),
+            b'M' | b'm' => (&value[..value.len() - 1], 20),
+            b'G' | b'g' => (&value[..value.len() - 1], 30),
+            _ => (value, 0),
+        };
+        parse_number(digits).and_then(|n| n.checked_mul(1 << shift))
+    }
+}
+
+impl fmt::Debug for CommandLine {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "CommandLine({:?})", self.as_str())
+    }
+}
+
+impl fmt::Display for CommandLine {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// Iterator over the options of a `CommandLine`
+#[derive(Clone, Copy, Debug)]
+pub struct Options<'a> {
+    rest: &'a str,
+}
+
+impl<'a> Iterator for Options<'a> {
+    type Item = (&'a str, Option<&'a str>);
+
+    fn next(&mut self) -> Option<(&'a str, Option<&'a str>)> {
+        let rest = self.rest.trim_left_matches(is_separator);
+        if rest.is_empty() {
+            self.rest = rest;
+            return None;
+        }
+        let end = rest.find(is_separator).unwrap_or(rest.len());
+        let (option, rest) = rest.split_at(end);
+        self.rest = rest;
+        Some(match option.find('=') {
+            Some(i) => (&option[..i], Some(&option[i + 1..])),
+            None => (option, None),
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{CMDLINE_LEN, CommandLine};
+    use std::iter;
+    use std::string::String;
+    use std::vec::Vec;
+
+    #[test]
+    fn test_options() {
+        let cmdline = CommandLine::new("/boot/genesis  nosmp\tloglevel=info \
+                                        init=/boot/init empty=");
+        let options: Vec<_> = cmdline.options().collect();
+        assert_eq!(options,
+                   vec![("/boot/genesis", None),
+                        ("nosmp", None),
+                        ("loglevel", Some("info")),
+                        ("init", Some("/boot/init")),
+                        ("empty", Some(""))]);
+        assert!(cmdline.flag("nosmp"));
+        assert!(!cmdline.flag("loglevel"));
+        assert_eq!(cmdline.value("init"), Some("/boot/init"));
+        assert_eq!(cmdline.value("nosmp"), None);
+        assert_eq!(cmdline.value("missing"), None);
+    }
+
+    #[test]
+    fn test_last_wins() {
+        let cmdline = CommandLine::new("baud=9600 baud=115200");
+        assert_eq!(cmdline.parse::<u32>("baud"), Some(115200));
+    }
+
+    #[test]
+    fn test_numbers() {
+        let cmdline = CommandLine::new("serial=0x2f8 mem=512M big=4g \
+                                        bad=12x small=100");
+        assert_eq!(cmdline.number("serial"), Some(0x2f8));
+        assert_eq!(cmdline.size("mem"), Some(512 << 20));
+        assert_eq!(cmdline.size("big"), Some(4 << 30));
+        assert_eq!(cmdline.size("small"), Some(100));
+        assert_eq!(cmdline.size("bad"), None);
+    }
+
+    #[test]
+    fn test_truncated() {
+        let long: String = iter::repeat('x').take(CMDLINE_LEN + 1).collect();
+        let cmdline = CommandLine::new(&long);
+        assert!(cmdline.truncated());
+        assert_eq!(cmdline.as_str().len(), CMDLINE_LEN);
+    }
+}
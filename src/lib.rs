@@ -12,7 +12,8 @@
 
 // You should have received a copy of the GNU Affero General Public License
 // along with Genesis.  If not, see <http://www.gnu.org/licenses/>.
-#![feature(asm, const_fn, drop_types_in_const, lang_items, plugin)]
+#![feature(alloc, asm, const_fn, drop_types_in_const, lang_items, oom,
+           plugin)]
 #![feature(type_ascription, unique)]
 #![no_std]
 
@@ -27,9 +28,13 @@ extern crate core as std;
 #[cfg(test)]
 #[macro_use]
 extern crate std;
+extern crate alloc;
 #[macro_use]
 extern crate bitflags;
 extern crate fixedvec;
+extern crate genesis_abi as abi;
+#[cfg(not(test))]
+extern crate genesis_allocator;
 #[macro_use]
 extern crate lazy_static;
 #[macro_use]
@@ -44,12 +49,17 @@ extern crate x86;
 
 /// Architecture-specific interfaces
 mod arch;
+/// Kernel command line options
+mod cmdline;
 mod console {
     pub use arch::serial::*;
 }
 mod logimpl;
 mod memory;
+/// System call dispatch
+mod syscall;
 mod unwind;
 
 pub use arch::arch_init;
 pub use arch::interrupt_handler;
+pub use arch::syscall_handler;
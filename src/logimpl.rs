@@ -22,14 +22,15 @@ pub struct Logger {
 }
 
 impl Logger {
-    pub fn init() {
+    /// Start logging records up to `level`
+    pub fn init(level: log::LogLevelFilter) {
         assert_has_not_been_called!("Logger::init() function \
                                     must only be called once");
         unsafe {
             let _ = log::set_logger_raw(|max_log_level| {
                 static LOGGER: Logger =
                     Logger { writer: spin::Mutex::new(LogWriter) };
-                max_log_level.set(log::LogLevelFilter::Debug);
+                max_log_level.set(level);
                 &LOGGER
             });
         }
@@ -38,21 +39,30 @@ impl Logger {
 
 impl log::Log for Logger {
     fn enabled(&self, metadata: &log::LogMetadata) -> bool {
-        metadata.level() <= log::LogLevel::Debug
+        metadata.level() <= log::max_log_level()
     }
 
     fn log(&self, record: &log::LogRecord) {
-        if self.enabled(record.metadata()) {
-            let mut w = self.writer.lock();
-            let _ = writeln!(w,
-                             "{}:{}: {}",
-                             record.level(),
-                             record.location().module_path(),
-                             record.args());
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+        // A CPU stopped by a panic may have held the lock
+        if ::arch::other_cpus_stopped() {
+            write_record(&mut LogWriter, record);
+        } else {
+            write_record(&mut *self.writer.lock(), record);
         }
     }
 }
 
+fn write_record(w: &mut LogWriter, record: &log::LogRecord) {
+    let _ = writeln!(w,
+                     "{}:{}: {}",
+                     record.level(),
+                     record.location().module_path(),
+                     record.args());
+}
+
 struct LogWriter;
 
 impl Write for LogWriter {
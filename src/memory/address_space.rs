@@ -0,0 +1,382 @@
+// Copyright Dan Schatzberg, 2016. This file is part of Genesis.
+
+// Genesis is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// Genesis is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+
+// You should have received a copy of the GNU Affero General Public License
+// along with Genesis.  If not, see <http://www.gnu.org/licenses/>.
+use alloc::boxed::Box;
+use core::fmt;
+use spin;
+use x86::controlregs::cr3_write;
+use x86::tlb;
+use super::*;
+use super::cpu_mutex::{CpuMutex, CpuMutexGuard};
+use super::frame_table::{self, FRAME_COW, FrameUsage};
+
+/// How faults within a `Region` are resolved
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RegionKind {
+    /// Frames are allocated and zeroed on first access
+    Lazy,
+    /// Present pages are mapped read-only and copied on the first write
+    CopyOnWrite,
+    /// Any access is an error (e.g. below a stack)
+    Guard,
+}
+
+/// A range of pages [`start`, `end`) with a fault policy
+#[derive(Clone, Copy, Debug)]
+pub struct Region {
+    start: Page,
+    end: Page,
+    kind: RegionKind,
+    flags: PTEntry,
+}
+
+impl Region {
+    /// Construct a `Region`, `flags` are used when mapping its pages
+    pub const fn new(start: Page,
+                     end: Page,
+                     kind: RegionKind,
+                     flags: PTEntry)
+                     -> Region {
+        Region {
+            start: start,
+            end: end,
+            kind: kind,
+            flags: flags,
+        }
+    }
+
+    /// Construct a guard `Region`
+    pub fn guard(start: Page, end: Page) -> Region {
+        Region::new(start, end, RegionKind::Guard, PTEntry::empty())
+    }
+
+    fn contains(&self, page: Page) -> bool {
+        self.start <= page && page < self.end
+    }
+
+    fn overlaps(&self, other: &Region) -> bool {
+        self.start < other.end && other.start < self.end
+    }
+}
+
+/// The outcome of asking an `AddressSpace` to handle a page fault
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FaultResolution {
+    /// The fault was satisfied and the access can be retried
+    Resolved,
+    /// The access hit a guard region
+    GuardPage,
+    /// The access was invalid
+    Unhandled,
+}
+
+/// Errors returned by `AddressSpace::add_region`
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RegionError {
+    /// The region overlaps an existing one
+    Overlap,
+    /// There is no space to store the region
+    Full,
+}
+
+const MAX_REGIONS: usize = 64;
+
+// Frames handed to an `AddressSpace` are always reachable through `PHYS_MAP`
+fn phys_slice<'a>(frame: Frame) -> &'a mut PageSlice {
+    unsafe { frame_to_slice(frame) }
+}
+
+/// A `PageTable` and the `Region`s describing how to fault pages into it
+pub struct AddressSpace {
+    root: Frame,
+    page_table: CpuMutex<PageTable>,
+    regions: CpuMutex<[Option<Region>; MAX_REGIONS]>,
+    allocator: &'static KernelAllocator,
+}
+
+static KERNEL_SPACE: spin::Once<AddressSpace> = spin::Once::new();
+
+/// Create the kernel `AddressSpace` around the runtime page table at `root`
+pub fn init_kernel(root: Frame,
+                   allocator: &'static KernelAllocator)
+                   -> &'static AddressSpace {
+    assert_has_not_been_called!("address_space::init_kernel() function \
+                                 must only be called once");
+    KERNEL_SPACE.call_once(|| unsafe { AddressSpace::new(root, allocator) })
+}
+
+/// Create an `AddressSpace` with an empty user half
+///
+/// The kernel half is shared with `kernel_space()`, which must exist. The
+/// `AddressSpace` is allocated on the kernel heap and is never freed.
+pub fn new_user(allocator: &'static KernelAllocator)
+                -> Option<&'static AddressSpace> {
+    let kernel = kernel_space()
+        .expect("User address space created before the kernel's");
+    let root = match allocator.allocate_manual() {
+        Some(root) => root,
+        None => return None,
+    };
+    for b in phys_slice(root).iter_mut() {
+        *b = 0;
+    }
+    frame_table::set_usage(root, FrameUsage::PageTable);
+    unsafe {
+        let space = AddressSpace::new(root, allocator);
+        space.page_table().share_kernel_half(&kernel.page_table());
+        Some(&*Box::into_raw(Box::new(space)))
+    }
+}
+
+/// Returns the kernel `AddressSpace`, if it has been created
+pub fn kernel_space() -> Option<&'static AddressSpace> {
+    KERNEL_SPACE.try()
+}
+
+impl AddressSpace {
+    /// Construct an `AddressSpace` around the PML4 in `root`
+    ///
+    /// `root` must hold a valid PML4 reachable through `PHYS_MAP`.
+    pub unsafe fn new(root: Frame,
+                      allocator: &'static KernelAllocator)
+                      -> AddressSpace {
+        let pml4: *mut _ = frame_to_slice(root).as_mut_ptr() as *mut _;
+        AddressSpace {
+            root: root,
+            page_table: CpuMutex::new("page table", PageTable::new(pml4)),
+            regions: CpuMutex::new("regions", [None; MAX_REGIONS]),
+            allocator: allocator,
+        }
+    }
+
+    /// Returns the frame holding the PML4
+    pub fn root(&self) -> Frame {
+        self.root
+    }
+
+    /// Switch the calling CPU to this `AddressSpace`
+    ///
+    /// Non-global TLB entries are flushed.
+    pub unsafe fn activate(&self) {
+        cr3_write(self.root.start_address().as_u64());
+    }
+
+    /// Lock and return the underlying `PageTable`
+    pub fn page_table(&self) -> CpuMutexGuard<PageTable> {
+        self.page_table.lock()
+    }
+
+    /// Add a `Region` whose faults will be handled by this `AddressSpace`
+    pub fn add_region(&self, region: Region) -> Result<(), RegionError> {
+        let mut regions = self.regions.lock();
+        if regions.iter()
+            .filter_map(|r| r.as_ref())
+            .any(|r| r.overlaps(&region)) {
+            return Err(RegionError::Overlap);
+        }
+        match regions.iter_mut().find(|r| r.is_none()) {
+            Some(slot) => {
+                *slot = Some(region);
+                Ok(())
+            }
+            None => Err(RegionError::Full),
+        }
+    }
+
+    /// Map `frame` at `page`, to be copied on the first write
+    ///
+    /// `page` must be unmapped and lie in a `CopyOnWrite` region. The
+    /// mapping takes its own reference to `frame`. Returns whether `frame`
+    /// was mapped.
+    pub fn map_cow(&self, page: Page, frame: Frame) -> bool {
+        let flags = match self.find_region(page) {
+            Some(region) if region.kind == RegionKind::CopyOnWrite => {
+                region.flags
+            }
+            _ => return false,
+        };
+        let mut page_table = self.page_table.lock();
+        if page_table.entry_mut(page, phys_slice)
+            .map_or(false, |entry| entry.contains(PT_P)) {
+            return false;
+        }
+        frame_table::share_cow(frame);
+        page_table.map(page,
+                       frame,
+                       (flags | PT_P) - PT_RW,
+                       self.allocator,
+                       phys_slice);
+        true
+    }
+
+    fn find_region(&self, page: Page) -> Option<Region> {
+        self.regions
+            .lock()
+            .iter()
+            .filter_map(|r| *r)
+            .find(|r| r.contains(page))
+    }
+
+    /// Try to satisfy a page fault on `addr`
+    ///
+    /// Panics if the faulting code on this CPU holds a lock it needs.
+    pub fn handle_fault(&self,
+                        addr: VAddr,
+                        error: PageFaultError)
+                        -> FaultResolution {
+        if error.contains(PF_RESERVED) {
+            return FaultResolution::Unhandled;
+        }
+        let page = Page::down(addr);
+        let region = match self.find_region(page) {
+            Some(region) => region,
+            None => return FaultResolution::Unhandled,
+        };
+        if region.kind == RegionKind::Guard {
+            return FaultResolution::GuardPage;
+        }
+        if (error.contains(PF_USER) && !region.flags.contains(PT_US)) ||
+           (error.contains(PF_WRITE) && !region.flags.contains(PT_RW)) ||
+           (error.contains(PF_INSTRUCTION) && region.flags.contains(PT_XD)) {
+            return FaultResolution::Unhandled;
+        }
+        if !error.contains(PF_PRESENT) {
+            self.populate(page, region.flags)
+        } else if region.kind == RegionKind::CopyOnWrite &&
+                  error.contains(PF_WRITE) {
+            self.copy_on_write(page, region.flags)
+        } else {
+            FaultResolution::Unhandled
+        }
+    }
+
+    /// Make the user memory [`start`, `end`) accessible to the kernel
+    ///
+    /// Every page must be mapped user accessible (and writable if `write`
+    /// is set), or be faulted in as a user access of that kind would.
+    /// Returns whether the whole range is accessible.
+    pub fn fault_in(&self, start: VAddr, end: VAddr, write: bool) -> bool {
+        let mut page = Page::down(start);
+        while page < Page::up(end) {
+            let mut error = PF_USER;
+            if write {
+                error |= PF_WRITE;
+            }
+            let accessible = {
+                let mut page_table = self.page_table.lock();
+                match page_table.entry_mut(page, phys_slice) {
+                    Some(entry) if entry.contains(PT_P) => {
+                        error |= PF_PRESENT;
+                        entry.contains(PT_US) &&
+                        (!write || entry.contains(PT_RW))
+                    }
+                    _ => false,
+                }
+            };
+            if !accessible &&
+               self.handle_fault(page.start_address(), error) !=
+               FaultResolution::Resolved {
+                return false;
+            }
+            page = page + 1;
+        }
+        true
+    }
+
+    /// Map a zeroed frame at `page`
+    fn populate(&self, page: Page, flags: PTEntry) -> FaultResolution {
+        let addr = page.start_address();
+        let mut page_table = self.page_table.lock();
+        if page_table.entry_mut(page, phys_slice)
+            .map_or(false, |entry| entry.contains(PT_P)) {
+            // Another CPU populated the page first
+            return FaultResolution::Resolved;
+        }
+        let frame = match self.allocator.allocate_manual() {
+            Some(frame) => frame,
+            None => {
+                warn!("Out of memory populating {:#X}", addr);
+                return FaultResolution::Unhandled;
+            }
+        };
+        for b in phys_slice(frame).iter_mut() {
+            *b = 0;
+        }
+        frame_table::set_usage(frame, usage(flags));
+        page_table.map(page, frame, flags | PT_P, self.allocator, phys_slice);
+        FaultResolution::Resolved
+    }
+
+    /// Make the read-only frame at `page` writable
+    ///
+    /// The frame is written in place if this is its last reference, or
+    /// replaced with a copy and the reference dropped otherwise.
+    fn copy_on_write(&self, page: Page, flags: PTEntry) -> FaultResolution {
+        let addr = page.start_address();
+        let mut page_table = self.page_table.lock();
+        let entry = match page_table.entry_mut(page, phys_slice) {
+            Some(entry) => entry,
+            None => return FaultResolution::Unhandled,
+        };
+        if !entry.contains(PT_P) {
+            return FaultResolution::Unhandled;
+        }
+        if entry.contains(PT_RW) {
+            // Another CPU already copied the page
+            return FaultResolution::Resolved;
+        }
+        let old = Frame::down(entry.get_address());
+        if let Some(info) = frame_table::get(old) {
+            if info.refcount() == 1 {
+                // No one else maps the frame, so it is written in place
+                info.remove_flags(FRAME_COW);
+                *entry = PTEntry::new(old.start_address(),
+                                      flags | PT_P | PT_RW);
+                unsafe { tlb::flush(addr.as_usize()) };
+                return FaultResolution::Resolved;
+            }
+        }
+        let new = match self.allocator.allocate_manual() {
+            Some(frame) => frame,
+            None => {
+                warn!("Out of memory copying {:#X}", addr);
+                return FaultResolution::Unhandled;
+            }
+        };
+        phys_slice(new).copy_from_slice(phys_slice(old));
+        frame_table::set_usage(new, usage(flags));
+        *entry = PTEntry::new(new.start_address(), flags | PT_P | PT_RW);
+        unsafe { tlb::flush(addr.as_usize()) };
+        // The other holders may have dropped theirs since it was checked
+        if frame_table::put_ref(old) {
+            unsafe { self.allocator.free_manual(old) };
+        }
+        FaultResolution::Resolved
+    }
+}
+
+impl fmt::Debug for AddressSpace {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "AddressSpace {{ root: {:?} }}", self.root)
+    }
+}
+
+/// Returns the usage of a frame mapped with `flags`
+fn usage(flags: PTEntry) -> FrameUsage {
+    if flags.contains(PT_US) {
+        FrameUsage::User
+    } else {
+        FrameUsage::Kernel
+    }
+}
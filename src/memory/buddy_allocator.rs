@@ -0,0 +1,512 @@
+// Copyright Dan Schatzberg, 2016. This file is part of Genesis.
+
+// Genesis is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// Genesis is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+
+// You should have received a copy of the GNU Affero General Public License
+// along with Genesis.  If not, see <http://www.gnu.org/licenses/>.
+use core::cmp;
+use core::ptr;
+use super::{Frame, FrameAllocator, FrameRange, PAddr, PAGE_SHIFT};
+use super::cpu_mutex::CpuMutex;
+use super::zone::{self, Constraint, MAX_ZONES, ZoneTable};
+
+/// Largest block order, a block of order `n` is `2^n` frames
+pub const MAX_ORDER: usize = 18;
+/// Order of a 2 MiB block
+pub const ORDER_2M: usize = 9;
+/// Order of a 1 GiB block
+pub const ORDER_1G: usize = 18;
+
+/// Physical memory tracked by the allocator returned by `get`
+///
+/// Frames above this are dropped with a warning when freed.
+pub const MAX_MEMORY: u64 = 16 << 30;
+const BITMAP_WORDS: usize = (MAX_MEMORY >> PAGE_SHIFT) as usize / 64;
+
+// Marks the end of a free list
+const NONE: u64 = !0;
+
+/// Header stored in the first frame of every free block
+///
+/// Links are frame numbers rather than pointers, so the lists survive a
+/// change of the physical memory mapping.
+#[repr(C)]
+struct FreeBlock {
+    prev: u64,
+    next: u64,
+    order: u64,
+}
+
+struct State<'a> {
+    /// First block of the free list of each zone and order
+    heads: [[u64; MAX_ORDER + 1]; MAX_ZONES],
+    zones: &'a ZoneTable,
+    /// One bit per frame, set when a free block starts at that frame
+    free: &'a mut [u64],
+    /// Virtual address at which physical memory is mapped
+    phys_offset: usize,
+    /// Whether frames beyond `free` were dropped
+    warned: bool,
+}
+
+/// A binary buddy allocator
+///
+/// Free blocks are naturally aligned runs of `2^order` frames kept on one
+/// doubly linked list per zone and order. The lists live in the free frames
+/// themselves, so only a bitmap of one bit per frame is kept on the side.
+/// Blocks never cross zone boundaries. Allocation and freeing take
+/// O(`MAX_ORDER`) steps per zone tried.
+pub struct BuddyAllocator<'a> {
+    state: CpuMutex<State<'a>>,
+}
+
+lazy_static! {
+    static ref ALLOCATOR: BuddyAllocator<'static> = {
+        static mut BITMAP_MEM: [u64; BITMAP_WORDS] = [0; BITMAP_WORDS];
+        // Unsafe to take a mutable reference of a static.
+        // We instantly store it behind a Mutex, so this is safe
+        unsafe { BuddyAllocator::new(&mut BITMAP_MEM, 0, zone::table()) }
+    };
+}
+
+impl BuddyAllocator<'static> {
+    /// Returns the kernel's buddy allocator
+    ///
+    /// Its zones are fixed on first use, so `zone::init` must come first.
+    pub fn get() -> &'static BuddyAllocator<'static> {
+        &*ALLOCATOR
+    }
+}
+
+fn frame_number(frame: Frame) -> u64 {
+    frame.start_address().as_u64() >> PAGE_SHIFT
+}
+
+fn frame_at(number: u64) -> Frame {
+    Frame::down(PAddr::from_u64(number << PAGE_SHIFT))
+}
+
+/// Returns the smallest order holding `nframes` frames
+fn order_of(nframes: u64) -> usize {
+    (64 - (nframes - 1).leading_zeros()) as usize
+}
+
+impl<'a> BuddyAllocator<'a> {
+    /// Construct an empty allocator
+    ///
+    /// `free` needs one bit for every frame that may be freed to the
+    /// allocator. Frames are accessed at `phys_offset` plus their physical
+    /// address and kept apart by the zones of `zones`.
+    pub fn new(free: &'a mut [u64],
+               phys_offset: usize,
+               zones: &'a ZoneTable)
+               -> BuddyAllocator<'a> {
+        for word in free.iter_mut() {
+            *word = 0;
+        }
+        BuddyAllocator {
+            state: CpuMutex::new("buddy allocator", State {
+                heads: [[NONE; MAX_ORDER + 1]; MAX_ZONES],
+                zones: zones,
+                free: free,
+                phys_offset: phys_offset,
+                warned: false,
+            }),
+        }
+    }
+
+    /// Change where the allocator accesses physical memory
+    ///
+    /// Every free frame must be mapped at the new offset.
+    pub unsafe fn set_phys_offset(&self, phys_offset: usize) {
+        self.state.lock().phys_offset = phys_offset;
+    }
+
+    /// Allocate a naturally aligned block of `2^order` frames
+    pub fn allocate_order(&self,
+                          order: usize,
+                          constraint: Constraint)
+                          -> Option<Frame> {
+        if order > MAX_ORDER {
+            return None;
+        }
+        unsafe {
+            self.state
+                .lock()
+                .allocate_aligned(1 << order, order, constraint)
+                .map(|range| range.lower())
+        }
+    }
+
+    /// Free a block returned by `allocate_order`
+    pub unsafe fn free_order(&self, frame: Frame, order: usize) {
+        assert!(order <= MAX_ORDER);
+        self.state.lock().free(frame_number(frame), order)
+    }
+
+    /// Returns the number of free blocks of each order
+    pub fn free_blocks(&self) -> [u64; MAX_ORDER + 1] {
+        let state = self.state.lock();
+        let mut counts = [0; MAX_ORDER + 1];
+        for heads in state.heads.iter() {
+            for (order, count) in counts.iter_mut().enumerate() {
+                let mut block = heads[order];
+                while block != NONE {
+                    *count += 1;
+                    block = unsafe { (*state.block(block)).next };
+                }
+            }
+        }
+        counts
+    }
+}
+
+impl<'a> State<'a> {
+    fn limit(&self) -> u64 {
+        self.free.len() as u64 * 64
+    }
+
+    fn zone(&self, frame: u64) -> usize {
+        self.zones.index(frame_at(frame))
+    }
+
+    fn block(&self, frame: u64) -> *mut FreeBlock {
+        (self.phys_offset + (frame << PAGE_SHIFT) as usize) as *mut FreeBlock
+    }
+
+    fn is_free(&self, frame: u64) -> bool {
+        self.free[(frame / 64) as usize] & (1 << (frame % 64)) != 0
+    }
+
+    fn set_free(&mut self, frame: u64, free: bool) {
+        let word = &mut self.free[(frame / 64) as usize];
+        if free {
+            *word |= 1 << (frame % 64);
+        } else {
+            *word &= !(1 << (frame % 64));
+        }
+    }
+
+    unsafe fn push(&mut self, frame: u64, order: usize) {
+        let zone = self.zone(frame);
+        let next = self.heads[zone][order];
+        ptr::write(self.block(frame),
+                   FreeBlock {
+                       prev: NONE,
+                       next: next,
+                       order: order as u64,
+                   });
+        if next != NONE {
+            (*self.block(next)).prev = frame;
+        }
+        self.heads[zone][order] = frame;
+        self.set_free(frame, true);
+    }
+
+    unsafe fn remove(&mut self, frame: u64, order: usize) {
+        let block = ptr::read(self.block(frame));
+        if block.prev == NONE {
+            let zone = self.zone(frame);
+            self.heads[zone][order] = block.next;
+        } else {
+            (*self.block(block.prev)).next = block.next;
+        }
+        if block.next != NONE {
+            (*self.block(block.next)).prev = block.prev;
+        }
+        self.set_free(frame, false);
+    }
+
+    /// Take a block of `order` from `zone`, splitting a larger one if needed
+    unsafe fn allocate(&mut self, zone: usize, order: usize) -> Option<u64> {
+        let found = match (order..MAX_ORDER + 1)
+            .find(|&k| self.heads[zone][k] != NONE) {
+            Some(found) => found,
+            None => return None,
+        };
+        let frame = self.heads[zone][found];
+        self.remove(frame, found);
+        // Give back the upper half until the block is the right size
+        for k in (order..found).rev() {
+            self.push(frame + (1 << k), k);
+        }
+        Some(frame)
+    }
+
+    /// Return a block, merging it with its buddy while the buddy is free
+    ///
+    /// Any part of the block above `limit()` is forgotten.
+    unsafe fn free(&mut self, mut frame: u64, mut order: usize) {
+        if frame + (1 << order) > self.limit() {
+            return self.free_range(frame, frame + (1 << order));
+        }
+        assert!(!self.is_free(frame), "Double free of frame {:#x}", frame);
+        let zone = self.zone(frame);
+        while order < MAX_ORDER {
+            let buddy = frame ^ (1 << order);
+            if buddy >= self.limit() || !self.is_free(buddy) ||
+               (*self.block(buddy)).order != order as u64 ||
+               self.zone(buddy) != zone {
+                break;
+            }
+            self.remove(buddy, order);
+            frame = cmp::min(frame, buddy);
+            order += 1;
+        }
+        self.push(frame, order);
+    }
+
+    /// Free [`lower`, `upper`) as the largest aligned blocks that fit in
+    /// each zone, warning once if any of it is above `limit()`
+    unsafe fn free_range(&mut self, mut lower: u64, upper: u64) {
+        let limit = self.limit();
+        if upper > limit && !self.warned {
+            warn!("Frames above {:#x} are not tracked and will be forgotten",
+                  limit << PAGE_SHIFT);
+            self.warned = true;
+        }
+        let upper = cmp::min(upper, limit);
+        while lower < upper {
+            let zone_end = self.zones.zones()[self.zone(lower)].end();
+            let end = cmp::max(cmp::min(upper, frame_number(zone_end)),
+                               lower + 1);
+            while lower < end {
+                let align = cmp::min(lower.trailing_zeros() as usize,
+                                     MAX_ORDER);
+                let fit = 63 - (end - lower).leading_zeros() as usize;
+                let order = cmp::min(align, fit);
+                self.free(lower, order);
+                lower += 1 << order;
+            }
+        }
+    }
+
+    /// Allocate `nframes` frames aligned to `2^order` frames from the
+    /// first zone allowed by `constraint` that has room
+    unsafe fn allocate_aligned(&mut self,
+                               nframes: u64,
+                               order: usize,
+                               constraint: Constraint)
+                               -> Option<FrameRange> {
+        if nframes == 0 {
+            return None;
+        }
+        let order = cmp::max(order, order_of(nframes));
+        if order > MAX_ORDER {
+            return None;
+        }
+        let zones = self.zones;
+        for zone in zones.preferred(constraint) {
+            if let Some(frame) = self.allocate(zone, order) {
+                // Return the unused tail of the block
+                self.free_range(frame + nframes, frame + (1 << order));
+                return Some(FrameRange::new(frame_at(frame),
+                                            frame_at(frame + nframes)));
+            }
+        }
+        None
+    }
+}
+
+impl<'a> FrameAllocator for BuddyAllocator<'a> {
+    fn allocate_manual(&self) -> Option<Frame> {
+        self.allocate_order(0, Constraint::any())
+    }
+
+    unsafe fn free_manual(&self, frame: Frame) {
+        self.free_order(frame, 0)
+    }
+
+    fn allocate_range_manual(&self, nframes: u64) -> Option<FrameRange> {
+        self.allocate_constrained_manual(nframes, 1, Constraint::any())
+    }
+
+    fn allocate_batch(&self, frames: &mut [Frame]) -> usize {
+        let mut state = self.state.lock();
+        for (i, slot) in frames.iter_mut().enumerate() {
+            match unsafe { state.allocate_aligned(1, 0, Constraint::any()) } {
+                Some(range) => *slot = range.lower(),
+                None => return i,
+            }
+        }
+        frames.len()
+    }
+
+    unsafe fn free_batch(&self, frames: &[Frame]) {
+        let mut state = self.state.lock();
+        for &frame in frames {
+            state.free(frame_number(frame), 0);
+        }
+    }
+
+    unsafe fn free_range_manual(&self, range: FrameRange) {
+        self.state
+            .lock()
+            .free_range(frame_number(range.lower()),
+                        frame_number(range.upper()))
+    }
+
+    fn allocate_constrained_manual(&self,
+                                   nframes: u64,
+                                   align: u64,
+                                   constraint: Constraint)
+                                   -> Option<FrameRange> {
+        assert!(align.is_power_of_two());
+        let order = align.trailing_zeros() as usize;
+        unsafe {
+            self.state
+                .lock()
+                .allocate_aligned(nframes, order, constraint)
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{BuddyAllocator, MAX_ORDER, ORDER_2M, frame_at, frame_number};
+    use memory::{FrameAllocator, FrameRange, PAddr, PAGE_SIZE};
+    use memory::zone::{Constraint, ZoneTable};
+    use std::vec::Vec;
+
+    const NFRAMES: u64 = 1 << 10;
+
+    /// Returns memory standing in for `NFRAMES` physical frames
+    fn memory() -> Vec<u64> {
+        vec![0; (NFRAMES * PAGE_SIZE) as usize / 8]
+    }
+
+    /// Returns zones without NUMA nodes, `NFRAMES` fit in the first
+    fn zones() -> ZoneTable {
+        ZoneTable::new(None::<(PAddr, PAddr, u32)>.into_iter())
+    }
+
+    fn range(lower: u64, upper: u64) -> FrameRange {
+        FrameRange::new(frame_at(lower), frame_at(upper))
+    }
+
+    #[test]
+    fn test_get() {
+        BuddyAllocator::get();
+    }
+
+    #[test]
+    fn test_split_and_merge() {
+        let mem = memory();
+        let zones = zones();
+        let mut bitmap = [0; (NFRAMES / 64) as usize];
+        let allocator = BuddyAllocator::new(&mut bitmap,
+                                            mem.as_ptr() as usize,
+                                            &zones);
+        unsafe { allocator.free_range_manual(range(0, 16)) };
+        assert_eq!(allocator.free_blocks()[4], 1);
+        assert_eq!(allocator.allocate_range_manual(0), None);
+
+        let a = allocator.allocate_manual().unwrap();
+        let b = allocator.allocate_range_manual(4).unwrap();
+        assert_eq!(frame_number(a), 0);
+        assert_eq!(b, range(4, 8));
+        let counts = allocator.free_blocks();
+        assert_eq!(&counts[..4], &[1, 1, 0, 1]);
+
+        unsafe {
+            allocator.free_manual(a);
+            allocator.free_range_manual(b);
+        }
+        let counts = allocator.free_blocks();
+        assert_eq!(counts[4], 1);
+        assert_eq!(counts.iter().sum::<u64>(), 1);
+        assert_eq!(allocator.allocate_range_manual(16), Some(range(0, 16)));
+        assert_eq!(allocator.allocate_manual(), None);
+    }
+
+    #[test]
+    fn test_unaligned_range() {
+        let mem = memory();
+        let zones = zones();
+        let mut bitmap = [0; (NFRAMES / 64) as usize];
+        let allocator = BuddyAllocator::new(&mut bitmap,
+                                            mem.as_ptr() as usize,
+                                            &zones);
+        unsafe { allocator.free_range_manual(range(3, 21)) };
+        // 3, 4-7, 8-15, 16-19, 20
+        assert_eq!(&allocator.free_blocks()[..4], &[2, 0, 2, 1]);
+
+        let r = allocator.allocate_aligned_manual(3, 8).unwrap();
+        assert_eq!(r, range(8, 11));
+        // The tail of the block is given back
+        assert_eq!(allocator.allocate_aligned_manual(1, 1),
+                   Some(range(11, 12)));
+        assert_eq!(allocator.allocate_range_manual(5), None);
+    }
+
+    #[test]
+    fn test_large_blocks() {
+        let mem = memory();
+        let zones = zones();
+        let mut bitmap = [0; (NFRAMES / 64) as usize];
+        let allocator = BuddyAllocator::new(&mut bitmap,
+                                            mem.as_ptr() as usize,
+                                            &zones);
+        unsafe { allocator.free_range_manual(range(1, NFRAMES)) };
+        let any = Constraint::any();
+        let block = allocator.allocate_order(ORDER_2M, any).unwrap();
+        assert_eq!(frame_number(block) % (1 << ORDER_2M), 0);
+        assert_eq!(allocator.allocate_order(ORDER_2M, any), None);
+        assert_eq!(allocator.allocate_order(MAX_ORDER + 1, any), None);
+        unsafe {
+            allocator.free_order(block, ORDER_2M);
+            allocator.free_manual(frame_at(0));
+        }
+        assert_eq!(allocator.allocate_order(10, any), Some(frame_at(0)));
+    }
+
+    #[test]
+    fn test_untracked_frames() {
+        let mem = memory();
+        let zones = zones();
+        let mut bitmap = [0; (NFRAMES / 64) as usize];
+        let allocator = BuddyAllocator::new(&mut bitmap,
+                                            mem.as_ptr() as usize,
+                                            &zones);
+        // Nothing above the bitmap is tracked, whichever way it is freed
+        unsafe {
+            allocator.free_manual(frame_at(NFRAMES));
+            allocator.free_order(frame_at(NFRAMES + 8), 3);
+            allocator.free_batch(&[frame_at(NFRAMES + 1), frame_at(1)]);
+            allocator.free_order(frame_at(NFRAMES - 8), 3);
+        }
+        let counts = allocator.free_blocks();
+        assert_eq!(&counts[..4], &[1, 0, 0, 1]);
+        assert_eq!(counts.iter().sum::<u64>(), 2);
+    }
+
+    #[test]
+    fn test_zones() {
+        // Node 1 starts at frame 96, which splits the first 128 frames
+        let start = PAddr::from_u64(96 * PAGE_SIZE);
+        let end = PAddr::from_u64(NFRAMES * PAGE_SIZE);
+        let zones = ZoneTable::new(Some((start, end, 1)).into_iter());
+        let mem = memory();
+        let mut bitmap = [0; (NFRAMES / 64) as usize];
+        let allocator = BuddyAllocator::new(&mut bitmap,
+                                            mem.as_ptr() as usize,
+                                            &zones);
+        unsafe { allocator.free_range_manual(range(0, 128)) };
+        // 0-63 and 64-95 on node 0, 96-127 on node 1
+        assert_eq!(&allocator.free_blocks()[5..8], &[2, 1, 0]);
+
+        let node1 = Constraint::any().on_node(1);
+        assert_eq!(allocator.allocate_constrained_manual(32, 1, node1),
+                   Some(range(96, 128)));
+        assert_eq!(allocator.allocate_constrained_manual(1, 1, node1), None);
+        assert_eq!(allocator.allocate_range_manual(64), Some(range(0, 64)));
+    }
+}
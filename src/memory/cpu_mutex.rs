@@ -0,0 +1,106 @@
+// Copyright Dan Schatzberg, 2016. This file is part of Genesis.
+
+// Genesis is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// Genesis is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+
+// You should have received a copy of the GNU Affero General Public License
+// along with Genesis.  If not, see <http://www.gnu.org/licenses/>.
+use core::ops::{Deref, DerefMut};
+use core::sync::atomic::{AtomicUsize, Ordering};
+use spin;
+use super::cpu_index;
+
+/// A spin lock that knows which CPU holds it
+///
+/// Taking it again on the holding CPU, from a page fault or an interrupt
+/// handler that interrupted the holder, could never succeed, so it panics
+/// instead of spinning forever. Other CPUs wait for the holder as with a
+/// `spin::Mutex`. CPUs without per-CPU data are not told apart.
+pub struct CpuMutex<T> {
+    name: &'static str,
+    // Index of the holding CPU plus one, 0 when free or unknown
+    holder: AtomicUsize,
+    lock: spin::Mutex<T>,
+}
+
+/// Holds a `CpuMutex` until dropped
+pub struct CpuMutexGuard<'a, T: 'a> {
+    holder: &'a AtomicUsize,
+    guard: spin::MutexGuard<'a, T>,
+}
+
+impl<T> CpuMutex<T> {
+    /// Construct a `CpuMutex` called `name` in its panic message
+    pub const fn new(name: &'static str, data: T) -> CpuMutex<T> {
+        CpuMutex {
+            name: name,
+            holder: AtomicUsize::new(0),
+            lock: spin::Mutex::new(data),
+        }
+    }
+
+    /// Wait for the lock and take it
+    pub fn lock(&self) -> CpuMutexGuard<T> {
+        let cpu = cpu_index().map_or(0, |cpu| cpu + 1);
+        loop {
+            if let Some(guard) = self.lock.try_lock() {
+                self.holder.store(cpu, Ordering::SeqCst);
+                return CpuMutexGuard {
+                    holder: &self.holder,
+                    guard: guard,
+                };
+            }
+            if cpu != 0 && self.holder.load(Ordering::SeqCst) == cpu {
+                panic!("The {} lock was taken again on CPU {}, which holds it",
+                       self.name,
+                       cpu - 1);
+            }
+        }
+    }
+}
+
+impl<'a, T> Deref for CpuMutexGuard<'a, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &*self.guard
+    }
+}
+
+impl<'a, T> DerefMut for CpuMutexGuard<'a, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        &mut *self.guard
+    }
+}
+
+// Runs before `guard` unlocks, so the next holder's index is not lost
+impl<'a, T> Drop for CpuMutexGuard<'a, T> {
+    fn drop(&mut self) {
+        self.holder.store(0, Ordering::SeqCst);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use core::sync::atomic::Ordering;
+    use super::CpuMutex;
+
+    #[test]
+    fn test_lock() {
+        let mutex = CpuMutex::new("test", 1);
+        {
+            let mut guard = mutex.lock();
+            *guard += 1;
+            assert!(mutex.lock.try_lock().is_none());
+        }
+        assert_eq!(*mutex.lock(), 2);
+        assert_eq!(mutex.holder.load(Ordering::SeqCst), 0);
+    }
+}
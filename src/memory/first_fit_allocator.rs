@@ -12,16 +12,18 @@
 
 // You should have received a copy of the GNU Affero General Public License
 // along with Genesis.  If not, see <http://www.gnu.org/licenses/>.
+use core::cmp;
 use fixedvec::FixedVec;
-use spin;
-use super::{Frame, FrameAllocator, FrameRange, PAddr};
+use super::{Frame, FrameAllocator, FrameRange, PAddr, PAGE_SIZE};
+use super::cpu_mutex::CpuMutex;
+use super::zone::{self, Constraint, Zone};
 
 pub struct FirstFitAllocator<'a> {
-    frames: &'a spin::Mutex<FixedVec<'a, FrameRange>>,
+    frames: &'a CpuMutex<FixedVec<'a, FrameRange>>,
 }
 
 lazy_static! {
-    static ref FRAMES: spin::Mutex<FixedVec<'static, FrameRange>> = {
+    static ref FRAMES: CpuMutex<FixedVec<'static, FrameRange>> = {
         const FRAMES_SIZE: usize = 256;
         static mut FRAMES_MEM: [FrameRange; FRAMES_SIZE] = [FrameRange::new(
             Frame::down(PAddr::from_u64(0)), Frame::down(PAddr::from_u64(0)));
@@ -29,7 +31,8 @@ lazy_static! {
         // Unsafe to take a mutable reference of a static.
         // We instantly store it behind a Mutex, so this is safe
         unsafe {
-            spin::Mutex::new(FixedVec::new(&mut FRAMES_MEM))
+            CpuMutex::new("first fit allocator",
+                          FixedVec::new(&mut FRAMES_MEM))
         }
     };
     static ref ALLOCATOR: FirstFitAllocator<'static> = {
@@ -68,51 +71,130 @@ impl<'a> FrameAllocator for FirstFitAllocator<'a> {
             })
     }
 
+    fn allocate_constrained_manual(&self,
+                                   nframes: u64,
+                                   align: u64,
+                                   constraint: Constraint)
+                                   -> Option<FrameRange> {
+        assert!(align.is_power_of_two());
+        let align_bytes = align * PAGE_SIZE;
+        // The aligned frames at the start of the part of `range` in `zone`
+        let aligned = |range: &FrameRange, zone: &Zone| {
+            let lower = cmp::max(range.lower(), zone.start());
+            let addr = lower.start_address().as_u64();
+            let start = Frame::down(PAddr::from_u64((addr + align_bytes - 1) &
+                                                    !(align_bytes - 1)));
+            (start, start + nframes)
+        };
+        let table = zone::table();
+        let found = {
+            let mut frames = self.frames.lock();
+            let mut found = None;
+            for zone in table.preferred(constraint) {
+                let zone = &table.zones()[zone];
+                let index = frames.iter().position(|range| {
+                    let end = aligned(range, zone).1;
+                    end <= range.upper() && end <= zone.end()
+                });
+                if let Some(index) = index {
+                    found = Some((frames.remove(index), zone));
+                    break;
+                }
+            }
+            found
+        };
+        let (range, zone) = match found {
+            Some(found) => found,
+            None => return None,
+        };
+        // Give back the frames on either side of the allocation
+        let (start, end) = aligned(&range, zone);
+        unsafe {
+            if range.lower() < start {
+                self.free_range_manual(FrameRange::new(range.lower(), start));
+            }
+            if end < range.upper() {
+                self.free_range_manual(FrameRange::new(end, range.upper()));
+            }
+        }
+        Some(FrameRange::new(start, end))
+    }
+
     unsafe fn free_range_manual(&self, range: FrameRange) {
+        insert(&mut self.frames.lock(), range)
+    }
+
+    fn allocate_batch(&self, out: &mut [Frame]) -> usize {
         let mut frames = self.frames.lock();
-        let ind = {
-            let slice = frames.as_slice();
-            slice.binary_search_by(|r| r.partial_cmp(&range).unwrap())
-                .unwrap_err()
-        };
-        let prev_coalesce = if ind > 0 {
-            if let Some(prev) = frames.get(ind - 1) {
-                prev.upper() == range.lower()
+        let mut len = 0;
+        while len < out.len() && frames.len() > 0 {
+            let nframes = cmp::min(frames[0].nframes(),
+                                   (out.len() - len) as u64);
+            for i in 0..nframes {
+                out[len] = frames[0].lower() + i;
+                len += 1;
+            }
+            if frames[0].nframes() == nframes {
+                frames.remove(0);
             } else {
-                false
+                frames[0].trim_front(nframes);
             }
+        }
+        len
+    }
+
+    unsafe fn free_batch(&self, frames: &[Frame]) {
+        let mut ranges = self.frames.lock();
+        for &frame in frames {
+            insert(&mut ranges, FrameRange::new(frame, frame + 1));
+        }
+    }
+}
+
+/// Add `range` to the sorted `frames`, coalescing with its neighbours
+fn insert(frames: &mut FixedVec<FrameRange>, range: FrameRange) {
+    let ind = {
+        let slice = frames.as_slice();
+        slice.binary_search_by(|r| r.partial_cmp(&range).unwrap())
+            .unwrap_err()
+    };
+    let prev_coalesce = if ind > 0 {
+        if let Some(prev) = frames.get(ind - 1) {
+            prev.upper() == range.lower()
         } else {
             false
-        };
-        let next_coalesce = if ind < frames.len() {
-            if let Some(next) = frames.get(ind) {
-                range.upper() == next.lower()
-            } else {
-                false
-            }
+        }
+    } else {
+        false
+    };
+    let next_coalesce = if ind < frames.len() {
+        if let Some(next) = frames.get(ind) {
+            range.upper() == next.lower()
         } else {
             false
-        };
-        if !prev_coalesce && !next_coalesce {
-            if frames.insert(ind, range).is_err() {
-                warn!("No space to store freed range.\
-                       It will be forgetten: {:?}",
-                      range)
-            }
-        } else if prev_coalesce && !next_coalesce {
+        }
+    } else {
+        false
+    };
+    if !prev_coalesce && !next_coalesce {
+        if frames.insert(ind, range).is_err() {
+            warn!("No space to store freed range.\
+                   It will be forgetten: {:?}",
+                  range)
+        }
+    } else if prev_coalesce && !next_coalesce {
+        let mut prev = frames.get_mut(ind - 1).unwrap();
+        prev.push_back(range.nframes());
+    } else if !prev_coalesce && next_coalesce {
+        let mut next = frames.get_mut(ind).unwrap();
+        next.push_front(range.nframes());
+    } else {
+        let nframes = frames.get(ind).unwrap().nframes() + range.nframes();
+        {
             let mut prev = frames.get_mut(ind - 1).unwrap();
-            prev.push_back(range.nframes());
-        } else if !prev_coalesce && next_coalesce {
-            let mut next = frames.get_mut(ind).unwrap();
-            next.push_front(range.nframes());
-        } else {
-            let nframes = frames.get(ind).unwrap().nframes() + range.nframes();
-            {
-                let mut prev = frames.get_mut(ind - 1).unwrap();
-                prev.push_back(nframes);
-            }
-            frames.remove(ind);
+            prev.push_back(nframes);
         }
+        frames.remove(ind);
     }
 }
 
@@ -121,7 +203,7 @@ mod test {
     use super::FirstFitAllocator;
     use fixedvec::FixedVec;
     use memory::{Frame, FrameAllocator, FrameRange};
-    use spin;
+    use memory::cpu_mutex::CpuMutex;
 
     #[test]
     fn test_get() {
@@ -135,7 +217,7 @@ mod test {
     #[test]
     fn test_simple() {
         let mut space = [create_range(0, 0); 256];
-        let frames = spin::Mutex::new(FixedVec::new(&mut space));
+        let frames = CpuMutex::new("frames", FixedVec::new(&mut space));
         let allocator = FirstFitAllocator { frames: &frames };
         let r = create_range(0, 1);
         unsafe { allocator.free_range_manual(r) };
@@ -145,7 +227,7 @@ mod test {
     #[test]
     fn test_prev_coalesce() {
         let mut space = [create_range(0, 0); 256];
-        let frames = spin::Mutex::new(FixedVec::new(&mut space));
+        let frames = CpuMutex::new("frames", FixedVec::new(&mut space));
         let allocator = FirstFitAllocator { frames: &frames };
         unsafe { allocator.free_range_manual(create_range(0, 1)) };
         unsafe { allocator.free_range_manual(create_range(1, 1)) };
@@ -156,7 +238,7 @@ mod test {
     #[test]
     fn test_next_coalesce() {
         let mut space = [create_range(0, 0); 256];
-        let frames = spin::Mutex::new(FixedVec::new(&mut space));
+        let frames = CpuMutex::new("frames", FixedVec::new(&mut space));
         let allocator = FirstFitAllocator { frames: &frames };
         unsafe { allocator.free_range_manual(create_range(1, 1)) };
         unsafe { allocator.free_range_manual(create_range(0, 1)) };
@@ -167,7 +249,7 @@ mod test {
     #[test]
     fn test_both_coalesce() {
         let mut space = [create_range(0, 0); 256];
-        let frames = spin::Mutex::new(FixedVec::new(&mut space));
+        let frames = CpuMutex::new("frames", FixedVec::new(&mut space));
         let allocator = FirstFitAllocator { frames: &frames };
         unsafe { allocator.free_range_manual(create_range(0, 1)) };
         unsafe { allocator.free_range_manual(create_range(2, 1)) };
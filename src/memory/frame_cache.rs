@@ -0,0 +1,330 @@
+// Copyright Dan Schatzberg, 2016. This file is part of Genesis.
+
+// Genesis is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// Genesis is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+
+// You should have received a copy of the GNU Affero General Public License
+// along with Genesis.  If not, see <http://www.gnu.org/licenses/>.
+use core::fmt;
+use core::sync::atomic::{ATOMIC_BOOL_INIT, ATOMIC_USIZE_INIT, AtomicBool,
+                         AtomicUsize, Ordering};
+use spin;
+use super::{Frame, FrameAllocator, PAddr};
+
+/// Frames a magazine holds
+pub const MAGAZINE_SIZE: usize = 64;
+/// Frames moved between a magazine and the global allocator at once
+pub const BATCH: usize = MAGAZINE_SIZE / 2;
+/// Most caches that `register` keeps track of
+pub const MAX_CACHES: usize = 64;
+
+/// Counters of one `CpuCache`
+#[derive(Clone, Copy, Debug, Default)]
+pub struct CacheStats {
+    /// Allocations served from the magazine
+    pub alloc_hits: u64,
+    /// Allocations that refilled the magazine first
+    pub alloc_misses: u64,
+    /// Frees kept in the magazine
+    pub free_hits: u64,
+    /// Frees that drained the magazine first
+    pub free_misses: u64,
+}
+
+impl CacheStats {
+    /// Returns the share of operations that did not touch the global
+    /// allocator, in percent
+    pub fn hit_rate(&self) -> u64 {
+        let hits = self.alloc_hits + self.free_hits;
+        let total = hits + self.alloc_misses + self.free_misses;
+        if total == 0 { 0 } else { hits * 100 / total }
+    }
+}
+
+struct Magazine {
+    frames: [Frame; MAGAZINE_SIZE],
+    len: usize,
+    stats: CacheStats,
+}
+
+/// A CPU's stack of free frames in front of the global allocator
+///
+/// Only the owning CPU allocates from or frees to its cache. The lock is
+/// uncontended except when an interrupt handler on the same CPU reenters,
+/// in which case the operation bypasses the cache.
+pub struct CpuCache {
+    magazine: spin::Mutex<Magazine>,
+}
+
+static ENABLED: AtomicBool = ATOMIC_BOOL_INIT;
+static BYPASSED: AtomicUsize = ATOMIC_USIZE_INIT;
+static CACHES: spin::RwLock<[Option<&'static CpuCache>; MAX_CACHES]> =
+    spin::RwLock::new([None; MAX_CACHES]);
+
+/// Turn the per-CPU caches on or off
+///
+/// Frames already cached stay there until the caches are turned back on.
+/// Once on, every CPU that allocates frames must have a cache, which is
+/// looked up without checking that it is set up.
+pub fn set_enabled(enabled: bool) {
+    ENABLED.store(enabled, Ordering::SeqCst);
+}
+
+/// Returns whether the per-CPU caches are on
+pub fn enabled() -> bool {
+    ENABLED.load(Ordering::SeqCst)
+}
+
+/// Returns the calling CPU's cache, if caches are on
+pub fn current() -> Option<&'static CpuCache> {
+    if ENABLED.load(Ordering::Relaxed) {
+        Some(super::cpu_frame_cache())
+    } else {
+        None
+    }
+}
+
+/// Make the cache of CPU `cpu` show up in `log_stats`
+pub fn register(cpu: usize, cache: &'static CpuCache) {
+    match CACHES.write().get_mut(cpu) {
+        Some(slot) => *slot = Some(cache),
+        None => warn!("No room to track the frame cache of CPU {}", cpu),
+    }
+}
+
+/// Return the frames of every registered cache to `global`
+///
+/// For allocations of ranges, which the caches do not serve and which may
+/// fail only because the free frames sit in magazines. Busy caches are
+/// skipped. Returns the number of frames given back.
+pub fn drain_all<A: FrameAllocator>(global: &A) -> usize {
+    let mut drained = 0;
+    for cache in CACHES.read().iter().filter_map(|cache| *cache) {
+        if let Some(mut magazine) = cache.magazine.try_lock() {
+            unsafe { global.free_batch(&magazine.frames[..magazine.len]) };
+            drained += magazine.len;
+            magazine.len = 0;
+        }
+    }
+    drained
+}
+
+/// Log the counters of every registered cache
+pub fn log_stats() {
+    for (cpu, cache) in CACHES.read().iter().enumerate() {
+        if let Some(cache) = *cache {
+            let stats = cache.stats();
+            info!("CPU {} frame cache: {}% hits, {:?}",
+                  cpu,
+                  stats.hit_rate(),
+                  stats);
+        }
+    }
+    info!("Frame cache bypassed {} times",
+          BYPASSED.load(Ordering::Relaxed));
+}
+
+impl CpuCache {
+    /// Construct an empty cache
+    pub const fn new() -> CpuCache {
+        CpuCache {
+            magazine: spin::Mutex::new(Magazine {
+                frames: [Frame::down(PAddr::from_u64(0)); MAGAZINE_SIZE],
+                len: 0,
+                stats: CacheStats {
+                    alloc_hits: 0,
+                    alloc_misses: 0,
+                    free_hits: 0,
+                    free_misses: 0,
+                },
+            }),
+        }
+    }
+
+    /// Take a frame, refilling from `global` when the cache is empty
+    ///
+    /// Returns `None` when the cache is busy or `global` has no frames, in
+    /// which case the caller should go to `global` itself.
+    pub fn allocate<A: FrameAllocator>(&self, global: &A) -> Option<Frame> {
+        let mut guard = match self.magazine.try_lock() {
+            Some(guard) => guard,
+            None => {
+                BYPASSED.fetch_add(1, Ordering::Relaxed);
+                return None;
+            }
+        };
+        let magazine = &mut *guard;
+        if magazine.len == 0 {
+            magazine.stats.alloc_misses += 1;
+            let len = global.allocate_batch(&mut magazine.frames[..BATCH]);
+            magazine.len = len;
+            if len == 0 {
+                return None;
+            }
+        } else {
+            magazine.stats.alloc_hits += 1;
+        }
+        magazine.len -= 1;
+        Some(magazine.frames[magazine.len])
+    }
+
+    /// Keep `frame`, draining a batch to `global` when the cache is full
+    ///
+    /// Returns false when the cache is busy, in which case the caller must
+    /// free `frame` to `global` itself.
+    pub unsafe fn free<A: FrameAllocator>(&self,
+                                          global: &A,
+                                          frame: Frame)
+                                          -> bool {
+        let mut guard = match self.magazine.try_lock() {
+            Some(guard) => guard,
+            None => {
+                BYPASSED.fetch_add(1, Ordering::Relaxed);
+                return false;
+            }
+        };
+        let magazine = &mut *guard;
+        if magazine.len == MAGAZINE_SIZE {
+            magazine.stats.free_misses += 1;
+            // Give back the oldest frames, the newest are more likely to
+            // still be in the CPU cache
+            global.free_batch(&magazine.frames[..BATCH]);
+            for i in 0..MAGAZINE_SIZE - BATCH {
+                magazine.frames[i] = magazine.frames[i + BATCH];
+            }
+            magazine.len -= BATCH;
+        } else {
+            magazine.stats.free_hits += 1;
+        }
+        let len = magazine.len;
+        magazine.frames[len] = frame;
+        magazine.len += 1;
+        true
+    }
+
+    /// Return every cached frame to `global`
+    pub unsafe fn drain<A: FrameAllocator>(&self, global: &A) {
+        let mut guard = self.magazine.lock();
+        let magazine = &mut *guard;
+        global.free_batch(&magazine.frames[..magazine.len]);
+        magazine.len = 0;
+    }
+
+    /// Returns the counters of this cache
+    pub fn stats(&self) -> CacheStats {
+        self.magazine.lock().stats
+    }
+
+    /// Returns the number of cached frames
+    pub fn len(&self) -> usize {
+        self.magazine.lock().len
+    }
+}
+
+impl fmt::Debug for CpuCache {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.magazine.try_lock() {
+            Some(magazine) => {
+                write!(f, "CpuCache({} frames, {:?})", magazine.len,
+                       magazine.stats)
+            }
+            None => write!(f, "CpuCache(busy)"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use core::sync::atomic::{ATOMIC_USIZE_INIT, AtomicUsize, Ordering};
+    use memory::{Frame, FrameAllocator, FrameRange, PAddr, PAGE_SHIFT};
+    use memory::zone::Constraint;
+    use super::{BATCH, CpuCache, MAGAZINE_SIZE, drain_all, register};
+
+    // Hands out ever increasing frames and counts the frees
+    struct Counter {
+        allocated: AtomicUsize,
+        freed: AtomicUsize,
+    }
+
+    impl FrameAllocator for Counter {
+        fn allocate_manual(&self) -> Option<Frame> {
+            let n = self.allocated.fetch_add(1, Ordering::SeqCst) as u64;
+            Some(Frame::down(PAddr::from_u64(n << PAGE_SHIFT)))
+        }
+
+        unsafe fn free_manual(&self, _: Frame) {
+            self.freed.fetch_add(1, Ordering::SeqCst);
+        }
+
+        fn allocate_range_manual(&self, _: u64) -> Option<FrameRange> {
+            None
+        }
+
+        unsafe fn free_range_manual(&self, _: FrameRange) {}
+
+        fn allocate_constrained_manual(&self,
+                                       _: u64,
+                                       _: u64,
+                                       _: Constraint)
+                                       -> Option<FrameRange> {
+            None
+        }
+    }
+
+    #[test]
+    fn test_refill_and_drain() {
+        let global = Counter {
+            allocated: ATOMIC_USIZE_INIT,
+            freed: ATOMIC_USIZE_INIT,
+        };
+        let cache = CpuCache::new();
+        let first = cache.allocate(&global).unwrap();
+        assert_eq!(global.allocated.load(Ordering::SeqCst), BATCH);
+        assert_eq!(cache.len(), BATCH - 1);
+        for _ in 1..BATCH {
+            cache.allocate(&global).unwrap();
+        }
+        assert_eq!(global.allocated.load(Ordering::SeqCst), BATCH);
+
+        for i in 0..MAGAZINE_SIZE as u64 + 1 {
+            assert!(unsafe { cache.free(&global, first + i) });
+        }
+        assert_eq!(global.freed.load(Ordering::SeqCst), BATCH);
+        assert_eq!(cache.len(), MAGAZINE_SIZE - BATCH + 1);
+        // The most recently freed frame comes back first
+        assert_eq!(cache.allocate(&global),
+                   Some(first + MAGAZINE_SIZE as u64));
+
+        let stats = cache.stats();
+        assert_eq!(stats.alloc_misses, 1);
+        assert_eq!(stats.alloc_hits, BATCH as u64);
+        assert_eq!(stats.free_misses, 1);
+        assert_eq!(stats.free_hits, MAGAZINE_SIZE as u64);
+
+        unsafe { cache.drain(&global) };
+        assert_eq!(cache.len(), 0);
+        assert_eq!(global.freed.load(Ordering::SeqCst), MAGAZINE_SIZE);
+    }
+
+    #[test]
+    fn test_drain_all() {
+        static CACHE: CpuCache = CpuCache::new();
+        let global = Counter {
+            allocated: ATOMIC_USIZE_INIT,
+            freed: ATOMIC_USIZE_INIT,
+        };
+        register(0, &CACHE);
+        CACHE.allocate(&global).unwrap();
+        assert_eq!(drain_all(&global), BATCH - 1);
+        assert_eq!(global.freed.load(Ordering::SeqCst), BATCH - 1);
+        assert_eq!(CACHE.len(), 0);
+        assert_eq!(drain_all(&global), 0);
+    }
+}
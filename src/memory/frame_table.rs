@@ -0,0 +1,409 @@
+// Copyright Dan Schatzberg, 2016. This file is part of Genesis.
+
+// Genesis is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// Genesis is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+
+// You should have received a copy of the GNU Affero General Public License
+// along with Genesis.  If not, see <http://www.gnu.org/licenses/>.
+use core::mem;
+use core::ptr;
+use core::slice;
+use core::sync::atomic::{ATOMIC_USIZE_INIT, AtomicUsize, Ordering};
+use spin;
+use super::*;
+
+/// What a frame is used for
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FrameUsage {
+    /// Not handed out since the table was created, or never usable
+    Unknown,
+    /// Owned by the frame allocator
+    Free,
+    /// Allocated by the kernel for no more specific purpose
+    Kernel,
+    /// Holds a paging structure
+    PageTable,
+    /// Backs the kernel heap
+    Heap,
+    /// Mapped into a user address space
+    User,
+    /// Shared with a device
+    Device,
+}
+
+/// Number of `FrameUsage` values
+pub const NUM_USAGES: usize = 7;
+
+/// Every `FrameUsage`, in discriminant order
+pub const USAGES: [FrameUsage; NUM_USAGES] = [FrameUsage::Unknown,
+                                              FrameUsage::Free,
+                                              FrameUsage::Kernel,
+                                              FrameUsage::PageTable,
+                                              FrameUsage::Heap,
+                                              FrameUsage::User,
+                                              FrameUsage::Device];
+
+bitflags! {
+    /// Per-frame state
+    pub flags FrameFlags: usize {
+        /// The frame must not move or be freed, e.g. while used for DMA
+        const FRAME_PINNED = 1 << 0,
+        /// The frame is mapped copy-on-write
+        const FRAME_COW = 1 << 1,
+    }
+}
+
+// The usage is kept in the low byte of the state and the flags above it
+const USAGE_MASK: usize = 0xff;
+const FLAGS_SHIFT: usize = 8;
+
+/// The metadata of one frame
+///
+/// An all zero `FrameInfo` is an `Unknown` frame with no references.
+pub struct FrameInfo {
+    refcount: AtomicUsize,
+    state: AtomicUsize,
+}
+
+impl FrameInfo {
+    /// Construct the metadata of an `Unknown` frame
+    pub const fn new() -> FrameInfo {
+        FrameInfo {
+            refcount: ATOMIC_USIZE_INIT,
+            state: ATOMIC_USIZE_INIT,
+        }
+    }
+
+    /// Returns the number of references to the frame
+    pub fn refcount(&self) -> usize {
+        self.refcount.load(Ordering::SeqCst)
+    }
+
+    /// Take another reference and return the new count
+    pub fn get_ref(&self) -> usize {
+        self.refcount.fetch_add(1, Ordering::SeqCst) + 1
+    }
+
+    /// Drop a reference, returns whether it was the last
+    pub fn put_ref(&self) -> bool {
+        let old = self.refcount.fetch_sub(1, Ordering::SeqCst);
+        assert!(old > 0, "Frame reference count underflow");
+        old == 1
+    }
+
+    /// Returns what the frame is used for
+    pub fn usage(&self) -> FrameUsage {
+        let usage = self.state.load(Ordering::SeqCst) & USAGE_MASK;
+        USAGES.get(usage).cloned().unwrap_or(FrameUsage::Unknown)
+    }
+
+    /// Record what the frame is used for, keeping its flags
+    pub fn set_usage(&self, usage: FrameUsage) {
+        let mut old = self.state.load(Ordering::SeqCst);
+        loop {
+            let new = (old & !USAGE_MASK) | usage as usize;
+            let prev = self.state.compare_and_swap(old, new, Ordering::SeqCst);
+            if prev == old {
+                return;
+            }
+            old = prev;
+        }
+    }
+
+    /// Returns the flags of the frame
+    pub fn flags(&self) -> FrameFlags {
+        let state = self.state.load(Ordering::SeqCst);
+        FrameFlags::from_bits_truncate(state >> FLAGS_SHIFT)
+    }
+
+    /// Set `flags` on the frame
+    pub fn insert_flags(&self, flags: FrameFlags) {
+        self.state.fetch_or(flags.bits() << FLAGS_SHIFT, Ordering::SeqCst);
+    }
+
+    /// Clear `flags` on the frame
+    pub fn remove_flags(&self, flags: FrameFlags) {
+        self.state.fetch_and(!(flags.bits() << FLAGS_SHIFT), Ordering::SeqCst);
+    }
+
+    /// Returns whether the frame may go back to the allocator
+    ///
+    /// Pinned frames and frames someone else still references may not.
+    pub fn freeable(&self) -> bool {
+        self.refcount() <= 1 && !self.flags().contains(FRAME_PINNED)
+    }
+
+    fn reset(&self, usage: FrameUsage, refcount: usize) {
+        self.refcount.store(refcount, Ordering::SeqCst);
+        self.state.store(usage as usize, Ordering::SeqCst);
+    }
+}
+
+/// Most ranges of frames the table covers
+pub const MAX_SEGMENTS: usize = 64;
+
+// The metadata of one contiguous range of frames
+#[derive(Clone, Copy)]
+struct Segment {
+    first: Frame,
+    entries: &'static [FrameInfo],
+}
+
+impl Segment {
+    fn get(&self, frame: Frame) -> Option<&'static FrameInfo> {
+        if frame < self.first {
+            None
+        } else {
+            self.entries.get((frame - self.first) as usize)
+        }
+    }
+}
+
+// Only usable memory is covered, so holes between regions cost nothing
+struct FrameTable {
+    segments: [Segment; MAX_SEGMENTS],
+    len: usize,
+}
+
+static TABLE: spin::Once<FrameTable> = spin::Once::new();
+
+/// Create the metadata table for the frames of `ranges`
+///
+/// Each range gets its own part of the table, allocated from `allocator`
+/// and accessed through `PHYS_MAP`, so this should run once every usable
+/// frame is free. Frames handed out before this are left `Unknown`, as are
+/// frames that were free at the time until they are first allocated.
+pub fn init<I>(ranges: I, allocator: &KernelAllocator)
+    where I: Iterator<Item = FrameRange>
+{
+    assert_has_not_been_called!("frame_table::init() function \
+                                 must only be called once");
+    let mut table = FrameTable {
+        segments: [Segment {
+            first: Frame::down(PAddr::from_u64(0)),
+            entries: &[],
+        }; MAX_SEGMENTS],
+        len: 0,
+    };
+    let mut covered = 0;
+    let mut frames = 0;
+    for range in ranges.filter(|range| range.nframes() > 0) {
+        if table.len == MAX_SEGMENTS {
+            warn!("No room in the frame table for {:#X} - {:#X}",
+                  range.lower().start_address(),
+                  range.upper().start_address());
+            continue;
+        }
+        let len = range.nframes() as usize;
+        let pages = table_frames(len);
+        let storage = match allocator.allocate_range_manual(pages) {
+            Some(storage) => storage,
+            None => {
+                warn!("Could not allocate the frame table for {:#X} - {:#X}",
+                      range.lower().start_address(),
+                      range.upper().start_address());
+                continue;
+            }
+        };
+        let entries = unsafe {
+            let addr = storage.lower().start_address().as_u64() as usize;
+            let ptr = (addr + PHYS_MAP) as *mut FrameInfo;
+            ptr::write_bytes(ptr, 0, len);
+            slice::from_raw_parts(ptr, len)
+        };
+        table.segments[table.len] = Segment {
+            first: range.lower(),
+            entries: entries,
+        };
+        table.len += 1;
+        covered += range.nframes();
+        frames += storage.nframes();
+    }
+    let table = TABLE.call_once(|| table);
+    // The table's own frames were allocated before it could record them
+    for segment in &table.segments[..table.len] {
+        let addr = (segment.entries.as_ptr() as usize - PHYS_MAP) as u64;
+        let first = Frame::down(PAddr::from_u64(addr));
+        let pages = table_frames(segment.entries.len());
+        allocated(FrameRange::new(first, first + pages));
+    }
+    info!("Frame table covers {} frames in {} ranges using {} frames",
+          covered,
+          table.len,
+          frames);
+}
+
+// Returns the number of frames holding the metadata of `len` frames
+fn table_frames(len: usize) -> u64 {
+    let bytes = (len * mem::size_of::<FrameInfo>()) as u64;
+    (bytes + PAGE_SIZE - 1) / PAGE_SIZE
+}
+
+/// Returns the metadata of `frame`, if the table covers it
+pub fn get(frame: Frame) -> Option<&'static FrameInfo> {
+    TABLE.try().and_then(|table| {
+        table.segments[..table.len]
+            .iter()
+            .filter_map(|segment| segment.get(frame))
+            .next()
+    })
+}
+
+/// Record what `frame` is used for, if the table covers it
+pub fn set_usage(frame: Frame, usage: FrameUsage) {
+    if let Some(info) = get(frame) {
+        info.set_usage(usage);
+    }
+}
+
+/// Record what every frame of `range` is used for
+pub fn set_range_usage(range: FrameRange, usage: FrameUsage) {
+    for i in 0..range.nframes() {
+        set_usage(range.lower() + i, usage);
+    }
+}
+
+/// Record that the kernel allocator handed out `range`
+///
+/// Each frame gets a single reference and `Kernel` usage.
+pub fn allocated(range: FrameRange) {
+    for i in 0..range.nframes() {
+        if let Some(info) = get(range.lower() + i) {
+            info.reset(FrameUsage::Kernel, 1);
+        }
+    }
+}
+
+/// Returns whether every frame of `range` may go back to the allocator
+pub fn freeable(range: FrameRange) -> bool {
+    for i in 0..range.nframes() {
+        let frame = range.lower() + i;
+        if let Some(info) = get(frame) {
+            if !info.freeable() {
+                warn!("Not freeing {:#X} with {} references and flags {:?}",
+                      frame.start_address(),
+                      info.refcount(),
+                      info.flags());
+                return false;
+            }
+        }
+    }
+    true
+}
+
+/// Record that `range` is being returned to the kernel allocator
+///
+/// Returns false and changes nothing if any frame is pinned or still
+/// shared, in which case the range must not be freed.
+pub fn freed(range: FrameRange) -> bool {
+    if !freeable(range) {
+        return false;
+    }
+    for i in 0..range.nframes() {
+        if let Some(info) = get(range.lower() + i) {
+            info.reset(FrameUsage::Free, 0);
+        }
+    }
+    true
+}
+
+/// Drop a reference to `frame`, returns whether it was the last
+///
+/// The last holder of a shared frame frees it, since freeing it earlier
+/// is refused.
+pub fn put_ref(frame: Frame) -> bool {
+    get(frame).map_or(false, |info| info.put_ref())
+}
+
+/// Take a copy-on-write reference to `frame`
+///
+/// The frame is marked `FRAME_COW` and is only written in place again once
+/// a single reference is left.
+pub fn share_cow(frame: Frame) {
+    if let Some(info) = get(frame) {
+        info.insert_flags(FRAME_COW);
+        info.get_ref();
+    }
+}
+
+/// Returns the number of frames of each usage, indexed as `USAGES`
+pub fn usage_counts() -> [u64; NUM_USAGES] {
+    let mut counts = [0; NUM_USAGES];
+    if let Some(table) = TABLE.try() {
+        for segment in &table.segments[..table.len] {
+            for info in segment.entries {
+                counts[info.usage() as usize] += 1;
+            }
+        }
+    }
+    counts
+}
+
+/// Log the number of frames of each usage
+pub fn log_usage() {
+    let counts = usage_counts();
+    for (usage, count) in USAGES.iter().zip(counts.iter()) {
+        info!("{:?}: {} frames", usage, count);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use memory::{Frame, PAddr, PAGE_SHIFT};
+    use super::{FRAME_COW, FRAME_PINNED, FrameInfo, FrameUsage, Segment};
+
+    #[test]
+    fn test_frame_info() {
+        let info = FrameInfo::new();
+        assert_eq!(info.usage(), FrameUsage::Unknown);
+        assert_eq!(info.refcount(), 0);
+
+        info.reset(FrameUsage::User, 1);
+        info.insert_flags(FRAME_COW | FRAME_PINNED);
+        info.set_usage(FrameUsage::Device);
+        assert_eq!(info.usage(), FrameUsage::Device);
+        assert_eq!(info.flags(), FRAME_COW | FRAME_PINNED);
+        info.remove_flags(FRAME_PINNED);
+        assert_eq!(info.flags(), FRAME_COW);
+
+        assert_eq!(info.get_ref(), 2);
+        assert!(!info.put_ref());
+        assert!(info.put_ref());
+    }
+
+    #[test]
+    fn test_freeable() {
+        let info = FrameInfo::new();
+        info.reset(FrameUsage::User, 1);
+        assert!(info.freeable());
+        info.get_ref();
+        assert!(!info.freeable());
+        info.put_ref();
+        info.insert_flags(FRAME_PINNED);
+        assert!(!info.freeable());
+        info.remove_flags(FRAME_PINNED);
+        assert!(info.freeable());
+    }
+
+    #[test]
+    fn test_segment() {
+        static ENTRIES: [FrameInfo; 2] = [FrameInfo::new(), FrameInfo::new()];
+        let frame = |n: u64| Frame::down(PAddr::from_u64(n << PAGE_SHIFT));
+        let segment = Segment {
+            first: frame(4),
+            entries: &ENTRIES,
+        };
+        assert!(segment.get(frame(3)).is_none());
+        assert!(segment.get(frame(6)).is_none());
+        segment.get(frame(5)).unwrap().set_usage(FrameUsage::Heap);
+        assert_eq!(ENTRIES[1].usage(), FrameUsage::Heap);
+        assert_eq!(ENTRIES[0].usage(), FrameUsage::Unknown);
+    }
+}
@@ -0,0 +1,235 @@
+// Copyright Dan Schatzberg, 2016. This file is part of Genesis.
+
+// Genesis is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// Genesis is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+
+// You should have received a copy of the GNU Affero General Public License
+// along with Genesis.  If not, see <http://www.gnu.org/licenses/>.
+use alloc::oom;
+use core::ptr;
+use spin;
+use super::*;
+use super::frame_table::{self, FrameUsage};
+
+/// A free object, linked through its first word
+struct FreeObject {
+    next: *mut FreeObject,
+}
+
+/// The free objects of one size class
+///
+/// Slabs are single frames carved into objects of the class size, so every
+/// object is aligned to its size. Slabs are never returned to the frame
+/// allocator.
+struct SizeClass {
+    size: usize,
+    free: spin::Mutex<FreeList>,
+}
+
+struct FreeList(*mut FreeObject);
+
+// The objects on the list are only reached through the Mutex
+unsafe impl Send for FreeList {}
+
+impl SizeClass {
+    const fn new(size: usize) -> SizeClass {
+        SizeClass {
+            size: size,
+            free: spin::Mutex::new(FreeList(ptr::null_mut())),
+        }
+    }
+
+    unsafe fn alloc(&self, allocator: &KernelAllocator) -> *mut u8 {
+        let mut free = self.free.lock();
+        if free.0.is_null() {
+            free.0 = match self.new_slab(allocator) {
+                Some(objects) => objects,
+                None => return ptr::null_mut(),
+            };
+        }
+        let object = free.0;
+        free.0 = (*object).next;
+        object as *mut u8
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8) {
+        let object = ptr as *mut FreeObject;
+        let mut free = self.free.lock();
+        (*object).next = free.0;
+        free.0 = object;
+    }
+
+    /// Carve a new frame into objects and return them as a list
+    unsafe fn new_slab(&self,
+                       allocator: &KernelAllocator)
+                       -> Option<*mut FreeObject> {
+        let frame = match allocator.allocate_manual() {
+            Some(frame) => frame,
+            None => return None,
+        };
+        frame_table::set_usage(frame, FrameUsage::Heap);
+        Some(carve(frame_to_slice(frame).as_mut_ptr(), self.size))
+    }
+}
+
+/// Link the frame at `base` into a list of objects of `size` bytes
+unsafe fn carve(base: *mut u8, size: usize) -> *mut FreeObject {
+    let count = PAGE_SIZE as usize / size;
+    let mut head = ptr::null_mut();
+    for i in (0..count).rev() {
+        let object = base.offset((i * size) as isize) as *mut FreeObject;
+        (*object).next = head;
+        head = object;
+    }
+    head
+}
+
+/// Object sizes served from slabs, larger requests get whole frames
+static CLASSES: [SizeClass; 8] = [SizeClass::new(16),
+                                  SizeClass::new(32),
+                                  SizeClass::new(64),
+                                  SizeClass::new(128),
+                                  SizeClass::new(256),
+                                  SizeClass::new(512),
+                                  SizeClass::new(1024),
+                                  SizeClass::new(2048)];
+
+static ALLOCATOR: spin::Once<&'static KernelAllocator> = spin::Once::new();
+
+/// Back the kernel heap with frames from `allocator`
+///
+/// Allocations fail until this is called. Frames must be reachable through
+/// `PHYS_MAP`.
+pub fn init(allocator: &'static KernelAllocator) {
+    assert_has_not_been_called!("heap::init() function \
+                                 must only be called once");
+    ALLOCATOR.call_once(|| allocator);
+    oom::set_oom_handler(out_of_memory);
+}
+
+fn out_of_memory() -> ! {
+    panic!("Out of kernel heap memory")
+}
+
+/// Returns the size class serving `size` bytes aligned to `align`, if it is
+/// small enough
+fn class(size: usize, align: usize) -> Option<&'static SizeClass> {
+    let size = if size > align { size } else { align };
+    CLASSES.iter().find(|class| class.size >= size)
+}
+
+/// Returns the number of frames backing a large allocation of `size` bytes
+fn nframes(size: usize) -> u64 {
+    (size as u64 + PAGE_SIZE - 1) / PAGE_SIZE
+}
+
+/// Returns the number of frames for a large allocation, if `align` can be
+/// met by a frame boundary
+fn large_frames(size: usize, align: usize) -> Option<u64> {
+    if align > PAGE_SIZE as usize {
+        None
+    } else {
+        Some(nframes(size))
+    }
+}
+
+/// Allocate `size` bytes aligned to `align` from the kernel heap
+///
+/// Small allocations come from per size class slabs and larger ones are
+/// given whole frames, so alignment beyond a frame is not supported. The
+/// heap takes spin locks and must not be used from interrupt handlers.
+/// Returns null on failure. This is called by `__rust_allocate` in the
+/// `genesis-allocator` crate.
+#[no_mangle]
+pub unsafe extern "C" fn genesis_heap_allocate(size: usize,
+                                               align: usize)
+                                               -> *mut u8 {
+    let allocator = match ALLOCATOR.try() {
+        Some(allocator) => *allocator,
+        None => return ptr::null_mut(),
+    };
+    if let Some(class) = class(size, align) {
+        return class.alloc(allocator);
+    }
+    let nframes = match large_frames(size, align) {
+        Some(nframes) => nframes,
+        None => return ptr::null_mut(),
+    };
+    match allocator.allocate_range_manual(nframes) {
+        Some(range) => {
+            frame_table::set_range_usage(range, FrameUsage::Heap);
+            frame_to_slice(range.lower()).as_mut_ptr()
+        }
+        None => ptr::null_mut(),
+    }
+}
+
+/// Free memory returned by `genesis_heap_allocate` with the same `size`
+/// and `align`
+#[no_mangle]
+pub unsafe extern "C" fn genesis_heap_deallocate(ptr: *mut u8,
+                                                 size: usize,
+                                                 align: usize) {
+    if let Some(class) = class(size, align) {
+        return class.dealloc(ptr);
+    }
+    let allocator = ALLOCATOR.try()
+        .expect("Heap memory freed before the heap was initialized");
+    let addr = ptr as usize - PHYS_MAP;
+    let lower = Frame::down(PAddr::from_u64(addr as u64));
+    allocator.free_range_manual(FrameRange::new(lower, lower + nframes(size)));
+}
+
+#[cfg(test)]
+mod test {
+    use super::{carve, class, large_frames, nframes};
+    use memory::PAGE_SIZE;
+    use std::vec::Vec;
+
+    #[test]
+    fn test_class() {
+        assert_eq!(class(1, 1).map(|c| c.size), Some(16));
+        assert_eq!(class(16, 8).map(|c| c.size), Some(16));
+        assert_eq!(class(17, 8).map(|c| c.size), Some(32));
+        assert_eq!(class(24, 64).map(|c| c.size), Some(64));
+        assert_eq!(class(2048, 16).map(|c| c.size), Some(2048));
+        assert!(class(2049, 16).is_none());
+        assert!(class(8, 4096).is_none());
+    }
+
+    #[test]
+    fn test_nframes() {
+        assert_eq!(nframes(2049), 1);
+        assert_eq!(nframes(PAGE_SIZE as usize), 1);
+        assert_eq!(nframes(PAGE_SIZE as usize + 1), 2);
+        assert_eq!(nframes(10 * PAGE_SIZE as usize), 10);
+    }
+
+    #[test]
+    fn test_large_frames() {
+        assert_eq!(large_frames(8192, 4096), Some(2));
+        assert_eq!(large_frames(3000, 4096), Some(1));
+        assert_eq!(large_frames(8192, 8192), None);
+    }
+
+    #[test]
+    fn test_carve() {
+        let mut memory: Vec<u64> = vec![0; PAGE_SIZE as usize / 8];
+        let base = memory.as_mut_ptr() as *mut u8;
+        let mut object = unsafe { carve(base, 64) };
+        let mut count = 0;
+        while !object.is_null() {
+            assert_eq!(object as usize, base as usize + count * 64);
+            object = unsafe { (*object).next };
+            count += 1;
+        }
+        assert_eq!(count, PAGE_SIZE as usize / 64);
+    }
+}
@@ -15,7 +15,186 @@
 
 use core::ops::{Add, Deref, DerefMut, Sub};
 pub use ::arch::mem::*;
+use spin;
+use self::buddy_allocator::BuddyAllocator;
+use self::first_fit_allocator::FirstFitAllocator;
+use self::zone::Constraint;
+pub mod address_space;
+pub mod buddy_allocator;
+pub mod cpu_mutex;
 pub mod first_fit_allocator;
+pub mod frame_cache;
+pub mod frame_table;
+pub mod heap;
+pub mod zone;
+
+/// The `FrameAllocator` used by the kernel, picked at boot
+#[derive(Clone, Copy)]
+pub enum KernelAllocator {
+    /// A sorted list of free ranges
+    FirstFit(&'static FirstFitAllocator<'static>),
+    /// Power of two blocks, needed for aligned allocations
+    Buddy(&'static BuddyAllocator<'static>),
+}
+
+static KERNEL_ALLOCATOR: spin::Once<KernelAllocator> = spin::Once::new();
+
+/// Pick the kernel frame allocator
+///
+/// Frames are accessed at `phys_offset` plus their physical address until
+/// `KernelAllocator::set_phys_offset` is called.
+pub fn init_allocator(buddy: bool,
+                      phys_offset: usize)
+                      -> &'static KernelAllocator {
+    assert_has_not_been_called!("memory::init_allocator() function \
+                                 must only be called once");
+    KERNEL_ALLOCATOR.call_once(|| if buddy {
+        let allocator = BuddyAllocator::get();
+        unsafe { allocator.set_phys_offset(phys_offset) };
+        KernelAllocator::Buddy(allocator)
+    } else {
+        KernelAllocator::FirstFit(FirstFitAllocator::get())
+    })
+}
+
+impl KernelAllocator {
+    /// Returns the name of the allocator, as given on the command line
+    pub fn name(&self) -> &'static str {
+        match *self {
+            KernelAllocator::FirstFit(_) => "firstfit",
+            KernelAllocator::Buddy(_) => "buddy",
+        }
+    }
+
+    /// Give the frames in every per-CPU cache back to the allocator,
+    /// returns whether there were any
+    fn drain_caches(&self) -> bool {
+        let drained = match *self {
+            KernelAllocator::FirstFit(a) => frame_cache::drain_all(a),
+            KernelAllocator::Buddy(a) => frame_cache::drain_all(a),
+        };
+        drained > 0
+    }
+
+    /// Change where free frames are accessed, if the allocator needs to
+    ///
+    /// Every free frame must be mapped at the new offset.
+    pub unsafe fn set_phys_offset(&self, phys_offset: usize) {
+        if let KernelAllocator::Buddy(buddy) = *self {
+            buddy.set_phys_offset(phys_offset);
+        }
+    }
+}
+
+// Allocations and frees are recorded in the frame table, which refuses to
+// free pinned or shared frames. Single frames go through the calling CPU's
+// frame cache when caches are on, and the caches are drained when a range
+// allocation fails.
+impl FrameAllocator for KernelAllocator {
+    fn allocate_manual(&self) -> Option<Frame> {
+        let cached = frame_cache::current().and_then(|cache| match *self {
+            KernelAllocator::FirstFit(a) => cache.allocate(a),
+            KernelAllocator::Buddy(a) => cache.allocate(a),
+        });
+        let frame = cached.or_else(|| match *self {
+            KernelAllocator::FirstFit(a) => a.allocate_manual(),
+            KernelAllocator::Buddy(a) => a.allocate_manual(),
+        });
+        if let Some(frame) = frame {
+            frame_table::allocated(FrameRange::new(frame, frame + 1));
+        }
+        frame
+    }
+
+    unsafe fn free_manual(&self, frame: Frame) {
+        if !frame_table::freed(FrameRange::new(frame, frame + 1)) {
+            return;
+        }
+        let cached = frame_cache::current().map_or(false, |cache| match *self {
+            KernelAllocator::FirstFit(a) => cache.free(a, frame),
+            KernelAllocator::Buddy(a) => cache.free(a, frame),
+        });
+        if !cached {
+            match *self {
+                KernelAllocator::FirstFit(a) => a.free_manual(frame),
+                KernelAllocator::Buddy(a) => a.free_manual(frame),
+            }
+        }
+    }
+
+    fn allocate_batch(&self, frames: &mut [Frame]) -> usize {
+        let len = match *self {
+            KernelAllocator::FirstFit(a) => a.allocate_batch(frames),
+            KernelAllocator::Buddy(a) => a.allocate_batch(frames),
+        };
+        for &frame in &frames[..len] {
+            frame_table::allocated(FrameRange::new(frame, frame + 1));
+        }
+        len
+    }
+
+    unsafe fn free_batch(&self, frames: &[Frame]) {
+        let single = |frame: &Frame| FrameRange::new(*frame, *frame + 1);
+        if !frames.iter().map(single).all(frame_table::freeable) {
+            // Free what may be freed one at a time
+            for &frame in frames {
+                self.free_manual(frame);
+            }
+            return;
+        }
+        for range in frames.iter().map(single) {
+            frame_table::freed(range);
+        }
+        match *self {
+            KernelAllocator::FirstFit(a) => a.free_batch(frames),
+            KernelAllocator::Buddy(a) => a.free_batch(frames),
+        }
+    }
+
+    fn allocate_range_manual(&self, nframes: u64) -> Option<FrameRange> {
+        let allocate = || match *self {
+            KernelAllocator::FirstFit(a) => a.allocate_range_manual(nframes),
+            KernelAllocator::Buddy(a) => a.allocate_range_manual(nframes),
+        };
+        let range = allocate()
+            .or_else(|| if self.drain_caches() { allocate() } else { None });
+        if let Some(range) = range {
+            frame_table::allocated(range);
+        }
+        range
+    }
+
+    unsafe fn free_range_manual(&self, range: FrameRange) {
+        if !frame_table::freed(range) {
+            return;
+        }
+        match *self {
+            KernelAllocator::FirstFit(a) => a.free_range_manual(range),
+            KernelAllocator::Buddy(a) => a.free_range_manual(range),
+        }
+    }
+
+    fn allocate_constrained_manual(&self,
+                                   nframes: u64,
+                                   align: u64,
+                                   constraint: Constraint)
+                                   -> Option<FrameRange> {
+        let allocate = || match *self {
+            KernelAllocator::FirstFit(a) => {
+                a.allocate_constrained_manual(nframes, align, constraint)
+            }
+            KernelAllocator::Buddy(a) => {
+                a.allocate_constrained_manual(nframes, align, constraint)
+            }
+        };
+        let range = allocate()
+            .or_else(|| if self.drain_caches() { allocate() } else { None });
+        if let Some(range) = range {
+            frame_table::allocated(range);
+        }
+        range
+    }
+}
 
 /// A virtual page
 #[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
@@ -123,6 +302,27 @@ pub trait FrameAllocator: Sync + Sized {
         opt_range.map(|range| FrameHandle(range, self))
     }
 
+    /// Allocate up to `frames.len()` single frames into `frames`
+    ///
+    /// Returns how many were allocated. Implementations should hold their
+    /// lock once for the whole batch.
+    fn allocate_batch(&self, frames: &mut [Frame]) -> usize {
+        for (i, slot) in frames.iter_mut().enumerate() {
+            match self.allocate_manual() {
+                Some(frame) => *slot = frame,
+                None => return i,
+            }
+        }
+        frames.len()
+    }
+
+    /// Free every frame of `frames`
+    unsafe fn free_batch(&self, frames: &[Frame]) {
+        for &frame in frames {
+            self.free_manual(frame);
+        }
+    }
+
     fn allocate_range_manual(&self, nframes: u64) -> Option<FrameRange>;
     unsafe fn free_range_manual(&self, FrameRange);
 
@@ -130,4 +330,24 @@ pub trait FrameAllocator: Sync + Sized {
         let opt_range = self.allocate_range_manual(nframes);
         opt_range.map(|range| FrameRangeHandle(range, self))
     }
+
+    /// Allocate `nframes` frames starting at a multiple of `align` frames,
+    /// from the zones allowed by `constraint`
+    ///
+    /// `align` must be a power of two.
+    fn allocate_constrained_manual(&self,
+                                   nframes: u64,
+                                   align: u64,
+                                   constraint: Constraint)
+                                   -> Option<FrameRange>;
+
+    /// Allocate `nframes` frames starting at a multiple of `align` frames
+    ///
+    /// `align` must be a power of two.
+    fn allocate_aligned_manual(&self,
+                               nframes: u64,
+                               align: u64)
+                               -> Option<FrameRange> {
+        self.allocate_constrained_manual(nframes, align, Constraint::any())
+    }
 }
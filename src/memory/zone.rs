@@ -0,0 +1,394 @@
+// Copyright Dan Schatzberg, 2016. This file is part of Genesis.
+
+// Genesis is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// Genesis is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+
+// You should have received a copy of the GNU Affero General Public License
+// along with Genesis.  If not, see <http://www.gnu.org/licenses/>.
+use core::cmp;
+use spin;
+use super::{Frame, PAddr, PHYS_LIMIT};
+
+/// End of memory reachable by ISA DMA
+pub const DMA_LIMIT: PAddr = PAddr::from_u64(16 << 20);
+/// End of memory reachable by 32-bit DMA
+pub const DMA32_LIMIT: PAddr = PAddr::from_u64(4 << 30);
+
+/// Most zones the table can hold
+pub const MAX_ZONES: usize = 32;
+/// Most NUMA memory ranges taken into account
+pub const MAX_NODE_RANGES: usize = 14;
+
+/// The kind of memory in a zone, by the devices that can reach it
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum ZoneKind {
+    /// Below `DMA_LIMIT`
+    Dma,
+    /// Below `DMA32_LIMIT`
+    Dma32,
+    /// Everything else
+    Normal,
+}
+
+/// A range of physical memory of one kind on one NUMA node
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Zone {
+    start: Frame,
+    end: Frame,
+    kind: ZoneKind,
+    node: u32,
+}
+
+impl Zone {
+    const fn new(start: Frame,
+                 end: Frame,
+                 kind: ZoneKind,
+                 node: u32)
+                 -> Zone {
+        Zone {
+            start: start,
+            end: end,
+            kind: kind,
+            node: node,
+        }
+    }
+
+    /// Returns the first `Frame`
+    pub fn start(&self) -> Frame {
+        self.start
+    }
+
+    /// Returns the `Frame` after the zone
+    pub fn end(&self) -> Frame {
+        self.end
+    }
+
+    /// Returns the kind of memory in the zone
+    pub fn kind(&self) -> ZoneKind {
+        self.kind
+    }
+
+    /// Returns the NUMA node of the zone
+    pub fn node(&self) -> u32 {
+        self.node
+    }
+
+    /// Returns whether the zone holds `frame`
+    pub fn contains(&self, frame: Frame) -> bool {
+        self.start <= frame && frame < self.end
+    }
+}
+
+/// Where an allocation may come from
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Constraint {
+    below: Option<PAddr>,
+    node: Option<u32>,
+}
+
+impl Constraint {
+    /// Allow any zone
+    pub const fn any() -> Constraint {
+        Constraint {
+            below: None,
+            node: None,
+        }
+    }
+
+    /// Allow only ISA DMA memory
+    pub const fn dma() -> Constraint {
+        Constraint {
+            below: Some(DMA_LIMIT),
+            node: None,
+        }
+    }
+
+    /// Allow only memory below 4 GiB
+    pub const fn dma32() -> Constraint {
+        Constraint {
+            below: Some(DMA32_LIMIT),
+            node: None,
+        }
+    }
+
+    /// Also require memory below `addr`
+    ///
+    /// Only zones ending at or below `addr` are used, so `addr` should be
+    /// a zone boundary such as `DMA32_LIMIT`.
+    pub fn below(self, addr: PAddr) -> Constraint {
+        let below = self.below.map_or(addr, |b| cmp::min(b, addr));
+        Constraint {
+            below: Some(below),
+            node: self.node,
+        }
+    }
+
+    /// Also require memory on NUMA node `node`
+    pub fn on_node(self, node: u32) -> Constraint {
+        Constraint {
+            below: self.below,
+            node: Some(node),
+        }
+    }
+
+    /// Returns whether allocations may come from `zone`
+    pub fn allows(&self, zone: &Zone) -> bool {
+        self.node.map_or(true, |node| zone.node == node) &&
+        self.below.map_or(true, |below| zone.end.start_address() <= below)
+    }
+}
+
+/// Every zone of physical memory, in address order
+pub struct ZoneTable {
+    zones: [Zone; MAX_ZONES],
+    len: usize,
+    /// Zone indices with the most plentiful memory first
+    preferred: [usize; MAX_ZONES],
+}
+
+const NULL_FRAME: Frame = Frame::down(PAddr::from_u64(0));
+const NULL_ZONE: Zone = Zone::new(NULL_FRAME, NULL_FRAME, ZoneKind::Dma, 0);
+
+// Used until init() is called, a single zone of everything
+static DEFAULT: ZoneTable = ZoneTable {
+    zones: [Zone::new(NULL_FRAME,
+                      Frame::down(PAddr::from_u64(PHYS_LIMIT)),
+                      ZoneKind::Normal,
+                      0); MAX_ZONES],
+    len: 1,
+    preferred: [0; MAX_ZONES],
+};
+
+static ZONES: spin::Once<ZoneTable> = spin::Once::new();
+
+/// Split physical memory into zones
+///
+/// `nodes` gives the NUMA node of ranges of physical memory, memory in no
+/// range belongs to node 0. Frame allocators must still be empty, as free
+/// frames are kept per zone.
+pub fn init<I>(nodes: I)
+    where I: Iterator<Item = (PAddr, PAddr, u32)>
+{
+    assert_has_not_been_called!("zone::init() function \
+                                 must only be called once");
+    let table = ZONES.call_once(|| ZoneTable::new(nodes));
+    for zone in table.zones() {
+        info!("Zone {:#17X} - {:#17X}: {:?} on node {}",
+              zone.start.start_address(),
+              zone.end.start_address(),
+              zone.kind,
+              zone.node);
+    }
+}
+
+/// Returns the zone table, a single zone if `init` has not been called
+pub fn table() -> &'static ZoneTable {
+    ZONES.try().unwrap_or(&DEFAULT)
+}
+
+fn kind_of(frame: Frame) -> ZoneKind {
+    if frame.start_address() < DMA_LIMIT {
+        ZoneKind::Dma
+    } else if frame.start_address() < DMA32_LIMIT {
+        ZoneKind::Dma32
+    } else {
+        ZoneKind::Normal
+    }
+}
+
+/// Sort `items` in place by `less`, without allocating
+fn insertion_sort<T: Copy, F>(items: &mut [T], less: F)
+    where F: Fn(&T, &T) -> bool
+{
+    for i in 1..items.len() {
+        let mut j = i;
+        while j > 0 && less(&items[j], &items[j - 1]) {
+            items.swap(j, j - 1);
+            j -= 1;
+        }
+    }
+}
+
+impl ZoneTable {
+    /// Build the zones for the NUMA ranges in `nodes`
+    pub fn new<I>(nodes: I) -> ZoneTable
+        where I: Iterator<Item = (PAddr, PAddr, u32)>
+    {
+        let mut ranges = [(NULL_FRAME, NULL_FRAME, 0); MAX_NODE_RANGES];
+        let mut nranges = 0;
+        for (start, end, node) in nodes {
+            if nranges == MAX_NODE_RANGES {
+                warn!("Ignoring NUMA range {:#X} - {:#X}", start, end);
+                continue;
+            }
+            ranges[nranges] = (Frame::down(start), Frame::down(end), node);
+            nranges += 1;
+        }
+        let ranges = &ranges[..nranges];
+
+        // Zones start and end at the kind limits and at every range edge
+        let limit = Frame::down(PAddr::from_u64(PHYS_LIMIT));
+        let mut cuts = [NULL_FRAME; 4 + 2 * MAX_NODE_RANGES];
+        cuts[1] = Frame::down(DMA_LIMIT);
+        cuts[2] = Frame::down(DMA32_LIMIT);
+        cuts[3] = limit;
+        let mut ncuts = 4;
+        for &(start, end, _) in ranges {
+            cuts[ncuts] = start;
+            cuts[ncuts + 1] = end;
+            ncuts += 2;
+        }
+        let cuts = &mut cuts[..ncuts];
+        insertion_sort(cuts, |a, b| a < b);
+
+        let mut table = ZoneTable {
+            zones: [NULL_ZONE; MAX_ZONES],
+            len: 0,
+            preferred: [0; MAX_ZONES],
+        };
+        for pair in cuts.windows(2) {
+            let (start, end) = (pair[0], cmp::min(pair[1], limit));
+            if start >= end {
+                continue;
+            }
+            let node = ranges.iter()
+                .find(|&&(s, e, _)| s <= start && start < e)
+                .map_or(0, |&(_, _, node)| node);
+            let kind = kind_of(start);
+            if table.len > 0 {
+                let last = &mut table.zones[table.len - 1];
+                if last.kind == kind && last.node == node &&
+                   last.end == start {
+                    last.end = end;
+                    continue;
+                }
+            }
+            // There are fewer cuts than zone slots, so this always fits
+            table.zones[table.len] = Zone::new(start, end, kind, node);
+            table.len += 1;
+        }
+
+        for i in 0..table.len {
+            table.preferred[i] = i;
+        }
+        let zones = table.zones;
+        insertion_sort(&mut table.preferred[..table.len], |&a, &b| {
+            let (a, b) = (&zones[a], &zones[b]);
+            if a.kind != b.kind {
+                a.kind > b.kind
+            } else {
+                (a.node, a.start) < (b.node, b.start)
+            }
+        });
+        table
+    }
+
+    /// Returns every zone, in address order
+    pub fn zones(&self) -> &[Zone] {
+        &self.zones[..self.len]
+    }
+
+    /// Returns the index of the zone holding `frame`
+    ///
+    /// Frames past the last zone are counted in it.
+    pub fn index(&self, frame: Frame) -> usize {
+        self.zones()
+            .iter()
+            .position(|zone| zone.contains(frame))
+            .unwrap_or(self.len - 1)
+    }
+
+    /// Returns the zone indices allowed by `constraint`
+    ///
+    /// `Normal` zones come first and `Dma` zones last, so unconstrained
+    /// allocations leave low memory to the devices that need it.
+    pub fn preferred(&self, constraint: Constraint) -> Preferred {
+        Preferred {
+            table: self,
+            constraint: constraint,
+            next: 0,
+        }
+    }
+}
+
+/// Iterator over the zones allowed by a `Constraint`, best first
+#[derive(Clone, Copy)]
+pub struct Preferred<'a> {
+    table: &'a ZoneTable,
+    constraint: Constraint,
+    next: usize,
+}
+
+impl<'a> Iterator for Preferred<'a> {
+    type Item = usize;
+
+    fn next(&mut self) -> Option<usize> {
+        while self.next < self.table.len {
+            let index = self.table.preferred[self.next];
+            self.next += 1;
+            if self.constraint.allows(&self.table.zones[index]) {
+                return Some(index);
+            }
+        }
+        None
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Constraint, DMA32_LIMIT, ZoneKind, ZoneTable};
+    use memory::{Frame, PAddr};
+    use std::vec::Vec;
+
+    fn frame(addr: u64) -> Frame {
+        Frame::down(PAddr::from_u64(addr))
+    }
+
+    #[test]
+    fn test_no_numa() {
+        let table = ZoneTable::new(None::<(PAddr, PAddr, u32)>.into_iter());
+        let kinds: Vec<_> = table.zones().iter().map(|z| z.kind()).collect();
+        assert_eq!(kinds,
+                   vec![ZoneKind::Dma, ZoneKind::Dma32, ZoneKind::Normal]);
+        assert_eq!(table.index(frame(0x1000)), 0);
+        assert_eq!(table.index(frame(64 << 20)), 1);
+        assert_eq!(table.index(frame(8 << 30)), 2);
+        let any: Vec<_> = table.preferred(Constraint::any()).collect();
+        assert_eq!(any, vec![2, 1, 0]);
+        let low: Vec<_> = table.preferred(Constraint::dma32()).collect();
+        assert_eq!(low, vec![1, 0]);
+    }
+
+    #[test]
+    fn test_numa() {
+        // Two nodes of 3 GiB each
+        let gib = |n: u64| PAddr::from_u64(n << 30);
+        let nodes = vec![(gib(0), gib(3), 0), (gib(3), gib(6), 1)];
+        let table = ZoneTable::new(nodes.into_iter());
+        let zones: Vec<_> = table.zones()
+            .iter()
+            .map(|z| (z.start(), z.kind(), z.node()))
+            .collect();
+        assert_eq!(zones,
+                   vec![(frame(0), ZoneKind::Dma, 0),
+                        (frame(16 << 20), ZoneKind::Dma32, 0),
+                        (frame(3 << 30), ZoneKind::Dma32, 1),
+                        (frame(4 << 30), ZoneKind::Normal, 1),
+                        (frame(6 << 30), ZoneKind::Normal, 0)]);
+        let node1: Vec<_> = table.preferred(Constraint::any().on_node(1))
+            .collect();
+        assert_eq!(node1, vec![3, 2]);
+        let low1: Vec<_> = table.preferred(Constraint::any()
+                .on_node(1)
+                .below(DMA32_LIMIT))
+            .collect();
+        assert_eq!(low1, vec![2]);
+    }
+}
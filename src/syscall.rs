@@ -0,0 +1,67 @@
+// Copyright Dan Schatzberg, 2016. This file is part of Genesis.
+
+// Genesis is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// Genesis is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+
+// You should have received a copy of the GNU Affero General Public License
+// along with Genesis.  If not, see <http://www.gnu.org/licenses/>.
+use abi::{self, ABI_VERSION, Error, MAX_ARGS, Syscall};
+use core::slice;
+use core::str;
+use memory::{USER_END, VAddr};
+
+/// Longest string accepted by `Syscall::DebugWrite`
+const MAX_DEBUG_WRITE: u64 = 256;
+
+/// Run system call `number` and return the encoded result for user mode
+pub fn dispatch(number: u64, args: [u64; MAX_ARGS]) -> u64 {
+    abi::encode(Syscall::from_number(number)
+        .ok_or(Error::NoSys)
+        .and_then(|syscall| call(syscall, &args)))
+}
+
+fn call(syscall: Syscall, args: &[u64; MAX_ARGS]) -> Result<u64, Error> {
+    if args[syscall.nargs()..].iter().any(|arg| *arg != 0) {
+        return Err(Error::InvalidArgument);
+    }
+    match syscall {
+        Syscall::AbiVersion => Ok(ABI_VERSION),
+        Syscall::DebugWrite => debug_write(args[0], args[1]),
+        // There is no scheduler yet, so there is nothing else to run
+        Syscall::Yield => Ok(0),
+    }
+}
+
+/// Returns the user memory [`ptr`, `ptr + len`)
+///
+/// Every page is faulted in first, so reading the slice cannot fault.
+fn user_slice(ptr: u64, len: u64) -> Result<&'static [u8], Error> {
+    let end = match ptr.checked_add(len) {
+        Some(end) if ptr != 0 && end <= USER_END as u64 => end,
+        _ => return Err(Error::BadAddress),
+    };
+    let space = try!(::arch::user_space().ok_or(Error::BadAddress));
+    if !space.fault_in(VAddr::from_usize(ptr as usize),
+                       VAddr::from_usize(end as usize),
+                       false) {
+        return Err(Error::BadAddress);
+    }
+    unsafe { Ok(slice::from_raw_parts(ptr as *const u8, len as usize)) }
+}
+
+fn debug_write(ptr: u64, len: u64) -> Result<u64, Error> {
+    if len > MAX_DEBUG_WRITE {
+        return Err(Error::InvalidArgument);
+    }
+    let bytes = try!(user_slice(ptr, len));
+    let s = try!(str::from_utf8(bytes).map_err(|_| Error::InvalidArgument));
+    info!("user: {}", s);
+    Ok(len)
+}
@@ -17,6 +17,7 @@ use core::fmt;
 #[cfg(not(test))]
 #[lang = "panic_fmt"]
 extern "C" fn panic_fmt(fmt: fmt::Arguments, file: &str, line: u32) -> ! {
+    ::arch::stop_other_cpus();
     error!("KERNEL PANIC: {}:{}", file, line);
     error!("    {}", fmt);
     loop {}